@@ -0,0 +1,146 @@
+use crate::media::channels::ChannelMap;
+use crate::media::codec::{Codec, OpusSettings};
+use crate::media::dither::DitherSettings;
+use crate::media::format::{SampleFormat, StreamFormat};
+use crate::media::jitter::JitterSettings;
+use crate::media::resample::ResampleQuality;
+use crate::network::ReconnectSettings;
+use std::net::{Ipv4Addr, SocketAddr};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Serve,
+    Play,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    // Framed stream with a format handshake, see network::protocol
+    Tcp,
+    // L16 over RTP/UDP, which trades reliability for latency on lossy networks
+    Rtp,
+    // RTP sent once to a multicast group that any number of receivers can join
+    Multicast,
+}
+
+// What the server does with a packet for a client whose send queue is full
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    // Make room by dropping the oldest queued packet, so the client skips ahead
+    DropOldest,
+    // Drop the new packet, so the client hears a gap later
+    DropNewest,
+    Disconnect,
+}
+
+// Which GStreamer sink plays the stream on Linux
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sink {
+    // Whatever autoaudiosink finds, or the sink for the API of the chosen device
+    Auto,
+    Pulse,
+    Alsa,
+    PipeWire,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    // Address to bind to when serving, or the server to connect to when playing
+    pub host: String,
+    pub port: u16,
+    // Name a server advertises itself as, or the server to look up instead of `host` when playing
+    pub name: Option<String>,
+    // Whether a TCP server advertises itself for discovery
+    pub advertise: bool,
+    // Device to record from when serving, or to play to when playing, by index, id or name, see
+    // media::device::select. When this is None recording uses the default playback device's
+    // monitor and playback uses the default output
+    pub device: Option<String>,
+    pub sink: Sink,
+    // Application to capture on its own instead of a device, by name or PID. Needs PipeWire
+    pub app: Option<String>,
+    // Samples the capture side sends, which the playback side learns from the handshake over TCP
+    pub sample_format: SampleFormat,
+    // How the capture side reduces float audio to an integer sample format
+    pub dither: DitherSettings,
+    // Rate the capture side records at on Linux, and generates test signals at
+    pub rate: u32,
+    // Rate the capture side resamples to before sending, whatever it records at. None sends what
+    // it records, which on Windows is the rate the device mixes at
+    pub transport_rate: Option<u32>,
+    // How finely audio is resampled, to the transport rate and to the rate a playback device runs at
+    pub resample: ResampleQuality,
+    pub channels: u16,
+    // Mix from the captured channels to the stream's, or from the stream's to the playback
+    // device's, used instead of the standard downmix or upmix
+    pub channel_map: Option<ChannelMap>,
+    pub transport: Transport,
+    // Addresses an RTP sender streams to
+    pub receivers: Vec<SocketAddr>,
+    // Multicast group and the number of router hops multicast packets may cross
+    pub group: Ipv4Addr,
+    pub ttl: u32,
+    // Packets queued for each TCP client before the overflow policy kicks in
+    pub queue_size: usize,
+    pub overflow: Overflow,
+    pub codec: Codec,
+    pub opus: OpusSettings,
+    // How much the playback side buffers against late packets
+    pub jitter: JitterSettings,
+    // How the playback side retries when it can't reach the server
+    pub reconnect: ReconnectSettings,
+}
+
+impl Config {
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    // Format the capture side records in, where it gets to choose
+    pub fn capture_format(&self) -> StreamFormat {
+        StreamFormat {
+            sample_format: self.sample_format,
+            rate: self.rate,
+            channels: self.channels,
+        }
+    }
+
+    // Format the capture side advertises to clients, and that an RTP receiver expects
+    pub fn stream_format(&self) -> StreamFormat {
+        StreamFormat {
+            rate: self.transport_rate.unwrap_or(self.rate),
+            ..self.capture_format()
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            host: "0.0.0.0".to_string(),
+            port: 42795,
+            name: None,
+            advertise: true,
+            device: None,
+            sink: Sink::Auto,
+            app: None,
+            sample_format: SampleFormat::S16LE,
+            dither: DitherSettings::default(),
+            rate: 48_000,
+            transport_rate: None,
+            resample: ResampleQuality::High,
+            channels: 2,
+            channel_map: None,
+            transport: Transport::Tcp,
+            receivers: vec![],
+            group: Ipv4Addr::new(239, 255, 42, 95),
+            ttl: 1,
+            queue_size: 50,
+            overflow: Overflow::DropOldest,
+            codec: Codec::Pcm,
+            opus: OpusSettings::default(),
+            jitter: JitterSettings::default(),
+            reconnect: ReconnectSettings::default(),
+        }
+    }
+}
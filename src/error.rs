@@ -0,0 +1,100 @@
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    // No usable audio device, or the requested one could not be found
+    Device(String),
+    // A call into the platform audio API failed
+    Backend(BackendError),
+    // Binding, connecting to, reading from or writing to a socket failed
+    Network(io::Error),
+    // The stream format could not be negotiated or is not supported
+    Format(String),
+    // The server ended the stream
+    Closed,
+    // Advertising or browsing for servers with mDNS failed, or no server had the requested name
+    Discovery(String),
+}
+
+#[derive(Debug)]
+pub enum BackendError {
+    // A COM or WASAPI call returned a failing HRESULT
+    Hresult { call: &'static str, code: i32 },
+    Gstreamer(String),
+    // The requested backend isn't in this build or can't run on this machine
+    Unavailable(String),
+}
+
+impl Error {
+    pub fn gstreamer<S: Into<String>>(message: S) -> Self {
+        Error::Backend(BackendError::Gstreamer(message.into()))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Device(message) => write!(f, "audio device error: {}", message),
+            Error::Backend(error) => write!(f, "audio backend error: {}", error),
+            Error::Network(error) => write!(f, "network error: {}", error),
+            Error::Format(message) => write!(f, "stream format error: {}", message),
+            Error::Closed => write!(f, "the server closed the stream"),
+            Error::Discovery(message) => write!(f, "discovery error: {}", message),
+        }
+    }
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackendError::Hresult { call, code } => write!(f, "{} failed with HRESULT {:#x}", call, code),
+            BackendError::Gstreamer(message) => write!(f, "gstreamer: {}", message),
+            BackendError::Unavailable(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Network(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Network(error)
+    }
+}
+
+impl From<mdns_sd::Error> for Error {
+    fn from(error: mdns_sd::Error) -> Self {
+        Error::Discovery(error.to_string())
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "backend-gstreamer"))]
+impl From<glib::Error> for Error {
+    fn from(error: glib::Error) -> Self {
+        Error::gstreamer(error.to_string())
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "backend-gstreamer"))]
+impl From<glib::BoolError> for Error {
+    fn from(error: glib::BoolError) -> Self {
+        Error::gstreamer(error.to_string())
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "backend-gstreamer"))]
+impl From<gstreamer::StateChangeError> for Error {
+    fn from(error: gstreamer::StateChangeError) -> Self {
+        Error::gstreamer(error.to_string())
+    }
+}
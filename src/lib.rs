@@ -0,0 +1,26 @@
+//! Capture system audio and stream it to other machines on the network.
+//!
+//! `create_audio_interface` returns a capture and playback backend, either the one named or the
+//! first of those compiled in that can run on this machine. `media::backends` lists them.
+//! `network::serve` and `network::Stream` can also be used directly to stream audio that comes
+//! from somewhere else, over TCP (`Server` and `Client`) or RTP. Requesting a `Shutdown` stops
+//! either side cleanly. TCP servers advertise themselves on the local network, and
+//! `network::discover` lists them.
+//!
+//! `null::Interface` records a WAV file or a test signal and plays to a file or to memory, so the
+//! whole pipeline can be exercised without audio hardware.
+
+pub mod config;
+pub mod error;
+pub mod media;
+pub mod network;
+mod platform;
+pub mod shutdown;
+
+pub use config::{Config, Mode, Overflow, Sink, Transport};
+pub use error::{Error, Result};
+pub use media::{create_audio_interface, InterfaceTrait};
+pub use network::{Client, Server, Stream};
+#[cfg(feature = "backend-null")]
+pub use platform::null;
+pub use shutdown::Shutdown;
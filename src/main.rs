@@ -1,10 +1,486 @@
-use media::{create_audio_interface, InterfaceTrait};
-
-mod media;
-mod platform;
-mod network;
+use audio_share::media::channels::ChannelMap;
+use audio_share::media::codec::{Codec, OpusSettings};
+use audio_share::media::jitter::{Concealment, JitterSettings};
+use audio_share::media::dither::{DitherSettings, NoiseShaping};
+use audio_share::media::format::SampleFormat;
+use audio_share::media::resample::ResampleQuality;
+use audio_share::media::{Device, DeviceKind, InterfaceTrait};
+use audio_share::network::ReconnectSettings;
+use audio_share::{create_audio_interface, Config, Mode, Overflow, Shutdown, Sink, Transport};
+use clap::{value_t_or_exit, App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::process;
+use std::time::Duration;
 
 fn main() {
-    let audio_interface = create_audio_interface();
-    let _ = audio_interface.start_recording();
+    let matches = App::new("audio-share")
+        .about("Shares system audio over the network")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("serve")
+            .about("Records audio and streams it to connecting clients")
+            .arg(Arg::with_name("host")
+                .long("host")
+                .takes_value(true)
+                .default_value("0.0.0.0")
+                .help("Address to listen on"))
+            .arg(Arg::with_name("name")
+                .long("name")
+                .takes_value(true)
+                .help("Name to advertise the server as on the local network. Defaults to the host name"))
+            .arg(Arg::with_name("no-advertise")
+                .long("no-advertise")
+                .help("Don't advertise the server for discovery"))
+            .arg(Arg::with_name("device")
+                .long("device")
+                .takes_value(true)
+                .help("Capture device to record from, by index or name as listed by `devices`. Defaults to the monitor of the default output"))
+            .arg(Arg::with_name("app")
+                .long("app")
+                .takes_value(true)
+                .conflicts_with("device")
+                .help("Capture only this application, by name or PID. Linux with PipeWire only"))
+            .arg(Arg::with_name("receiver")
+                .long("receiver")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|value| value.parse::<SocketAddr>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("Address to send the RTP stream to. Can be given more than once"))
+            .arg(Arg::with_name("bitrate")
+                .long("bitrate")
+                .takes_value(true)
+                .default_value("128000")
+                .validator(in_range(6_000, 510_000))
+                .help("Opus bitrate in bits per second"))
+            .arg(Arg::with_name("frame-size")
+                .long("frame-size")
+                .takes_value(true)
+                .possible_values(&["2.5", "5", "10", "20", "40", "60"])
+                .default_value("20")
+                .help("Milliseconds of audio in each Opus frame"))
+            .arg(Arg::with_name("complexity")
+                .long("complexity")
+                .takes_value(true)
+                .default_value("10")
+                .validator(in_range(0, 10))
+                .help("Opus encoder complexity from 0 to 10"))
+            .arg(Arg::with_name("no-dither")
+                .long("no-dither")
+                .help("Round float audio to s16 or s24 instead of dithering it"))
+            .arg(Arg::with_name("noise-shaping")
+                .long("noise-shaping")
+                .takes_value(true)
+                .possible_values(&["none", "first-order", "lipshitz"])
+                .default_value("none")
+                .help("Filter to shape the dither noise with, moving it to where it is least audible"))
+            .arg(Arg::with_name("transport-rate")
+                .long("transport-rate")
+                .takes_value(true)
+                .validator(in_range(1, u32::MAX))
+                .help("Sample rate in Hz to resample to before sending. Defaults to the rate audio is captured at"))
+            .arg(Arg::with_name("ttl")
+                .long("ttl")
+                .takes_value(true)
+                .default_value("1")
+                .validator(in_range(0, 255))
+                .help("How many routers multicast packets may cross"))
+            .arg(Arg::with_name("queue-size")
+                .long("queue-size")
+                .takes_value(true)
+                .default_value("50")
+                .validator(in_range(1, u32::MAX))
+                .help("Packets to queue for each TCP client before it counts as too slow"))
+            .arg(Arg::with_name("overflow")
+                .long("overflow")
+                .takes_value(true)
+                .possible_values(&["drop-oldest", "drop-newest", "disconnect"])
+                .default_value("drop-oldest")
+                .help("What to do when a TCP client's queue is full"))
+            .args(&common_args()))
+        .subcommand(SubCommand::with_name("play")
+            .about("Connects to a server and plays its stream")
+            .arg(Arg::with_name("host")
+                .long("host")
+                .takes_value(true)
+                .help("Address of the server to connect to, or of the interface to receive RTP on"))
+            .arg(Arg::with_name("name")
+                .long("name")
+                .takes_value(true)
+                .conflicts_with("host")
+                .help("Name of a server on the local network to connect to, as listed by `discover`"))
+            .arg(Arg::with_name("latency")
+                .long("latency")
+                .takes_value(true)
+                .default_value("60")
+                .help("Milliseconds of audio to buffer against late packets. Grows when the network is jittery"))
+            .arg(Arg::with_name("conceal")
+                .long("conceal")
+                .takes_value(true)
+                .possible_values(&["silence", "repeat"])
+                .default_value("silence")
+                .help("What to play when audio arrives too late"))
+            .arg(Arg::with_name("device")
+                .long("device")
+                .takes_value(true)
+                .help("Playback device to play to, by index or name as listed by `devices`. Defaults to the default output"))
+            .arg(Arg::with_name("sink")
+                .long("sink")
+                .takes_value(true)
+                .possible_values(&["auto", "pulse", "alsa", "pipewire"])
+                .default_value("auto")
+                .help("Audio API to play through on Linux. Auto uses the chosen device's API, or autoaudiosink without one"))
+            .arg(Arg::with_name("retries")
+                .long("retries")
+                .takes_value(true)
+                .help("Times to try reconnecting after losing the server before giving up. Retries forever by default"))
+            .arg(Arg::with_name("retry-delay")
+                .long("retry-delay")
+                .takes_value(true)
+                .default_value("500")
+                .help("Milliseconds to wait before the first reconnect attempt. Doubles after every failed attempt"))
+            .arg(Arg::with_name("max-retry-delay")
+                .long("max-retry-delay")
+                .takes_value(true)
+                .default_value("30000")
+                .help("Longest wait in milliseconds between reconnect attempts"))
+            .args(&common_args()))
+        .subcommand(SubCommand::with_name("devices")
+            .about("Lists the audio devices that can be recorded from or played to")
+            .arg(backend_arg()))
+        .subcommand(SubCommand::with_name("backends")
+            .about("Lists the audio backends in this build and whether they can run here"))
+        .subcommand(SubCommand::with_name("discover")
+            .about("Lists servers advertised on the local network")
+            .arg(Arg::with_name("timeout")
+                .long("timeout")
+                .takes_value(true)
+                .default_value("3")
+                .help("Seconds to listen for servers")))
+        .get_matches();
+
+    let (mode, config, audio_interface) = match matches.subcommand() {
+        ("serve", Some(matches)) => (Mode::Serve, parse_config(matches), audio_interface(matches)),
+        ("play", Some(matches)) => (Mode::Play, parse_config(matches), audio_interface(matches)),
+        ("devices", Some(matches)) => {
+            list_devices(audio_interface(matches).as_ref());
+            return;
+        }
+        ("backends", Some(_)) => {
+            list_backends();
+            return;
+        }
+        ("discover", Some(matches)) => {
+            discover(Duration::from_secs(value_t_or_exit!(matches, "timeout", u64)));
+            return;
+        }
+        _ => unreachable!("a subcommand is required"),
+    };
+
+    // The first Ctrl-C or SIGTERM stops cleanly, and a second one gives up waiting for that
+    let shutdown = Shutdown::new();
+    let signal_shutdown = shutdown.clone();
+    let handler = ctrlc::set_handler(move || {
+        if signal_shutdown.is_requested() {
+            process::exit(130);
+        }
+        signal_shutdown.request();
+    });
+    if let Err(error) = handler {
+        eprintln!("audio-share: could not install signal handler: {}", error);
+        process::exit(1);
+    }
+
+    let result = match mode {
+        Mode::Serve => audio_interface.start_recording(&config, &shutdown),
+        Mode::Play => audio_interface.start_playback(&config, &shutdown),
+    };
+
+    if let Err(error) = result {
+        eprintln!("audio-share: {}", error);
+        process::exit(1);
+    }
+}
+
+fn audio_interface(matches: &ArgMatches) -> Box<dyn InterfaceTrait> {
+    match create_audio_interface(matches.value_of("backend")) {
+        Ok(audio_interface) => audio_interface,
+        Err(error) => {
+            eprintln!("audio-share: {}", error);
+            process::exit(1);
+        }
+    }
+}
+
+fn list_backends() {
+    let backends = audio_share::media::backends();
+    if backends.is_empty() {
+        println!("This build has no audio backends");
+    }
+    for backend in backends {
+        let status = if backend.is_available() { "available" } else { "not available" };
+        println!("{:<10} {:<14} {}", backend.name, status, backend.description);
+    }
+}
+
+fn list_devices(audio_interface: &dyn InterfaceTrait) {
+    let devices = match audio_interface.devices() {
+        Ok(devices) => devices,
+        Err(error) => {
+            eprintln!("audio-share: {}", error);
+            process::exit(1);
+        }
+    };
+
+    for (kind, title) in &[(DeviceKind::Capture, "Capture devices"), (DeviceKind::Playback, "Playback devices")] {
+        println!("{}:", title);
+        let devices: Vec<&Device> = devices.iter().filter(|device| device.kind == *kind).collect();
+        if devices.is_empty() {
+            println!("  none");
+        }
+        for (index, device) in devices.iter().enumerate() {
+            let mut notes = vec![];
+            if device.monitor && *kind == DeviceKind::Capture {
+                notes.push("monitor");
+            }
+            if device.default {
+                notes.push("default");
+            }
+            let notes = if notes.is_empty() { String::new() } else { format!(" ({})", notes.join(", ")) };
+            println!("  {:>2}  {}{}\n      {}: {}", index, device.name, notes, device.api, device.id);
+        }
+    }
+}
+
+fn discover(timeout: Duration) {
+    let servers = match audio_share::network::discover(timeout) {
+        Ok(servers) => servers,
+        Err(error) => {
+            eprintln!("audio-share: {}", error);
+            process::exit(1);
+        }
+    };
+
+    if servers.is_empty() {
+        println!("No servers found");
+    }
+    for server in servers {
+        let address = match server.address() {
+            Some(address) => address.to_string(),
+            None => server.hostname.clone(),
+        };
+        match server.handshake {
+            Some(handshake) => println!(
+                "{}  {}  {:?} {:?} {} Hz, {} channels",
+                server.name,
+                address,
+                handshake.codec,
+                handshake.format.sample_format,
+                handshake.format.rate,
+                handshake.format.channels,
+            ),
+            None => println!("{}  {}  (unknown stream format)", server.name, address),
+        }
+    }
+}
+
+fn backend_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("backend")
+        .long("backend")
+        .takes_value(true)
+        .help("Audio backend to use, as listed by `backends`. Defaults to the first one that can run here")
+}
+
+fn common_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        backend_arg(),
+        Arg::with_name("port")
+            .long("port")
+            .takes_value(true)
+            .default_value("42795")
+            .help("Port to listen on or connect to"),
+        Arg::with_name("transport")
+            .long("transport")
+            .takes_value(true)
+            .possible_values(&["tcp", "rtp", "multicast"])
+            .default_value("tcp")
+            .help("Stream over TCP, as RTP over UDP, or as RTP to a multicast group"),
+        Arg::with_name("codec")
+            .long("codec")
+            .takes_value(true)
+            .possible_values(&["pcm", "opus", "flac"])
+            .default_value("pcm")
+            .help("Codec to send with, or to expect from an RTP sender"),
+        Arg::with_name("group")
+            .long("group")
+            .takes_value(true)
+            .default_value("239.255.42.95")
+            .help("Multicast group to send to or join"),
+        Arg::with_name("format")
+            .long("format")
+            .takes_value(true)
+            .possible_values(&["s16", "s24", "f32"])
+            .default_value("s16")
+            .help("Samples to send, or to expect from an RTP sender. Opus only sends s16"),
+        Arg::with_name("rate")
+            .long("rate")
+            .takes_value(true)
+            .default_value("48000")
+            .validator(in_range(1, u32::MAX))
+            .help("Sample rate in Hz, such as 44100, 48000 or 96000"),
+        Arg::with_name("resample-quality")
+            .long("resample-quality")
+            .takes_value(true)
+            .possible_values(&["low", "medium", "high"])
+            .default_value("high")
+            .help("Quality of resampling to the transport rate, and to the rate the playback device runs at"),
+        Arg::with_name("channels")
+            .long("channels")
+            .takes_value(true)
+            .default_value("2")
+            .validator(in_range(1, 8))
+            .help("Number of channels to send, mixed from however many are captured, or to expect from an RTP sender"),
+        Arg::with_name("channel-map")
+            .long("channel-map")
+            .takes_value(true)
+            .validator(|value| ChannelMap::parse(&value).map(|_| ()).map_err(|e| e.to_string()))
+            .help("Mix to use instead of the standard downmix or upmix: a row of comma separated gains for each output channel, with one gain for each input channel, separated by semicolons. 0,1;1,0 swaps left and right"),
+    ]
+}
+
+// Accepts whole numbers from `min` to `max` inclusive
+fn in_range(min: u32, max: u32) -> impl Fn(String) -> Result<(), String> {
+    move |value| match value.parse::<u32>() {
+        Ok(number) if (min..=max).contains(&number) => Ok(()),
+        Ok(_) if max == u32::MAX => Err(format!("must be at least {}", min)),
+        Ok(_) => Err(format!("must be from {} to {}", min, max)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_config(matches: &ArgMatches) -> Config {
+    let transport = match matches.value_of("transport") {
+        Some("rtp") => Transport::Rtp,
+        Some("multicast") => Transport::Multicast,
+        _ => Transport::Tcp,
+    };
+
+    // A TCP client has to know where the server is, but an RTP receiver can listen anywhere
+    let name = matches.value_of("name").map(|name| name.to_string());
+    let host = match matches.value_of("host") {
+        Some(host) => host,
+        None if transport != Transport::Tcp || name.is_some() => "0.0.0.0",
+        None => clap::Error::argument_not_found_auto("--host <host>").exit(),
+    };
+
+    let codec = match matches.value_of("codec") {
+        Some("opus") => Codec::Opus,
+        Some("flac") => Codec::Flac,
+        _ => Codec::Pcm,
+    };
+
+    // Encoder settings only exist for `serve`
+    let mut opus = OpusSettings::default();
+    if matches.is_present("bitrate") {
+        opus.bitrate = value_t_or_exit!(matches, "bitrate", u32);
+        opus.frame_duration = (value_t_or_exit!(matches, "frame-size", f32) * 1_000.0) as u32;
+        opus.complexity = value_t_or_exit!(matches, "complexity", u8);
+    }
+
+    // As do the dither settings
+    let mut dither = DitherSettings::default();
+    if matches.is_present("noise-shaping") {
+        dither.enabled = !matches.is_present("no-dither");
+        dither.shaping = match matches.value_of("noise-shaping") {
+            Some("first-order") => NoiseShaping::FirstOrder,
+            Some("lipshitz") => NoiseShaping::Lipshitz,
+            _ => NoiseShaping::None,
+        };
+    }
+
+    // Buffer settings only exist for `play`
+    let mut jitter = JitterSettings::default();
+    if matches.is_present("latency") {
+        jitter.latency = match value_t_or_exit!(matches, "latency", u32).checked_mul(1_000) {
+            Some(latency) => latency,
+            None => clap::Error::with_description(
+                &format!("Invalid value for '--latency <latency>': must be at most {}", u32::MAX / 1_000),
+                clap::ErrorKind::ValueValidation,
+            )
+            .exit(),
+        };
+        jitter.conceal = match matches.value_of("conceal") {
+            Some("repeat") => Concealment::Repeat,
+            _ => Concealment::Silence,
+        };
+    }
+
+    // As are the reconnect settings
+    let mut reconnect = ReconnectSettings::default();
+    if matches.is_present("retry-delay") {
+        if matches.is_present("retries") {
+            reconnect.retries = Some(value_t_or_exit!(matches, "retries", u32));
+        }
+        reconnect.initial_delay = Duration::from_millis(value_t_or_exit!(matches, "retry-delay", u64));
+        reconnect.max_delay = Duration::from_millis(value_t_or_exit!(matches, "max-retry-delay", u64));
+    }
+
+    let sample_format = match matches.value_of("format") {
+        Some("s24") => SampleFormat::S24LE,
+        Some("f32") => SampleFormat::F32LE,
+        _ => SampleFormat::S16LE,
+    };
+
+    let resample = match matches.value_of("resample-quality") {
+        Some("low") => ResampleQuality::Low,
+        Some("medium") => ResampleQuality::Medium,
+        _ => ResampleQuality::High,
+    };
+
+    let sink = match matches.value_of("sink") {
+        Some("pulse") => Sink::Pulse,
+        Some("alsa") => Sink::Alsa,
+        Some("pipewire") => Sink::PipeWire,
+        _ => Sink::Auto,
+    };
+
+    let overflow = match matches.value_of("overflow") {
+        Some("drop-newest") => Overflow::DropNewest,
+        Some("disconnect") => Overflow::Disconnect,
+        _ => Overflow::DropOldest,
+    };
+
+    let receivers = matches.values_of("receiver")
+        .map(|values| values.map(|value| value.parse().unwrap()).collect())
+        .unwrap_or_default();
+
+    Config {
+        host: host.to_string(),
+        port: value_t_or_exit!(matches, "port", u16),
+        name,
+        advertise: !matches.is_present("no-advertise"),
+        device: matches.value_of("device").map(|device| device.to_string()),
+        sink,
+        app: matches.value_of("app").map(|app| app.to_string()),
+        sample_format,
+        dither,
+        rate: value_t_or_exit!(matches, "rate", u32),
+        transport_rate: if matches.is_present("transport-rate") {
+            Some(value_t_or_exit!(matches, "transport-rate", u32))
+        } else {
+            None
+        },
+        resample,
+        channels: value_t_or_exit!(matches, "channels", u16),
+        channel_map: matches.value_of("channel-map").map(|map| ChannelMap::parse(map).unwrap()),
+        transport,
+        receivers,
+        group: value_t_or_exit!(matches, "group", Ipv4Addr),
+        ttl: if matches.is_present("ttl") { value_t_or_exit!(matches, "ttl", u32) } else { 1 },
+        queue_size: if matches.is_present("queue-size") { value_t_or_exit!(matches, "queue-size", usize) } else { 50 },
+        overflow,
+        codec,
+        opus,
+        jitter,
+        reconnect,
+    }
 }
@@ -0,0 +1,99 @@
+// The audio backends compiled into this build. Each is enabled with a `backend-*` cargo feature and
+// only builds on the platforms it supports, so one binary can carry several and pick one at runtime.
+
+use crate::error::{BackendError, Error, Result};
+use crate::media::InterfaceTrait;
+
+#[cfg(all(target_os = "linux", feature = "backend-gstreamer"))]
+use crate::platform::linux;
+
+#[cfg(all(target_os = "windows", feature = "backend-wasapi"))]
+use crate::platform::windows;
+
+#[cfg(feature = "backend-null")]
+use crate::platform::null;
+
+pub struct Backend {
+    // What `--backend` takes
+    pub name: &'static str,
+    pub description: &'static str,
+    // Never picked unless asked for by name
+    pub explicit: bool,
+    available: fn() -> bool,
+    create: fn() -> Box<dyn InterfaceTrait>,
+}
+
+impl Backend {
+    // Whether the backend can run on this machine, such as GStreamer being installed
+    pub fn is_available(&self) -> bool {
+        (self.available)()
+    }
+
+    pub fn create(&self) -> Box<dyn InterfaceTrait> {
+        (self.create)()
+    }
+}
+
+// Every compiled in backend, in the order they are tried when none is named
+#[allow(clippy::vec_init_then_push)]
+pub fn backends() -> Vec<Backend> {
+    #[allow(unused_mut)]
+    let mut backends = vec![];
+
+    #[cfg(all(target_os = "linux", feature = "backend-gstreamer"))]
+    backends.push(Backend {
+        name: "gstreamer",
+        description: "PulseAudio or PipeWire capture and any GStreamer sink for playback",
+        explicit: false,
+        available: linux::is_available,
+        create: || Box::new(linux::Interface::new()),
+    });
+
+    #[cfg(all(target_os = "windows", feature = "backend-wasapi"))]
+    backends.push(Backend {
+        name: "wasapi",
+        description: "WASAPI loopback capture and shared mode playback",
+        explicit: false,
+        available: windows::is_available,
+        create: || Box::new(windows::Interface::new()),
+    });
+
+    // Nobody would hear anything, so it is never the default
+    #[cfg(feature = "backend-null")]
+    backends.push(Backend {
+        name: "null",
+        description: "Streams a 440 Hz test tone and discards playback, without any audio hardware",
+        explicit: true,
+        available: || true,
+        create: || Box::new(null::Interface::new(null::Settings { realtime: true, ..null::Settings::default() })),
+    });
+
+    backends
+}
+
+// The backend called `name`, or the first available one that can be picked automatically
+pub fn create_audio_interface(name: Option<&str>) -> Result<Box<dyn InterfaceTrait>> {
+    let backends = backends();
+    let names = backends.iter().map(|backend| backend.name).collect::<Vec<_>>().join(", ");
+
+    let backend = match name {
+        Some(name) => {
+            let backend = backends.iter().find(|backend| backend.name == name).ok_or_else(|| {
+                unavailable(format!("there is no {} backend in this build, it has: {}", name, names))
+            })?;
+            if !backend.is_available() {
+                return Err(unavailable(format!("the {} backend can't run on this machine", name)));
+            }
+            backend
+        }
+        None => backends.iter()
+            .find(|backend| !backend.explicit && backend.is_available())
+            .ok_or_else(|| unavailable(format!("none of the audio backends can run on this machine ({})", names)))?,
+    };
+
+    Ok(backend.create())
+}
+
+fn unavailable(message: String) -> Error {
+    Error::Backend(BackendError::Unavailable(message))
+}
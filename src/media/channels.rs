@@ -0,0 +1,440 @@
+// Which speaker each channel of a stream or device is for, and mixing between two layouts. The
+// standard mix keeps every channel the output also has, folds the rest into their nearest
+// neighbours with the ITU-R BS.775 coefficients, drops the LFE channel as that recommendation
+// does, and plays mono at full level on both front speakers. A custom map replaces all of that.
+
+use crate::error::{Error, Result};
+use std::f32::consts::FRAC_1_SQRT_2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    FrontLeft,
+    FrontRight,
+    FrontCenter,
+    LowFrequency,
+    BackLeft,
+    BackRight,
+    BackCenter,
+    SideLeft,
+    SideRight,
+    // A channel with no known speaker, which only mixes into the same channel of the output
+    Aux(u16),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelLayout {
+    channels: Vec<Channel>,
+}
+
+impl ChannelLayout {
+    pub fn new(channels: Vec<Channel>) -> ChannelLayout {
+        ChannelLayout { channels }
+    }
+
+    // The usual layout for `count` channels, in the order WAVE files and WASAPI use: mono, stereo,
+    // 3.0, quad, 5.0, 5.1, 6.1 and 7.1. Channels past the eighth have no speaker
+    pub fn standard(count: u16) -> ChannelLayout {
+        use Channel::*;
+        let speakers: &[Channel] = match count {
+            1 => &[FrontCenter],
+            2 => &[FrontLeft, FrontRight],
+            3 => &[FrontLeft, FrontRight, FrontCenter],
+            4 => &[FrontLeft, FrontRight, BackLeft, BackRight],
+            5 => &[FrontLeft, FrontRight, FrontCenter, BackLeft, BackRight],
+            6 => &[FrontLeft, FrontRight, FrontCenter, LowFrequency, BackLeft, BackRight],
+            7 => &[FrontLeft, FrontRight, FrontCenter, LowFrequency, BackCenter, SideLeft, SideRight],
+            _ => &[FrontLeft, FrontRight, FrontCenter, LowFrequency, BackLeft, BackRight, SideLeft, SideRight],
+        };
+        let channels = (0..count)
+            .map(|index| speakers.get(index as usize).copied().unwrap_or(Aux(index)))
+            .collect();
+        ChannelLayout { channels }
+    }
+
+    // The layout of `count` channels described by a WAVEFORMATEXTENSIBLE speaker mask, where the
+    // channels come in the order of the mask's bits
+    pub fn from_mask(mask: u32, count: u16) -> ChannelLayout {
+        use Channel::*;
+        // Speakers that aren't listed, such as front left of center and the top speakers, get no
+        // position
+        let speakers = [
+            (0x1, FrontLeft),
+            (0x2, FrontRight),
+            (0x4, FrontCenter),
+            (0x8, LowFrequency),
+            (0x10, BackLeft),
+            (0x20, BackRight),
+            (0x100, BackCenter),
+            (0x200, SideLeft),
+            (0x400, SideRight),
+        ];
+
+        let mut channels = vec![];
+        for bit in 0..32 {
+            if mask & (1 << bit) != 0 {
+                let index = channels.len() as u16;
+                let speaker = speakers.iter().find(|(speaker, _)| *speaker == 1 << bit);
+                channels.push(speaker.map_or(Aux(index), |(_, channel)| *channel));
+            }
+        }
+        // A mask that doesn't add up to the channel count is no use
+        if channels.len() != count as usize {
+            return ChannelLayout::standard(count);
+        }
+        ChannelLayout { channels }
+    }
+
+    pub fn channels(&self) -> &[Channel] {
+        &self.channels
+    }
+
+    pub fn len(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty()
+    }
+
+    fn has(&self, channel: Channel) -> bool {
+        self.channels.contains(&channel)
+    }
+}
+
+// A mix given by the user: one row of gains for each output channel, with one gain for each input
+// channel
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelMap {
+    rows: Vec<Vec<f32>>,
+}
+
+impl ChannelMap {
+    // Parses rows separated by semicolons of gains separated by commas, so "0,1;1,0" swaps left
+    // and right and "0.5,0.5" mixes stereo down to mono
+    pub fn parse(text: &str) -> Result<ChannelMap> {
+        let parse_row = |row: &str| row.split(',').map(|gain| gain.trim().parse::<f32>()).collect::<std::result::Result<Vec<_>, _>>();
+        let rows = text.split(';')
+            .map(parse_row)
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|error| Error::Format(format!("invalid channel map {:?}: {}", text, error)))?;
+
+        if rows.iter().any(|row| row.len() != rows[0].len()) {
+            return Err(Error::Format(format!("every row of channel map {:?} needs a gain for each input", text)));
+        }
+        Ok(ChannelMap { rows })
+    }
+
+    pub fn inputs(&self) -> usize {
+        self.rows[0].len()
+    }
+
+    pub fn outputs(&self) -> usize {
+        self.rows.len()
+    }
+}
+
+pub struct ChannelMixer {
+    inputs: usize,
+    outputs: usize,
+    // Gain from each input channel to each output channel, a row of inputs for each output
+    matrix: Vec<f32>,
+}
+
+impl ChannelMixer {
+    // The standard mix from one layout to another. Outputs that would sum to more than full scale
+    // are turned down so they can't clip
+    pub fn new(from: &ChannelLayout, to: &ChannelLayout) -> ChannelMixer {
+        let (inputs, outputs) = (from.len(), to.len());
+        let mut matrix = vec![0.0; inputs * outputs];
+
+        let mono = from.channels == [Channel::FrontCenter];
+        for (input, channel) in from.channels.iter().enumerate() {
+            for (target, gain) in fold(*channel, mono, to) {
+                let output = to.channels.iter().position(|channel| *channel == target).unwrap();
+                matrix[output * inputs + input] += gain;
+            }
+        }
+
+        for row in matrix.chunks_mut(inputs.max(1)) {
+            let sum: f32 = row.iter().sum();
+            if sum > 1.0 {
+                for gain in row.iter_mut() {
+                    *gain /= sum;
+                }
+            }
+        }
+
+        ChannelMixer { inputs, outputs, matrix }
+    }
+
+    // Mixes with `map` if there is one, which has to take the channels of `from` to those of `to`,
+    // or else with the standard mix
+    pub fn with_map(map: Option<&ChannelMap>, from: &ChannelLayout, to: &ChannelLayout) -> Result<ChannelMixer> {
+        let map = match map {
+            Some(map) => map,
+            None => return Ok(ChannelMixer::new(from, to)),
+        };
+
+        if map.inputs() != from.len() || map.outputs() != to.len() {
+            return Err(Error::Format(format!(
+                "the channel map mixes {} channels into {}, but has to mix {} into {}",
+                map.inputs(),
+                map.outputs(),
+                from.len(),
+                to.len()
+            )));
+        }
+        Ok(ChannelMixer {
+            inputs: map.inputs(),
+            outputs: map.outputs(),
+            matrix: map.rows.concat(),
+        })
+    }
+
+    pub fn inputs(&self) -> usize {
+        self.inputs
+    }
+
+    pub fn outputs(&self) -> usize {
+        self.outputs
+    }
+
+    // Whether every channel goes straight through, so mixing can be skipped
+    pub fn is_identity(&self) -> bool {
+        self.inputs == self.outputs && self.matrix.iter().enumerate().all(|(index, gain)| {
+            *gain == if index / self.inputs == index % self.inputs { 1.0 } else { 0.0 }
+        })
+    }
+
+    // Mixes as many whole frames of `input` as fit in `output`, and returns how many
+    pub fn process(&self, input: &[f32], output: &mut [f32]) -> usize {
+        let frames = (input.len() / self.inputs).min(output.len() / self.outputs);
+        let input_frames = input.chunks_exact(self.inputs);
+        for (input, output) in input_frames.zip(output.chunks_exact_mut(self.outputs)).take(frames) {
+            for (output, gains) in output.iter_mut().zip(self.matrix.chunks_exact(self.inputs)) {
+                *output = input.iter().zip(gains).map(|(sample, gain)| sample * gain).sum();
+            }
+        }
+        frames
+    }
+
+    // Mixes all of `input`, resizing `output` to fit
+    pub fn mix(&self, input: &[f32], output: &mut Vec<f32>) {
+        output.resize(input.len() / self.inputs * self.outputs, 0.0);
+        self.process(input, output);
+    }
+}
+
+// The output channels `channel` plays on and their gains. Surround channels go to the surround
+// channels the output has, then to the front, and the front goes to the center for mono
+fn fold(channel: Channel, mono: bool, to: &ChannelLayout) -> Vec<(Channel, f32)> {
+    use Channel::*;
+    if to.has(channel) {
+        return vec![(channel, 1.0)];
+    }
+
+    let front = |left: Channel, right: Channel, gain: f32| -> Vec<(Channel, f32)> {
+        if to.has(left) && to.has(right) {
+            vec![(left, gain), (right, gain)]
+        } else {
+            vec![]
+        }
+    };
+    let side = |front: Channel, other: Channel| -> Vec<(Channel, f32)> {
+        if to.has(other) {
+            vec![(other, 1.0)]
+        } else if to.has(front) {
+            vec![(front, FRAC_1_SQRT_2)]
+        } else if to.has(FrontCenter) {
+            vec![(FrontCenter, 0.5)]
+        } else {
+            vec![]
+        }
+    };
+
+    match channel {
+        FrontCenter if mono => front(FrontLeft, FrontRight, 1.0),
+        FrontCenter => front(FrontLeft, FrontRight, FRAC_1_SQRT_2),
+        FrontLeft | FrontRight if to.has(FrontCenter) => vec![(FrontCenter, FRAC_1_SQRT_2)],
+        BackLeft => side(FrontLeft, SideLeft),
+        BackRight => side(FrontRight, SideRight),
+        SideLeft => side(FrontLeft, BackLeft),
+        SideRight => side(FrontRight, BackRight),
+        BackCenter => {
+            let mut targets = fold(BackLeft, false, to);
+            targets.extend(fold(BackRight, false, to));
+            targets.into_iter().map(|(target, gain)| (target, gain * FRAC_1_SQRT_2)).collect()
+        }
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Channel::*;
+
+    // Gains of the standard mix, a row of inputs for each output, checked to within float rounding
+    fn assert_mix(from: &ChannelLayout, to: &ChannelLayout, expected: &[&[f32]]) {
+        let mixer = ChannelMixer::new(from, to);
+        let rows: Vec<&[f32]> = mixer.matrix.chunks(mixer.inputs).collect();
+        assert_eq!(rows.len(), expected.len());
+        for (row, expected) in rows.iter().zip(expected) {
+            assert_eq!(row.len(), expected.len());
+            for (gain, expected) in row.iter().zip(expected.iter()) {
+                assert!((gain - expected).abs() < 1e-6, "mix {:?} to {:?}: {:?}", from, to, rows);
+            }
+        }
+    }
+
+    #[test]
+    fn standard_layouts() {
+        assert_eq!(ChannelLayout::standard(1).channels(), &[FrontCenter]);
+        assert_eq!(ChannelLayout::standard(6).channels()[3], LowFrequency);
+        assert_eq!(ChannelLayout::standard(8).channels()[6..], [SideLeft, SideRight]);
+        assert_eq!(ChannelLayout::standard(10).channels()[8..], [Aux(8), Aux(9)]);
+    }
+
+    #[test]
+    fn layouts_from_speaker_masks() {
+        assert_eq!(ChannelLayout::from_mask(0x3f, 6), ChannelLayout::standard(6));
+        assert_eq!(ChannelLayout::from_mask(0x63f, 8), ChannelLayout::standard(8));
+        // 5.1 with side rather than back speakers
+        assert_eq!(
+            ChannelLayout::from_mask(0x60f, 6).channels(),
+            &[FrontLeft, FrontRight, FrontCenter, LowFrequency, SideLeft, SideRight]
+        );
+        // Front left of center has no position
+        assert_eq!(ChannelLayout::from_mask(0x43, 3).channels(), &[FrontLeft, FrontRight, Aux(2)]);
+        // Too few or too many bits for the channels
+        assert_eq!(ChannelLayout::from_mask(0x3, 6), ChannelLayout::standard(6));
+        assert_eq!(ChannelLayout::from_mask(0x3f, 2), ChannelLayout::standard(2));
+    }
+
+    #[test]
+    fn parses_channel_maps() {
+        let map = ChannelMap::parse("0,1;1,0").unwrap();
+        assert_eq!((map.inputs(), map.outputs()), (2, 2));
+        assert_eq!(map.rows, vec![vec![0.0, 1.0], vec![1.0, 0.0]]);
+
+        let map = ChannelMap::parse("0.5, 0.5").unwrap();
+        assert_eq!((map.inputs(), map.outputs()), (2, 1));
+        assert_eq!(map.rows, vec![vec![0.5, 0.5]]);
+
+        for text in &["", "1,", "a,1", "1;0,1", "0,1;1"] {
+            assert!(matches!(ChannelMap::parse(text), Err(Error::Format(_))), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn downmixes_surround_to_stereo() {
+        // Center and surround at -3 dB, then each output scaled down by its total of 1 + 2 / √2
+        let (front, other) = (1.0 / (1.0 + 2.0 * FRAC_1_SQRT_2), FRAC_1_SQRT_2 / (1.0 + 2.0 * FRAC_1_SQRT_2));
+        assert_mix(&ChannelLayout::standard(6), &ChannelLayout::standard(2), &[
+            &[front, 0.0, other, 0.0, other, 0.0],
+            &[0.0, front, other, 0.0, 0.0, other],
+        ]);
+
+        // Back and side channels both fold into the front, for a total of 1 + 3 / √2
+        let (front, other) = (1.0 / (1.0 + 3.0 * FRAC_1_SQRT_2), FRAC_1_SQRT_2 / (1.0 + 3.0 * FRAC_1_SQRT_2));
+        assert_mix(&ChannelLayout::standard(8), &ChannelLayout::standard(2), &[
+            &[front, 0.0, other, 0.0, other, 0.0, other, 0.0],
+            &[0.0, front, other, 0.0, 0.0, other, 0.0, other],
+        ]);
+    }
+
+    #[test]
+    fn downmixes_surround_to_mono() {
+        // Front left and right at -3 dB, surround at -6 dB and the LFE dropped, over a total of
+        // 1 + √2 + 1
+        let total = 2.0 + 2.0 * FRAC_1_SQRT_2;
+        let (front, center, back) = (FRAC_1_SQRT_2 / total, 1.0 / total, 0.5 / total);
+        assert_mix(&ChannelLayout::standard(6), &ChannelLayout::standard(1), &[
+            &[front, front, center, 0.0, back, back],
+        ]);
+
+        let total = 3.0 + 2.0 * FRAC_1_SQRT_2;
+        let (front, center, back) = (FRAC_1_SQRT_2 / total, 1.0 / total, 0.5 / total);
+        assert_mix(&ChannelLayout::standard(8), &ChannelLayout::standard(1), &[
+            &[front, front, center, 0.0, back, back, back, back],
+        ]);
+
+        assert_mix(&ChannelLayout::standard(2), &ChannelLayout::standard(1), &[&[0.5, 0.5]]);
+    }
+
+    #[test]
+    fn upmixes() {
+        // Mono plays at full level on both sides, stereo is left as it is in 5.1
+        assert_mix(&ChannelLayout::standard(1), &ChannelLayout::standard(2), &[&[1.0], &[1.0]]);
+        assert_mix(&ChannelLayout::standard(2), &ChannelLayout::standard(6), &[
+            &[1.0, 0.0],
+            &[0.0, 1.0],
+            &[0.0, 0.0],
+            &[0.0, 0.0],
+            &[0.0, 0.0],
+            &[0.0, 0.0],
+        ]);
+    }
+
+    #[test]
+    fn folds_into_the_nearest_speakers() {
+        // In quad the sides join the back, and the center splits between the front at -3 dB
+        let (front, center) = (1.0 / (1.0 + FRAC_1_SQRT_2), FRAC_1_SQRT_2 / (1.0 + FRAC_1_SQRT_2));
+        assert_mix(&ChannelLayout::standard(8), &ChannelLayout::standard(4), &[
+            &[front, 0.0, center, 0.0, 0.0, 0.0, 0.0, 0.0],
+            &[0.0, front, center, 0.0, 0.0, 0.0, 0.0, 0.0],
+            &[0.0, 0.0, 0.0, 0.0, 0.5, 0.0, 0.5, 0.0],
+            &[0.0, 0.0, 0.0, 0.0, 0.0, 0.5, 0.0, 0.5],
+        ]);
+
+        // Channels with no speaker only go to the same channel
+        let layout = ChannelLayout::from_mask(0x43, 3);
+        assert_mix(&layout, &ChannelLayout::standard(2), &[&[1.0, 0.0, 0.0], &[0.0, 1.0, 0.0]]);
+    }
+
+    #[test]
+    fn recognises_identity_mixes() {
+        for count in 1..=10 {
+            let layout = ChannelLayout::standard(count);
+            assert!(ChannelMixer::new(&layout, &layout).is_identity(), "{} channels", count);
+        }
+        assert!(!ChannelMixer::new(&ChannelLayout::standard(2), &ChannelLayout::standard(1)).is_identity());
+        assert!(!ChannelMixer::new(&ChannelLayout::standard(1), &ChannelLayout::standard(2)).is_identity());
+
+        let stereo = ChannelLayout::standard(2);
+        let straight = ChannelMap::parse("1,0;0,1").unwrap();
+        assert!(ChannelMixer::with_map(Some(&straight), &stereo, &stereo).unwrap().is_identity());
+        let swapped = ChannelMap::parse("0,1;1,0").unwrap();
+        assert!(!ChannelMixer::with_map(Some(&swapped), &stereo, &stereo).unwrap().is_identity());
+    }
+
+    #[test]
+    fn maps_must_fit_the_layouts() {
+        let map = ChannelMap::parse("0.5,0.5").unwrap();
+        let mixer = ChannelMixer::with_map(Some(&map), &ChannelLayout::standard(2), &ChannelLayout::standard(1));
+        assert_eq!(mixer.unwrap().matrix, vec![0.5, 0.5]);
+        let mixer = ChannelMixer::with_map(Some(&map), &ChannelLayout::standard(6), &ChannelLayout::standard(1));
+        assert!(matches!(mixer, Err(Error::Format(_))));
+    }
+
+    #[test]
+    fn mixes_whole_frames() {
+        let map = ChannelMap::parse("0,1;1,0").unwrap();
+        let stereo = ChannelLayout::standard(2);
+        let swap = ChannelMixer::with_map(Some(&map), &stereo, &stereo).unwrap();
+
+        // Three and a half frames in, room for two out
+        let input = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0];
+        let mut output = [0.0; 4];
+        assert_eq!(swap.process(&input, &mut output), 2);
+        assert_eq!(output, [2.0, 1.0, 4.0, 3.0]);
+
+        let mut output = vec![];
+        swap.mix(&input, &mut output);
+        assert_eq!(output, vec![2.0, 1.0, 4.0, 3.0, 6.0, 5.0]);
+
+        let downmix = ChannelMixer::new(&stereo, &ChannelLayout::standard(1));
+        downmix.mix(&input, &mut output);
+        assert_eq!(output, vec![1.5, 3.5, 5.5]);
+    }
+}
@@ -0,0 +1,1020 @@
+// Lossless compression using FLAC frames (https://xiph.org/flac/format.html). Each encoded packet is
+// one complete frame, so no STREAMINFO block is needed: every frame header carries its own block
+// size, sample rate, sample size and channel assignment.
+//
+// The encoder uses the fixed polynomial predictors with Rice coded residuals, and picks the best
+// stereo decorrelation for each block. The decoder also reads LPC subframes, so it can decode frames
+// from other encoders as long as they match the handshake's format.
+
+use crate::error::{Error, Result};
+use crate::media::codec::{Decoder, Encoded, Encoder};
+use crate::media::format::{SampleFormat, StreamFormat};
+use byteorder::{ByteOrder, LittleEndian};
+
+// Frames per block. Larger blocks compress slightly better at the cost of latency
+pub const BLOCK_SIZE: usize = 1024;
+
+const MAX_FIXED_ORDER: usize = 4;
+const MAX_PARTITION_ORDER: u32 = 6;
+
+const SUBFRAME_CONSTANT: u32 = 0b000000;
+const SUBFRAME_VERBATIM: u32 = 0b000001;
+const SUBFRAME_FIXED: u32 = 0b001000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ChannelAssignment {
+    Independent,
+    LeftSide,
+    RightSide,
+    MidSide,
+}
+
+impl ChannelAssignment {
+    fn to_code(self, channels: usize) -> u32 {
+        match self {
+            ChannelAssignment::Independent => channels as u32 - 1,
+            ChannelAssignment::LeftSide => 0b1000,
+            ChannelAssignment::RightSide => 0b1001,
+            ChannelAssignment::MidSide => 0b1010,
+        }
+    }
+
+    // Whether the subframe for `channel` carries a side channel, which needs an extra bit
+    fn is_side(self, channel: usize) -> bool {
+        match self {
+            ChannelAssignment::Independent => false,
+            ChannelAssignment::LeftSide | ChannelAssignment::MidSide => channel == 1,
+            ChannelAssignment::RightSide => channel == 0,
+        }
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::Format(format!("flac: {}", message))
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc: u8 = 0;
+    for &byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    accumulator: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter { bytes: vec![], accumulator: 0, bits: 0 }
+    }
+
+    fn write(&mut self, value: u32, bits: u32) {
+        debug_assert!(bits <= 32);
+        if bits == 0 {
+            return;
+        }
+        let mask = if bits == 32 { u32::MAX } else { (1 << bits) - 1 };
+        self.accumulator = (self.accumulator << bits) | (value & mask) as u64;
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.accumulator >> self.bits) as u8);
+        }
+    }
+
+    fn write_signed(&mut self, value: i32, bits: u32) {
+        self.write(value as u32, bits);
+    }
+
+    fn write_unary(&mut self, zeros: u32) {
+        let mut remaining = zeros;
+        while remaining >= 32 {
+            self.write(0, 32);
+            remaining -= 32;
+        }
+        self.write(1, remaining + 1);
+    }
+
+    fn write_rice(&mut self, value: i32, parameter: u32) {
+        let folded = ((value << 1) ^ (value >> 31)) as u32;
+        self.write_unary(folded >> parameter);
+        self.write(folded, parameter);
+    }
+
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, position: 0 }
+    }
+
+    fn read(&mut self, bits: u32) -> Result<u32> {
+        let mut value: u64 = 0;
+        for _ in 0..bits {
+            let byte = *self.bytes.get(self.position / 8).ok_or_else(|| invalid("frame is truncated"))?;
+            let bit = (byte >> (7 - self.position % 8)) & 1;
+            value = (value << 1) | bit as u64;
+            self.position += 1;
+        }
+        Ok(value as u32)
+    }
+
+    fn read_signed(&mut self, bits: u32) -> Result<i32> {
+        let value = self.read(bits)?;
+        if bits == 0 || bits == 32 {
+            return Ok(value as i32);
+        }
+        let shift = 32 - bits;
+        Ok(((value << shift) as i32) >> shift)
+    }
+
+    fn read_unary(&mut self) -> Result<u32> {
+        let mut zeros = 0;
+        while self.read(1)? == 0 {
+            zeros += 1;
+        }
+        Ok(zeros)
+    }
+
+    fn read_rice(&mut self, parameter: u32) -> Result<i32> {
+        let quotient = self.read_unary()?;
+        let folded = (quotient << parameter) | self.read(parameter)?;
+        Ok(((folded >> 1) as i32) ^ -((folded & 1) as i32))
+    }
+
+    fn align(&mut self) {
+        self.position = self.position.div_ceil(8) * 8;
+    }
+
+    fn byte_position(&self) -> usize {
+        self.position / 8
+    }
+}
+
+// Residual of the fixed predictor of `order` for every sample after the warm-up ones
+fn fixed_residual(samples: &[i64], order: usize) -> Vec<i64> {
+    samples[order..]
+        .iter()
+        .enumerate()
+        .map(|(i, &sample)| {
+            let i = i + order;
+            let prediction = match order {
+                0 => 0,
+                1 => samples[i - 1],
+                2 => 2 * samples[i - 1] - samples[i - 2],
+                3 => 3 * samples[i - 1] - 3 * samples[i - 2] + samples[i - 3],
+                _ => 4 * samples[i - 1] - 6 * samples[i - 2] + 4 * samples[i - 3] - samples[i - 4],
+            };
+            sample - prediction
+        })
+        .collect()
+}
+
+fn fold(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+// Finds the Rice parameter that codes `residual` in the fewest bits, and how many bits that is
+fn best_rice_parameter(residual: &[i64], max_parameter: u32) -> (u32, u64) {
+    let sum: u64 = residual.iter().map(|&value| fold(value)).sum();
+    let mut best = (0, u64::MAX);
+    for parameter in 0..=max_parameter {
+        let bits = residual.len() as u64 * (parameter as u64 + 1) + (sum >> parameter);
+        if bits < best.1 {
+            best = (parameter, bits);
+        }
+    }
+    best
+}
+
+struct RiceCoding {
+    partition_order: u32,
+    parameters: Vec<u32>,
+    parameter_bits: u32,
+    bits: u64,
+}
+
+fn best_rice_coding(residual: &[i64], block_size: usize, order: usize, bits_per_sample: u32) -> RiceCoding {
+    // Deep samples can need parameters above 14, which only the five bit coding method can hold
+    let parameter_bits = if bits_per_sample > 17 { 5 } else { 4 };
+    let max_parameter = (1 << parameter_bits) - 2;
+
+    let mut best: Option<RiceCoding> = None;
+    for partition_order in 0..=MAX_PARTITION_ORDER {
+        let partitions = 1 << partition_order;
+        if block_size % partitions != 0 || block_size / partitions <= order {
+            break;
+        }
+
+        let partition_size = block_size / partitions;
+        let mut parameters = Vec::with_capacity(partitions);
+        let mut bits = 0;
+        let mut start = 0;
+        for partition in 0..partitions {
+            let length = if partition == 0 { partition_size - order } else { partition_size };
+            let (parameter, partition_bits) = best_rice_parameter(&residual[start..start + length], max_parameter);
+            parameters.push(parameter);
+            bits += partition_bits + parameter_bits as u64;
+            start += length;
+        }
+
+        if best.as_ref().map_or(true, |coding| bits < coding.bits) {
+            best = Some(RiceCoding { partition_order, parameters, parameter_bits, bits });
+        }
+    }
+    best.unwrap()
+}
+
+enum Subframe {
+    Constant(i64),
+    Verbatim,
+    Fixed { order: usize, residual: Vec<i64>, coding: RiceCoding },
+}
+
+struct EncodedSubframe {
+    subframe: Subframe,
+    bits: u64,
+}
+
+fn plan_subframe(samples: &[i64], bits_per_sample: u32) -> EncodedSubframe {
+    if samples.iter().all(|&sample| sample == samples[0]) {
+        return EncodedSubframe { subframe: Subframe::Constant(samples[0]), bits: 8 + bits_per_sample as u64 };
+    }
+
+    let mut best = EncodedSubframe {
+        subframe: Subframe::Verbatim,
+        bits: 8 + bits_per_sample as u64 * samples.len() as u64,
+    };
+    for order in 0..=MAX_FIXED_ORDER.min(samples.len() - 1) {
+        let residual = fixed_residual(samples, order);
+        if residual.iter().any(|&value| value > i32::MAX as i64 || value < i32::MIN as i64) {
+            continue;
+        }
+
+        let coding = best_rice_coding(&residual, samples.len(), order, bits_per_sample);
+        let bits = 8 + order as u64 * bits_per_sample as u64 + 6 + coding.bits;
+        if bits < best.bits {
+            best = EncodedSubframe { subframe: Subframe::Fixed { order, residual, coding }, bits };
+        }
+    }
+    best
+}
+
+fn write_subframe(writer: &mut BitWriter, samples: &[i64], encoded: &EncodedSubframe, bits_per_sample: u32) {
+    match &encoded.subframe {
+        Subframe::Constant(value) => {
+            writer.write(SUBFRAME_CONSTANT << 1, 8);
+            writer.write_signed(*value as i32, bits_per_sample);
+        }
+        Subframe::Verbatim => {
+            writer.write(SUBFRAME_VERBATIM << 1, 8);
+            for &sample in samples {
+                writer.write_signed(sample as i32, bits_per_sample);
+            }
+        }
+        Subframe::Fixed { order, residual, coding } => {
+            writer.write((SUBFRAME_FIXED | *order as u32) << 1, 8);
+            for &sample in &samples[..*order] {
+                writer.write_signed(sample as i32, bits_per_sample);
+            }
+
+            writer.write(if coding.parameter_bits == 5 { 0b01 } else { 0b00 }, 2);
+            writer.write(coding.partition_order, 4);
+
+            let partition_size = samples.len() >> coding.partition_order;
+            let mut start = 0;
+            for (partition, &parameter) in coding.parameters.iter().enumerate() {
+                let length = if partition == 0 { partition_size - order } else { partition_size };
+                writer.write(parameter, coding.parameter_bits);
+                for &value in &residual[start..start + length] {
+                    writer.write_rice(value as i32, parameter);
+                }
+                start += length;
+            }
+        }
+    }
+}
+
+fn write_utf8_number(writer: &mut BitWriter, value: u32) {
+    if value < 0x80 {
+        writer.write(value, 8);
+        return;
+    }
+
+    let mut continuation_bytes = 1;
+    while value >> (6 * continuation_bytes + 6 - continuation_bytes) != 0 {
+        continuation_bytes += 1;
+    }
+    let lead_marker = (0xff00_u32 >> (continuation_bytes + 1)) & 0xff;
+    writer.write(lead_marker | (value >> (6 * continuation_bytes)), 8);
+    for byte in (0..continuation_bytes).rev() {
+        writer.write(0x80 | ((value >> (6 * byte)) & 0x3f), 8);
+    }
+}
+
+fn read_utf8_number(reader: &mut BitReader) -> Result<u32> {
+    let first = reader.read(8)?;
+    if first & 0x80 == 0 {
+        return Ok(first);
+    }
+
+    // The number of leading ones is the length of the number in bytes
+    let length = (!first << 24).leading_zeros();
+    if !(2..=7).contains(&length) {
+        return Err(invalid("bad frame number"));
+    }
+
+    let mut value = (first & (0x7f >> length)) as u64;
+    for _ in 1..length {
+        let byte = reader.read(8)?;
+        if byte & 0xc0 != 0x80 {
+            return Err(invalid("bad frame number"));
+        }
+        value = (value << 6) | (byte & 0x3f) as u64;
+    }
+    if value > u32::MAX as u64 {
+        return Err(invalid("frame number is too large"));
+    }
+    Ok(value as u32)
+}
+
+fn block_size_code(block_size: usize) -> (u32, Option<(u32, u32)>) {
+    match block_size {
+        192 => (0b0001, None),
+        576 => (0b0010, None),
+        1152 => (0b0011, None),
+        2304 => (0b0100, None),
+        4608 => (0b0101, None),
+        256 => (0b1000, None),
+        512 => (0b1001, None),
+        1024 => (0b1010, None),
+        2048 => (0b1011, None),
+        4096 => (0b1100, None),
+        8192 => (0b1101, None),
+        16384 => (0b1110, None),
+        32768 => (0b1111, None),
+        size if size <= 256 => (0b0110, Some((size as u32 - 1, 8))),
+        size => (0b0111, Some((size as u32 - 1, 16))),
+    }
+}
+
+fn sample_rate_code(rate: u32) -> (u32, Option<(u32, u32)>) {
+    match rate {
+        88_200 => (0b0001, None),
+        176_400 => (0b0010, None),
+        192_000 => (0b0011, None),
+        8_000 => (0b0100, None),
+        16_000 => (0b0101, None),
+        22_050 => (0b0110, None),
+        24_000 => (0b0111, None),
+        32_000 => (0b1000, None),
+        44_100 => (0b1001, None),
+        48_000 => (0b1010, None),
+        96_000 => (0b1011, None),
+        rate if rate % 1_000 == 0 && rate / 1_000 <= 0xff => (0b1100, Some((rate / 1_000, 8))),
+        rate if rate <= 0xffff => (0b1101, Some((rate, 16))),
+        rate => (0b1110, Some((rate / 10, 16))),
+    }
+}
+
+fn sample_size_code(bits_per_sample: u32) -> u32 {
+    match bits_per_sample {
+        8 => 0b001,
+        12 => 0b010,
+        16 => 0b100,
+        20 => 0b101,
+        24 => 0b110,
+        _ => 0b111,
+    }
+}
+
+// Bit depths the codec can carry for each sample format
+fn bits_per_sample(format: StreamFormat) -> Result<u32> {
+    match format.sample_format {
+        SampleFormat::S16LE | SampleFormat::S16BE => Ok(16),
+        SampleFormat::S24LE | SampleFormat::S24BE | SampleFormat::S24_32LE | SampleFormat::S24_32BE => Ok(24),
+        sample_format => Err(Error::Format(format!("flac can't encode {:?} samples", sample_format))),
+    }
+}
+
+fn check_format(format: StreamFormat) -> Result<u32> {
+    if format.channels == 0 || format.channels > 8 {
+        return Err(Error::Format(format!("flac doesn't support {} channels", format.channels)));
+    }
+    // Rates that aren't a whole number of tens of Hz above 65535 can't be written in a frame header
+    if format.rate == 0 || (format.rate > 0xffff && (format.rate % 10 != 0 || format.rate / 10 > 0xffff)) {
+        return Err(Error::Format(format!("flac doesn't support a rate of {} Hz", format.rate)));
+    }
+    bits_per_sample(format)
+}
+
+pub struct FlacEncoder {
+    format: StreamFormat,
+    bits_per_sample: u32,
+    frame_number: u32,
+    // Interleaved samples waiting for a full block
+    pending: Vec<i64>,
+}
+
+impl FlacEncoder {
+    pub fn new(format: StreamFormat) -> Result<FlacEncoder> {
+        Ok(FlacEncoder {
+            format,
+            bits_per_sample: check_format(format)?,
+            frame_number: 0,
+            pending: vec![],
+        })
+    }
+
+    fn encode_block(&mut self, interleaved: &[i64]) -> Vec<u8> {
+        let channels = self.format.channels as usize;
+        let mut planar: Vec<Vec<i64>> = (0..channels)
+            .map(|channel| interleaved.iter().skip(channel).step_by(channels).cloned().collect())
+            .collect();
+        let block_size = planar[0].len();
+
+        let independent: Vec<EncodedSubframe> = planar.iter()
+            .map(|samples| plan_subframe(samples, self.bits_per_sample))
+            .collect();
+        let mut assignment = ChannelAssignment::Independent;
+        let mut subframes = independent;
+
+        // Pick whichever pair of left, right, mid and side codes a stereo block smallest
+        if channels == 2 {
+            let side: Vec<i64> = planar[0].iter().zip(&planar[1]).map(|(left, right)| left - right).collect();
+            let mid: Vec<i64> = planar[0].iter().zip(&planar[1]).map(|(left, right)| (left + right) >> 1).collect();
+            let side_subframe = plan_subframe(&side, self.bits_per_sample + 1);
+            let mid_subframe = plan_subframe(&mid, self.bits_per_sample);
+
+            let left_bits = subframes[0].bits;
+            let right_bits = subframes[1].bits;
+            let candidates = [
+                (ChannelAssignment::Independent, left_bits + right_bits),
+                (ChannelAssignment::LeftSide, left_bits + side_subframe.bits),
+                (ChannelAssignment::RightSide, side_subframe.bits + right_bits),
+                (ChannelAssignment::MidSide, mid_subframe.bits + side_subframe.bits),
+            ];
+            assignment = candidates.iter().min_by_key(|(_, bits)| *bits).unwrap().0;
+
+            match assignment {
+                ChannelAssignment::Independent => (),
+                ChannelAssignment::LeftSide => {
+                    subframes[1] = side_subframe;
+                    planar[1] = side;
+                }
+                ChannelAssignment::RightSide => {
+                    subframes[0] = side_subframe;
+                    planar[0] = side;
+                }
+                ChannelAssignment::MidSide => {
+                    subframes = vec![mid_subframe, side_subframe];
+                    planar = vec![mid, side];
+                }
+            }
+        }
+
+        let mut writer = BitWriter::new();
+        writer.write(0b1111_1111_1111_1000, 16);
+
+        let (size_code, size_extra) = block_size_code(block_size);
+        let (rate_code, rate_extra) = sample_rate_code(self.format.rate);
+        writer.write(size_code, 4);
+        writer.write(rate_code, 4);
+        writer.write(assignment.to_code(channels), 4);
+        writer.write(sample_size_code(self.bits_per_sample), 3);
+        writer.write(0, 1);
+        write_utf8_number(&mut writer, self.frame_number);
+        for &(value, bits) in size_extra.iter().chain(rate_extra.iter()) {
+            writer.write(value, bits);
+        }
+        let header_crc = crc8(&writer.bytes);
+        writer.write(header_crc as u32, 8);
+
+        for (channel, (samples, subframe)) in planar.iter().zip(&subframes).enumerate() {
+            let bits = self.bits_per_sample + assignment.is_side(channel) as u32;
+            write_subframe(&mut writer, samples, subframe, bits);
+        }
+
+        let mut frame = writer.into_bytes();
+        let frame_crc = crc16(&frame);
+        frame.extend_from_slice(&frame_crc.to_be_bytes());
+
+        // Frame numbers only have 31 bits
+        self.frame_number = (self.frame_number + 1) & 0x7fff_ffff;
+        frame
+    }
+}
+
+impl Encoder for FlacEncoder {
+    fn encode(&mut self, samples: &[u8]) -> Result<Vec<Encoded>> {
+        // Widened to S32 first, so every format is read the same way
+        let mut wide = vec![0; samples.len() / self.format.sample_format.bytes_per_sample() * 4];
+        SampleFormat::convert(self.format.sample_format, samples, SampleFormat::S32LE, &mut wide);
+        let shift = 32 - self.bits_per_sample;
+        self.pending.extend(wide.chunks_exact(4).map(|sample| (LittleEndian::read_i32(sample) >> shift) as i64));
+
+        let block_samples = BLOCK_SIZE * self.format.channels as usize;
+        let mut encoded = vec![];
+        let mut consumed = 0;
+        while self.pending.len() - consumed >= block_samples {
+            let block = self.pending[consumed..consumed + block_samples].to_vec();
+            encoded.push(Encoded { data: self.encode_block(&block), frames: BLOCK_SIZE });
+            consumed += block_samples;
+        }
+        self.pending.drain(..consumed);
+
+        Ok(encoded)
+    }
+
+    fn buffered_frames(&self) -> usize {
+        self.pending.len() / self.format.channels as usize
+    }
+
+    // Frame headers carry their own block size, so the rest goes out as one short block
+    fn flush(&mut self) -> Result<Vec<Encoded>> {
+        if self.pending.is_empty() {
+            return Ok(vec![]);
+        }
+        let block = std::mem::take(&mut self.pending);
+        let frames = block.len() / self.format.channels as usize;
+        Ok(vec![Encoded { data: self.encode_block(&block), frames }])
+    }
+}
+
+fn read_residual(reader: &mut BitReader, block_size: usize, order: usize, samples: &mut Vec<i64>) -> Result<()> {
+    let parameter_bits = match reader.read(2)? {
+        0b00 => 4,
+        0b01 => 5,
+        _ => return Err(invalid("unknown residual coding method")),
+    };
+    let escape = (1 << parameter_bits) - 1;
+
+    let partition_order = reader.read(4)?;
+    let partition_size = block_size >> partition_order;
+    if partition_size << partition_order != block_size || partition_size < order {
+        return Err(invalid("bad residual partition order"));
+    }
+
+    for partition in 0..1 << partition_order {
+        let length = if partition == 0 { partition_size - order } else { partition_size };
+        let parameter = reader.read(parameter_bits)?;
+        if parameter == escape {
+            let bits = reader.read(5)?;
+            for _ in 0..length {
+                samples.push(reader.read_signed(bits)? as i64);
+            }
+        } else {
+            for _ in 0..length {
+                samples.push(reader.read_rice(parameter)? as i64);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Coefficients of the fixed predictors, for the previous sample first
+const FIXED_COEFFICIENTS: [&[i64]; 5] = [&[], &[1], &[2, -1], &[3, -3, 1], &[4, -6, 4, -1]];
+
+// Adds the prediction from the samples before it to each residual after the warm-up samples. A
+// frame can be built to overflow the prediction even with a good checksum, so every step is checked
+// and every sample has to fit in `bits`, as it would in a real stream
+fn restore(samples: &mut [i64], coefficients: &[i64], shift: u32, bits: u32) -> Result<()> {
+    let limit = 1i64 << (bits - 1);
+    for i in coefficients.len()..samples.len() {
+        let prediction = coefficients.iter()
+            .zip(samples[..i].iter().rev())
+            .try_fold(0i64, |sum, (coefficient, sample)| sum.checked_add(coefficient.checked_mul(*sample)?));
+        let sample = prediction.and_then(|prediction| samples[i].checked_add(prediction >> shift));
+        samples[i] = match sample {
+            Some(sample) if (-limit..limit).contains(&sample) => sample,
+            _ => return Err(invalid("predicted sample is out of range")),
+        };
+    }
+    Ok(())
+}
+
+fn read_subframe(reader: &mut BitReader, block_size: usize, bits_per_sample: u32) -> Result<Vec<i64>> {
+    if reader.read(1)? != 0 {
+        return Err(invalid("bad subframe padding"));
+    }
+    let kind = reader.read(6)?;
+    let wasted_bits = if reader.read(1)? == 1 { reader.read_unary()? + 1 } else { 0 };
+    if wasted_bits >= bits_per_sample {
+        return Err(invalid("too many wasted bits"));
+    }
+    let bits = bits_per_sample - wasted_bits;
+
+    let mut samples = Vec::with_capacity(block_size);
+    match kind {
+        SUBFRAME_CONSTANT => {
+            let value = reader.read_signed(bits)? as i64;
+            samples.resize(block_size, value);
+        }
+        SUBFRAME_VERBATIM => {
+            for _ in 0..block_size {
+                samples.push(reader.read_signed(bits)? as i64);
+            }
+        }
+        kind if kind & 0b111000 == SUBFRAME_FIXED && kind & 0b111 <= MAX_FIXED_ORDER as u32 => {
+            let order = (kind & 0b111) as usize;
+            if order > block_size {
+                return Err(invalid("predictor order is longer than the block"));
+            }
+            for _ in 0..order {
+                samples.push(reader.read_signed(bits)? as i64);
+            }
+            read_residual(reader, block_size, order, &mut samples)?;
+            restore(&mut samples, FIXED_COEFFICIENTS[order], 0, bits)?;
+        }
+        kind if kind & 0b100000 != 0 => {
+            let order = (kind & 0b011111) as usize + 1;
+            if order > block_size {
+                return Err(invalid("predictor order is longer than the block"));
+            }
+            for _ in 0..order {
+                samples.push(reader.read_signed(bits)? as i64);
+            }
+
+            let precision = reader.read(4)? + 1;
+            if precision == 16 {
+                return Err(invalid("bad coefficient precision"));
+            }
+            let shift = reader.read_signed(5)?;
+            if shift < 0 {
+                return Err(invalid("negative prediction shift"));
+            }
+            let mut coefficients = Vec::with_capacity(order);
+            for _ in 0..order {
+                coefficients.push(reader.read_signed(precision)? as i64);
+            }
+            read_residual(reader, block_size, order, &mut samples)?;
+            restore(&mut samples, &coefficients, shift as u32, bits)?;
+        }
+        _ => return Err(invalid("reserved subframe type")),
+    }
+
+    if wasted_bits > 0 {
+        for sample in &mut samples {
+            *sample <<= wasted_bits;
+        }
+    }
+    Ok(samples)
+}
+
+pub struct FlacDecoder {
+    format: StreamFormat,
+    bits_per_sample: u32,
+    // Length of the last decoded block, which is how much silence stands in for a lost one
+    last_block_size: usize,
+}
+
+impl FlacDecoder {
+    pub fn new(format: StreamFormat) -> Result<FlacDecoder> {
+        Ok(FlacDecoder {
+            format,
+            bits_per_sample: check_format(format)?,
+            last_block_size: 0,
+        })
+    }
+
+    fn decode_frame(&self, data: &[u8]) -> Result<Vec<Vec<i64>>> {
+        // Nothing in a damaged frame can be trusted, so the checksum over all of it comes first
+        if data.len() < 2 {
+            return Err(invalid("frame is truncated"));
+        }
+        let (data, checksum) = data.split_at(data.len() - 2);
+        if u16::from_be_bytes([checksum[0], checksum[1]]) != crc16(data) {
+            return Err(invalid("frame checksum mismatch"));
+        }
+
+        let mut reader = BitReader::new(data);
+        if reader.read(15)? != 0x7ffc {
+            return Err(invalid("missing frame sync code"));
+        }
+        let _variable_block_size = reader.read(1)?;
+
+        let size_code = reader.read(4)?;
+        let rate_code = reader.read(4)?;
+        let channel_code = reader.read(4)?;
+        let size_bits = reader.read(3)?;
+        if reader.read(1)? != 0 {
+            return Err(invalid("reserved header bit is set"));
+        }
+        read_utf8_number(&mut reader)?;
+
+        let block_size = match size_code {
+            0b0001 => 192,
+            0b0010..=0b0101 => 576 << (size_code - 2),
+            0b0110 => reader.read(8)? as usize + 1,
+            0b0111 => reader.read(16)? as usize + 1,
+            0b1000..=0b1111 => 256 << (size_code - 8),
+            _ => return Err(invalid("reserved block size")),
+        };
+
+        let rate = match rate_code {
+            0b0001 => 88_200,
+            0b0010 => 176_400,
+            0b0011 => 192_000,
+            0b0100 => 8_000,
+            0b0101 => 16_000,
+            0b0110 => 22_050,
+            0b0111 => 24_000,
+            0b1000 => 32_000,
+            0b1001 => 44_100,
+            0b1010 => 48_000,
+            0b1011 => 96_000,
+            0b1100 => reader.read(8)? * 1_000,
+            0b1101 => reader.read(16)?,
+            0b1110 => reader.read(16)? * 10,
+            // Without STREAMINFO the handshake is the only place the rate can come from
+            0b0000 => self.format.rate,
+            _ => return Err(invalid("bad sample rate")),
+        };
+
+        let header_length = reader.byte_position();
+        if reader.read(8)? as u8 != crc8(&data[..header_length]) {
+            return Err(invalid("frame header checksum mismatch"));
+        }
+
+        let (channels, assignment) = match channel_code {
+            0..=7 => (channel_code as usize + 1, ChannelAssignment::Independent),
+            0b1000 => (2, ChannelAssignment::LeftSide),
+            0b1001 => (2, ChannelAssignment::RightSide),
+            0b1010 => (2, ChannelAssignment::MidSide),
+            _ => return Err(invalid("reserved channel assignment")),
+        };
+        let bits_per_sample = match size_bits {
+            0b000 => self.bits_per_sample,
+            0b001 => 8,
+            0b010 => 12,
+            0b100 => 16,
+            0b101 => 20,
+            0b110 => 24,
+            0b111 => 32,
+            _ => return Err(invalid("reserved sample size")),
+        };
+
+        if rate != self.format.rate || channels != self.format.channels as usize || bits_per_sample != self.bits_per_sample {
+            return Err(Error::Format(format!(
+                "flac frame is {} Hz, {} channels, {} bits but the stream is {} Hz, {} channels, {} bits",
+                rate, channels, bits_per_sample, self.format.rate, self.format.channels, self.bits_per_sample
+            )));
+        }
+
+        let mut planar = Vec::with_capacity(channels);
+        for channel in 0..channels {
+            let bits = bits_per_sample + assignment.is_side(channel) as u32;
+            planar.push(read_subframe(&mut reader, block_size, bits)?);
+        }
+
+        reader.align();
+        if reader.byte_position() != data.len() {
+            return Err(invalid("frame is longer than its subframes"));
+        }
+
+        if assignment != ChannelAssignment::Independent {
+            let (first, second) = planar.split_at_mut(1);
+            for (first, second) in first[0].iter_mut().zip(second[0].iter_mut()) {
+                match assignment {
+                    ChannelAssignment::LeftSide => *second = *first - *second,
+                    ChannelAssignment::RightSide => *first += *second,
+                    _ => {
+                        let side = *second;
+                        let mid = (*first << 1) | (side & 1);
+                        *first = (mid + side) >> 1;
+                        *second = (mid - side) >> 1;
+                    }
+                }
+            }
+        }
+
+        Ok(planar)
+    }
+}
+
+impl Decoder for FlacDecoder {
+    fn decode(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        if data.is_empty() {
+            return Ok(vec![0; self.last_block_size * self.format.bytes_per_frame()]);
+        }
+
+        let planar = self.decode_frame(data)?;
+        let block_size = planar[0].len();
+        self.last_block_size = block_size;
+
+        let shift = 32 - self.bits_per_sample;
+        let mut wide = vec![0; block_size * planar.len() * 4];
+        let mut samples = wide.chunks_exact_mut(4);
+        for i in 0..block_size {
+            for channel in &planar {
+                LittleEndian::write_i32(samples.next().unwrap(), (channel[i] as i32) << shift);
+            }
+        }
+
+        let mut bytes = vec![0; block_size * self.format.bytes_per_frame()];
+        SampleFormat::convert(SampleFormat::S32LE, &wide, self.format.sample_format, &mut bytes);
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A tone with some noise on top, so every predictor order and stereo decorrelation gets used
+    fn signal(format: StreamFormat, frames: usize) -> Vec<u8> {
+        let channels = format.channels as usize;
+        let mut state: u32 = 0x1234_5678;
+        let samples: Vec<f32> = (0..frames * channels)
+            .map(|index| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                let noise = state as f32 / u32::MAX as f32 - 0.5;
+                let (frame, channel) = (index / channels, index % channels);
+                let tone = (frame as f32 * 0.01 * (channel + 1) as f32).sin();
+                0.7 * tone + 0.1 * noise
+            })
+            .collect();
+
+        let mut bytes = vec![0; samples.len() * format.sample_format.bytes_per_sample()];
+        format.sample_format.write_samples(&samples, &mut bytes);
+        bytes
+    }
+
+    fn round_trip(sample_format: SampleFormat, channels: u16, frames: usize) {
+        let format = StreamFormat { sample_format, rate: 48_000, channels };
+        let input = signal(format, frames);
+
+        let mut encoder = FlacEncoder::new(format).unwrap();
+        let mut decoder = FlacDecoder::new(format).unwrap();
+        // Fed in uneven pieces so blocks straddle the calls to `encode`
+        let mut encoded = vec![];
+        for piece in input.chunks(333 * format.bytes_per_frame()) {
+            encoded.extend(encoder.encode(piece).unwrap());
+        }
+        encoded.extend(encoder.flush().unwrap());
+        assert_eq!(encoder.buffered_frames(), 0);
+
+        let mut output = vec![];
+        for frame in &encoded {
+            let decoded = decoder.decode(&frame.data).unwrap();
+            assert_eq!(decoded.len(), frame.frames * format.bytes_per_frame());
+            output.extend(decoded);
+        }
+        assert!(output == input, "{:?} with {} channels doesn't round trip", sample_format, channels);
+    }
+
+    #[test]
+    fn round_trips_s16() {
+        for &channels in &[1, 2, 6] {
+            round_trip(SampleFormat::S16LE, channels, BLOCK_SIZE * 3);
+            round_trip(SampleFormat::S16BE, channels, BLOCK_SIZE * 3);
+        }
+    }
+
+    #[test]
+    fn round_trips_s24() {
+        for &channels in &[1, 2, 6] {
+            round_trip(SampleFormat::S24LE, channels, BLOCK_SIZE * 3);
+            round_trip(SampleFormat::S24_32BE, channels, BLOCK_SIZE * 3);
+        }
+    }
+
+    #[test]
+    fn flushes_the_last_short_block() {
+        for &channels in &[1, 2, 6] {
+            round_trip(SampleFormat::S16LE, channels, BLOCK_SIZE * 2 + 100);
+            round_trip(SampleFormat::S24LE, channels, BLOCK_SIZE + 1);
+            round_trip(SampleFormat::S16LE, channels, 10);
+        }
+    }
+
+    #[test]
+    fn flushes_nothing_when_nothing_is_buffered() {
+        let format = StreamFormat { sample_format: SampleFormat::S16LE, rate: 48_000, channels: 2 };
+        let mut encoder = FlacEncoder::new(format).unwrap();
+        assert!(encoder.flush().unwrap().is_empty());
+        encoder.encode(&signal(format, BLOCK_SIZE)).unwrap();
+        assert!(encoder.flush().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_damaged_frames() {
+        let format = StreamFormat { sample_format: SampleFormat::S16LE, rate: 48_000, channels: 2 };
+        let mut encoder = FlacEncoder::new(format).unwrap();
+        let frame = encoder.encode(&signal(format, BLOCK_SIZE)).unwrap().remove(0).data;
+        let mut decoder = FlacDecoder::new(format).unwrap();
+
+        // Any one byte changed anywhere in the frame, including the checksum itself
+        for position in 0..frame.len() {
+            let mut damaged = frame.clone();
+            damaged[position] ^= 0x10;
+            assert!(matches!(decoder.decode(&damaged), Err(Error::Format(_))), "byte {}", position);
+        }
+        assert!(matches!(decoder.decode(&frame[..frame.len() - 1]), Err(Error::Format(_))));
+        assert!(matches!(decoder.decode(&frame[..1]), Err(Error::Format(_))));
+        assert!(decoder.decode(&frame).is_ok());
+    }
+
+    #[test]
+    fn rejects_predictions_that_overflow() {
+        let format = StreamFormat { sample_format: SampleFormat::S16LE, rate: 48_000, channels: 1 };
+        let block_size = 64;
+
+        // A mono frame with a good checksum, whose single subframe is written by `subframe`
+        let frame = |subframe: &dyn Fn(&mut BitWriter)| {
+            let mut writer = BitWriter::new();
+            writer.write(0b1111_1111_1111_1000, 16);
+            let (size_code, size_extra) = block_size_code(block_size);
+            writer.write(size_code, 4);
+            writer.write(sample_rate_code(format.rate).0, 4);
+            writer.write(0, 4);
+            writer.write(sample_size_code(16), 3);
+            writer.write(0, 1);
+            write_utf8_number(&mut writer, 0);
+            for &(value, bits) in size_extra.iter() {
+                writer.write(value, bits);
+            }
+            let header_crc = crc8(&writer.bytes);
+            writer.write(header_crc as u32, 8);
+
+            subframe(&mut writer);
+            let mut frame = writer.into_bytes();
+            let frame_crc = crc16(&frame);
+            frame.extend_from_slice(&frame_crc.to_be_bytes());
+            frame
+        };
+        // A residual of `value` for every sample after `order` warm-up ones, written verbatim
+        let residual = |writer: &mut BitWriter, order: usize, value: i32| {
+            writer.write(0b00, 2);
+            writer.write(0, 4);
+            writer.write(0b1111, 4);
+            writer.write(16, 5);
+            for _ in order..block_size {
+                writer.write_signed(value, 16);
+            }
+        };
+
+        // An order 1 LPC predictor that multiplies the previous sample by 16383 overflows an i64
+        // within a handful of samples
+        let lpc = frame(&|writer| {
+            writer.write(0, 1);
+            writer.write(0b100000, 6);
+            writer.write(0, 1);
+            writer.write_signed(i16::MAX as i32, 16);
+            writer.write(14, 4);
+            writer.write_signed(0, 5);
+            writer.write_signed(16_383, 15);
+            residual(writer, 1, 0);
+        });
+        // The fixed predictors only grow past 16 bits
+        let fixed = frame(&|writer| {
+            writer.write(0, 1);
+            writer.write(SUBFRAME_FIXED | 4, 6);
+            writer.write(0, 1);
+            for &sample in &[-32_768, 32_767, -32_768, 32_767] {
+                writer.write_signed(sample, 16);
+            }
+            residual(writer, 4, 32_767);
+        });
+
+        let mut decoder = FlacDecoder::new(format).unwrap();
+        for frame in &[lpc, fixed] {
+            assert!(matches!(decoder.decode(frame), Err(Error::Format(_))));
+        }
+    }
+}
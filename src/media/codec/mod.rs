@@ -0,0 +1,118 @@
+use crate::config::Config;
+use crate::error::Result;
+use crate::media::format::StreamFormat;
+
+pub mod flac;
+#[cfg(feature = "opus")]
+pub mod opus;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    // Raw samples in the stream format
+    Pcm,
+    Opus,
+    // Lossless, sent as one FLAC frame per packet
+    Flac,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OpusSettings {
+    // Bits per second
+    pub bitrate: u32,
+    // Microseconds of audio per encoded frame
+    pub frame_duration: u32,
+    // 0 to 10, trading encoder CPU time for quality
+    pub complexity: u8,
+}
+
+impl Default for OpusSettings {
+    fn default() -> Self {
+        OpusSettings {
+            bitrate: 128_000,
+            frame_duration: 20_000,
+            complexity: 10,
+        }
+    }
+}
+
+pub struct Encoded {
+    pub data: Vec<u8>,
+    // Number of sample frames the data decodes to
+    pub frames: usize,
+}
+
+pub trait Encoder: Send {
+    // Takes samples in the stream format and returns every frame that could be completed. Samples
+    // that don't fill a frame are kept for the next call.
+    fn encode(&mut self, samples: &[u8]) -> Result<Vec<Encoded>>;
+
+    // Number of sample frames passed to `encode` that haven't been returned yet
+    fn buffered_frames(&self) -> usize;
+
+    // Encodes whatever is still buffered at the end of the stream, which may make a frame shorter
+    // than the usual ones
+    fn flush(&mut self) -> Result<Vec<Encoded>>;
+}
+
+pub trait Decoder: Send {
+    // Returns samples in the stream format. An empty `data` marks a frame lost in transit, which
+    // the decoder conceals as well as it can.
+    fn decode(&mut self, data: &[u8]) -> Result<Vec<u8>>;
+}
+
+struct PcmEncoder {
+    bytes_per_frame: usize,
+}
+
+impl Encoder for PcmEncoder {
+    fn encode(&mut self, samples: &[u8]) -> Result<Vec<Encoded>> {
+        Ok(vec![Encoded { data: samples.to_vec(), frames: samples.len() / self.bytes_per_frame }])
+    }
+
+    fn buffered_frames(&self) -> usize {
+        0
+    }
+
+    fn flush(&mut self) -> Result<Vec<Encoded>> {
+        Ok(vec![])
+    }
+}
+
+struct PcmDecoder;
+
+impl Decoder for PcmDecoder {
+    // Transports that can lose packets fill the gaps with silence themselves
+    fn decode(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+}
+
+#[cfg(not(feature = "opus"))]
+fn opus_unsupported() -> crate::error::Error {
+    crate::error::Error::Format("this build does not support Opus".to_string())
+}
+
+pub fn create_encoder(config: &Config, format: StreamFormat) -> Result<Box<dyn Encoder>> {
+    match config.codec {
+        Codec::Pcm => Ok(Box::new(PcmEncoder { bytes_per_frame: format.bytes_per_frame() })),
+        #[cfg(feature = "opus")]
+        Codec::Opus => Ok(Box::new(opus::OpusEncoder::new(format, &config.opus)?)),
+        #[cfg(not(feature = "opus"))]
+        Codec::Opus => Err(opus_unsupported()),
+        Codec::Flac => Ok(Box::new(flac::FlacEncoder::new(format)?)),
+    }
+}
+
+pub fn create_decoder(codec: Codec, format: StreamFormat) -> Result<Box<dyn Decoder>> {
+    match codec {
+        Codec::Pcm => Ok(Box::new(PcmDecoder)),
+        #[cfg(feature = "opus")]
+        Codec::Opus => Ok(Box::new(opus::OpusDecoder::new(format)?)),
+        #[cfg(not(feature = "opus"))]
+        Codec::Opus => {
+            let _ = format;
+            Err(opus_unsupported())
+        }
+        Codec::Flac => Ok(Box::new(flac::FlacDecoder::new(format)?)),
+    }
+}
@@ -0,0 +1,163 @@
+use crate::error::{Error, Result};
+use crate::media::codec::{Decoder, Encoded, Encoder, OpusSettings};
+use crate::media::format::{SampleFormat, StreamFormat};
+use audiopus::coder::{Decoder as RawDecoder, Encoder as RawEncoder};
+use audiopus::packet::Packet;
+use audiopus::{Application, Bitrate, Channels, MutSignals, SampleRate};
+use byteorder::{ByteOrder, LittleEndian};
+use std::convert::TryFrom;
+
+// Recommended maximum size of a single Opus packet
+const MAX_PACKET_SIZE: usize = 4_000;
+
+// Longest frame a decoder can be handed, in microseconds
+const MAX_FRAME_DURATION: usize = 120_000;
+
+const FRAME_DURATIONS: [u32; 6] = [2_500, 5_000, 10_000, 20_000, 40_000, 60_000];
+
+impl From<audiopus::Error> for Error {
+    fn from(error: audiopus::Error) -> Self {
+        Error::Format(format!("opus: {}", error))
+    }
+}
+
+fn opus_parameters(format: StreamFormat) -> Result<(SampleRate, Channels)> {
+    if format.sample_format != SampleFormat::S16LE {
+        return Err(Error::Format(format!("opus can't encode {:?} samples", format.sample_format)));
+    }
+
+    let rate = match format.rate {
+        8_000 => SampleRate::Hz8000,
+        12_000 => SampleRate::Hz12000,
+        16_000 => SampleRate::Hz16000,
+        24_000 => SampleRate::Hz24000,
+        48_000 => SampleRate::Hz48000,
+        rate => return Err(Error::Format(format!("opus doesn't support a rate of {} Hz", rate))),
+    };
+
+    let channels = match format.channels {
+        1 => Channels::Mono,
+        2 => Channels::Stereo,
+        channels => return Err(Error::Format(format!("opus doesn't support {} channels", channels))),
+    };
+
+    Ok((rate, channels))
+}
+
+pub struct OpusEncoder {
+    encoder: RawEncoder,
+    channels: usize,
+    // Interleaved samples per encoded frame
+    frame_samples: usize,
+    pending: Vec<i16>,
+}
+
+impl OpusEncoder {
+    pub fn new(format: StreamFormat, settings: &OpusSettings) -> Result<OpusEncoder> {
+        let (rate, channels) = opus_parameters(format)?;
+
+        if !FRAME_DURATIONS.contains(&settings.frame_duration) {
+            return Err(Error::Format(format!(
+                "opus frames can't be {} ms long",
+                settings.frame_duration as f32 / 1_000.0
+            )));
+        }
+
+        let mut encoder = RawEncoder::new(rate, channels, Application::Audio)?;
+        encoder.set_bitrate(Bitrate::BitsPerSecond(settings.bitrate as i32))?;
+        encoder.set_complexity(settings.complexity)?;
+
+        let frames = format.rate as usize * settings.frame_duration as usize / 1_000_000;
+        Ok(OpusEncoder {
+            encoder,
+            channels: format.channels as usize,
+            frame_samples: frames * format.channels as usize,
+            pending: vec![],
+        })
+    }
+}
+
+impl Encoder for OpusEncoder {
+    fn encode(&mut self, samples: &[u8]) -> Result<Vec<Encoded>> {
+        let start = self.pending.len();
+        self.pending.resize(start + samples.len() / 2, 0);
+        LittleEndian::read_i16_into(&samples[..(self.pending.len() - start) * 2], &mut self.pending[start..]);
+
+        let mut encoded = vec![];
+        let mut output = [0; MAX_PACKET_SIZE];
+        let mut consumed = 0;
+        while self.pending.len() - consumed >= self.frame_samples {
+            let frame = &self.pending[consumed..consumed + self.frame_samples];
+            let length = self.encoder.encode(frame, &mut output)?;
+            encoded.push(Encoded {
+                data: output[..length].to_vec(),
+                frames: self.frame_samples / self.channels,
+            });
+            consumed += self.frame_samples;
+        }
+        self.pending.drain(..consumed);
+
+        Ok(encoded)
+    }
+
+    fn buffered_frames(&self) -> usize {
+        self.pending.len() / self.channels
+    }
+
+    // Opus frames only come in a few lengths, so the last one is padded with silence
+    fn flush(&mut self) -> Result<Vec<Encoded>> {
+        if self.pending.is_empty() {
+            return Ok(vec![]);
+        }
+        self.pending.resize(self.frame_samples, 0);
+        let mut output = [0; MAX_PACKET_SIZE];
+        let length = self.encoder.encode(&self.pending, &mut output)?;
+        self.pending.clear();
+
+        Ok(vec![Encoded { data: output[..length].to_vec(), frames: self.frame_samples / self.channels }])
+    }
+}
+
+pub struct OpusDecoder {
+    decoder: RawDecoder,
+    channels: usize,
+    output: Vec<i16>,
+    // Length of the last decoded frame, which is how much a lost frame is assumed to cover
+    last_frame_samples: usize,
+}
+
+impl OpusDecoder {
+    pub fn new(format: StreamFormat) -> Result<OpusDecoder> {
+        let (rate, channels) = opus_parameters(format)?;
+        let max_samples = format.rate as usize * MAX_FRAME_DURATION / 1_000_000 * format.channels as usize;
+
+        Ok(OpusDecoder {
+            decoder: RawDecoder::new(rate, channels)?,
+            channels: format.channels as usize,
+            output: vec![0; max_samples],
+            last_frame_samples: 0,
+        })
+    }
+}
+
+impl Decoder for OpusDecoder {
+    fn decode(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let frames = if data.is_empty() {
+            if self.last_frame_samples == 0 {
+                return Ok(vec![]);
+            }
+            let output = MutSignals::try_from(&mut self.output[..self.last_frame_samples])?;
+            self.decoder.decode(None, output, false)?
+        } else {
+            let output = MutSignals::try_from(&mut self.output[..])?;
+            let frames = self.decoder.decode(Some(Packet::try_from(data)?), output, false)?;
+            self.last_frame_samples = frames * self.channels;
+            frames
+        };
+
+        let samples = &self.output[..frames * self.channels];
+        let mut bytes = vec![0; samples.len() * 2];
+        LittleEndian::write_i16_into(samples, &mut bytes);
+        Ok(bytes)
+    }
+}
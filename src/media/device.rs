@@ -0,0 +1,168 @@
+use crate::error::{Error, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceKind {
+    // Something to record from, including monitors of playback devices
+    Capture,
+    Playback,
+}
+
+#[derive(Clone, Debug)]
+pub struct Device {
+    // What the backend opens the device by, such as a PulseAudio source name or a WASAPI endpoint id
+    pub id: String,
+    // Human readable description
+    pub name: String,
+    pub kind: DeviceKind,
+    // The audio API the device belongs to, such as "pulse", "alsa", "pipewire" or "wasapi"
+    pub api: &'static str,
+    // Records what a playback device plays rather than an input
+    pub monitor: bool,
+    pub default: bool,
+}
+
+// Picks a device of `kind` by its index among devices of that kind, its id, or its name. Names are
+// matched ignoring case, and part of a name is enough when only one device matches it. When `api`
+// is given only devices of that API are considered.
+pub fn select<'a>(devices: &'a [Device], kind: DeviceKind, api: Option<&str>, query: &str) -> Result<&'a Device> {
+    let candidates: Vec<&Device> = devices.iter().filter(|device| device.kind == kind).collect();
+
+    // Indices count every device of the kind, so they match what `audio-share devices` lists
+    if let Ok(index) = query.parse::<usize>() {
+        let device = candidates.get(index).cloned().ok_or_else(|| {
+            Error::Device(format!("there is no {} device {}, see `audio-share devices`", kind_name(kind), index))
+        })?;
+        return match api {
+            Some(api) if device.api != api => Err(Error::Device(format!(
+                "{} device {} is a {} device, not {}",
+                kind_name(kind),
+                index,
+                device.api,
+                api
+            ))),
+            _ => Ok(device),
+        };
+    }
+
+    let candidates: Vec<&Device> = candidates.into_iter()
+        .filter(|device| api.is_none() || api == Some(device.api))
+        .collect();
+
+    if let Some(device) = candidates.iter().find(|device| device.id == query) {
+        return Ok(device);
+    }
+
+    let lowercase = query.to_lowercase();
+    if let Some(device) = candidates.iter().find(|device| device.name.to_lowercase() == lowercase) {
+        return Ok(device);
+    }
+
+    let matches: Vec<&Device> = candidates.into_iter()
+        .filter(|device| device.name.to_lowercase().contains(&lowercase) || device.id.to_lowercase().contains(&lowercase))
+        .collect();
+    match matches.len() {
+        1 => Ok(matches[0]),
+        0 => Err(Error::Device(format!("no {} device matches \"{}\", see `audio-share devices`", kind_name(kind), query))),
+        _ => Err(Error::Device(format!(
+            "\"{}\" matches more than one {} device: {}",
+            query,
+            kind_name(kind),
+            matches.iter().map(|device| device.name.as_str()).collect::<Vec<_>>().join(", ")
+        ))),
+    }
+}
+
+fn kind_name(kind: DeviceKind) -> &'static str {
+    match kind {
+        DeviceKind::Capture => "capture",
+        DeviceKind::Playback => "playback",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(id: &str, name: &str, kind: DeviceKind, api: &'static str) -> Device {
+        Device {
+            id: id.to_string(),
+            name: name.to_string(),
+            kind,
+            api,
+            monitor: false,
+            default: false,
+        }
+    }
+
+    // Laid out like the Linux backend lists them, with PulseAudio and ALSA devices interleaved
+    fn devices() -> Vec<Device> {
+        vec![
+            device("alsa_output.pci.analog-stereo", "Built-in Audio Analog Stereo", DeviceKind::Playback, "pulse"),
+            device("alsa_output.pci.analog-stereo.monitor", "Monitor of Built-in Audio", DeviceKind::Capture, "pulse"),
+            device("hw:0,0", "HDA Intel PCH", DeviceKind::Capture, "alsa"),
+            device("alsa_input.usb.mono", "USB Microphone", DeviceKind::Capture, "pulse"),
+            device("hw:1,0", "USB Microphone", DeviceKind::Capture, "alsa"),
+            device("hw:0,3", "HDMI 0", DeviceKind::Playback, "alsa"),
+        ]
+    }
+
+    fn selected(kind: DeviceKind, api: Option<&str>, query: &str) -> Result<String> {
+        select(&devices(), kind, api, query).map(|device| device.id.clone())
+    }
+
+    #[test]
+    fn selects_by_index_among_devices_of_the_kind() {
+        assert_eq!(selected(DeviceKind::Capture, None, "0").unwrap(), "alsa_output.pci.analog-stereo.monitor");
+        assert_eq!(selected(DeviceKind::Capture, None, "1").unwrap(), "hw:0,0");
+        assert_eq!(selected(DeviceKind::Playback, None, "1").unwrap(), "hw:0,3");
+        assert!(matches!(selected(DeviceKind::Capture, None, "4"), Err(Error::Device(_))));
+    }
+
+    #[test]
+    fn index_must_belong_to_the_api() {
+        // Indices aren't renumbered when filtering by API, so that they match the device list
+        assert_eq!(selected(DeviceKind::Capture, Some("pulse"), "2").unwrap(), "alsa_input.usb.mono");
+        assert!(matches!(selected(DeviceKind::Capture, Some("pulse"), "1"), Err(Error::Device(_))));
+    }
+
+    #[test]
+    fn selects_by_id() {
+        assert_eq!(selected(DeviceKind::Capture, None, "hw:1,0").unwrap(), "hw:1,0");
+        assert_eq!(selected(DeviceKind::Playback, Some("alsa"), "hw:0,3").unwrap(), "hw:0,3");
+        // Ids of another kind or API are ignored
+        assert!(selected(DeviceKind::Capture, None, "hw:0,3").is_err());
+        assert!(selected(DeviceKind::Capture, Some("pulse"), "hw:1,0").is_err());
+    }
+
+    #[test]
+    fn selects_by_exact_name_ignoring_case() {
+        assert_eq!(selected(DeviceKind::Capture, None, "hda intel pch").unwrap(), "hw:0,0");
+        assert_eq!(selected(DeviceKind::Playback, Some("alsa"), "HDMI 0").unwrap(), "hw:0,3");
+    }
+
+    #[test]
+    fn selects_by_part_of_a_name_or_id() {
+        assert_eq!(selected(DeviceKind::Capture, None, "monitor").unwrap(), "alsa_output.pci.analog-stereo.monitor");
+        assert_eq!(selected(DeviceKind::Playback, None, "analog").unwrap(), "alsa_output.pci.analog-stereo");
+        assert_eq!(selected(DeviceKind::Capture, None, "usb.mono").unwrap(), "alsa_input.usb.mono");
+    }
+
+    #[test]
+    fn api_narrows_ambiguous_names() {
+        assert!(matches!(selected(DeviceKind::Capture, None, "microphone"), Err(Error::Device(_))));
+        assert_eq!(selected(DeviceKind::Capture, Some("pulse"), "USB Microphone").unwrap(), "alsa_input.usb.mono");
+        assert_eq!(selected(DeviceKind::Capture, Some("alsa"), "microphone").unwrap(), "hw:1,0");
+    }
+
+    #[test]
+    fn reports_ambiguous_and_unmatched_queries() {
+        match selected(DeviceKind::Capture, None, "microphone") {
+            Err(Error::Device(message)) => assert!(message.contains("more than one"), "{}", message),
+            other => panic!("expected an ambiguous match, got {:?}", other),
+        }
+        match selected(DeviceKind::Playback, None, "microphone") {
+            Err(Error::Device(message)) => assert!(message.contains("no playback device"), "{}", message),
+            other => panic!("expected no match, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,289 @@
+// Reduces float samples to an integer format. TPDF dither of one LSB either way turns the rounding
+// error into a constant, signal independent hiss instead of distortion that follows the audio, and
+// noise shaping feeds the error back through a filter so that hiss moves to frequencies the ear
+// hears less. Samples beyond full scale are clipped, counted, and reported at most once a second.
+
+use crate::media::format::StreamFormat;
+use std::time::{Duration, Instant};
+
+const CLIP_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Samples reduced per block
+const BLOCK_SIZE: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoiseShaping {
+    // Flat dither noise
+    None,
+    // Error feedback through 1 - z^-1, which tilts the noise up towards high frequencies
+    FirstOrder,
+    // The 5-tap E-weighted filter from Lipshitz, Vanderkooy and Wannamaker's "Minimally audible
+    // noise shaping", which pushes the noise out of the ear's most sensitive 2-5 kHz band. Only
+    // worth it at 44.1 kHz and up
+    Lipshitz,
+}
+
+impl NoiseShaping {
+    fn coefficients(self) -> &'static [f32] {
+        match self {
+            NoiseShaping::None => &[],
+            NoiseShaping::FirstOrder => &[1.0],
+            NoiseShaping::Lipshitz => &[2.033, -2.165, 1.959, -1.590, 0.6149],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DitherSettings {
+    // Without dither samples are only rounded
+    pub enabled: bool,
+    pub shaping: NoiseShaping,
+}
+
+impl Default for DitherSettings {
+    fn default() -> Self {
+        DitherSettings {
+            enabled: true,
+            shaping: NoiseShaping::None,
+        }
+    }
+}
+
+pub struct Dither {
+    settings: DitherSettings,
+    format: StreamFormat,
+    // Recent quantisation errors of each channel, newest first
+    errors: Vec<[f32; 5]>,
+    // Channel of the next sample, since input doesn't have to end on a frame boundary
+    channel: usize,
+    random: u32,
+    clipped: u64,
+    unreported: u64,
+    last_report: Option<Instant>,
+}
+
+impl Dither {
+    pub fn new(settings: DitherSettings, format: StreamFormat) -> Dither {
+        Dither {
+            settings,
+            format,
+            errors: vec![[0.0; 5]; format.channels as usize],
+            channel: 0,
+            random: 0x2545_f491,
+            clipped: 0,
+            unreported: 0,
+            last_report: None,
+        }
+    }
+
+    // Samples clipped so far
+    pub fn clipped(&self) -> u64 {
+        self.clipped
+    }
+
+    // Reduces as many samples from `input` as fit in `output`, and returns how many
+    pub fn process(&mut self, input: &[f32], output: &mut [u8]) -> usize {
+        let sample_format = self.format.sample_format;
+        let count = input.len().min(output.len() / sample_format.bytes_per_sample());
+        if sample_format.is_float() {
+            return sample_format.write_samples(input, output);
+        }
+
+        // Everything is worked out in LSBs of the output format
+        let bits = sample_format.bits();
+        let scale = (1u64 << (bits - 1)) as f32;
+        let shift = 32 - bits;
+        let coefficients = self.settings.shaping.coefficients();
+
+        let mut block = [0i32; BLOCK_SIZE];
+        let output_blocks = output.chunks_mut(BLOCK_SIZE * sample_format.bytes_per_sample());
+        for (input, output) in input[..count].chunks(BLOCK_SIZE).zip(output_blocks) {
+            for (sample, value) in input.iter().zip(block.iter_mut()) {
+                let errors = &mut self.errors[self.channel];
+                self.channel = (self.channel + 1) % self.format.channels as usize;
+
+                let mut wanted = *sample * scale;
+                for (coefficient, error) in coefficients.iter().zip(errors.iter()) {
+                    wanted -= coefficient * error;
+                }
+
+                let dither = if self.settings.enabled { tpdf(&mut self.random) } else { 0.0 };
+                let mut quantised = (wanted + dither).round();
+                if quantised < -scale || quantised > scale - 1.0 {
+                    quantised = quantised.max(-scale).min(scale - 1.0);
+                    self.clipped += 1;
+                    self.unreported += 1;
+                    // The error is the clipping now, and feeding it back would only ring
+                    *errors = [0.0; 5];
+                } else {
+                    errors.rotate_right(1);
+                    errors[0] = quantised - wanted;
+                }
+
+                *value = (quantised as i32) << shift;
+            }
+            sample_format.write_i32(&block[..input.len()], output);
+        }
+
+        self.report_clipping();
+        count
+    }
+
+    fn report_clipping(&mut self) {
+        if self.unreported == 0 {
+            return;
+        }
+        let now = Instant::now();
+        if let Some(last_report) = self.last_report {
+            if now.duration_since(last_report) < CLIP_REPORT_INTERVAL {
+                return;
+            }
+        }
+
+        println!(
+            "Clipped {} samples converting to {:?} ({} in total)",
+            self.unreported, self.format.sample_format, self.clipped
+        );
+        self.unreported = 0;
+        self.last_report = Some(now);
+    }
+}
+
+// Triangular noise between -1 and 1, the sum of two uniform values, from an xorshift generator
+fn tpdf(state: &mut u32) -> f32 {
+    let mut uniform = || {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        (*state >> 8) as f32 / (1 << 24) as f32
+    };
+    uniform() - uniform()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::format::SampleFormat;
+    use std::f64::consts::PI;
+
+    const RATE: u32 = 44_100;
+
+    fn dither(enabled: bool, shaping: NoiseShaping, sample_format: SampleFormat) -> Dither {
+        let format = StreamFormat { sample_format, rate: RATE, channels: 1 };
+        Dither::new(DitherSettings { enabled, shaping }, format)
+    }
+
+    // Reduces `input` to S16 and returns the samples in LSBs
+    fn reduce(dither: &mut Dither, input: &[f32]) -> Vec<i32> {
+        let mut bytes = vec![0; input.len() * 2];
+        assert_eq!(dither.process(input, &mut bytes), input.len());
+        let mut samples = vec![0; input.len()];
+        SampleFormat::S16LE.read_i32(&bytes, &mut samples);
+        samples.iter().map(|sample| sample >> 16).collect()
+    }
+
+    // What reducing `input` added to it, in LSBs
+    fn errors(dither: &mut Dither, input: &[f32]) -> Vec<f64> {
+        let output = reduce(dither, input);
+        output.iter().zip(input).map(|(&output, &input)| output as f64 - input as f64 * 32_768.0).collect()
+    }
+
+    fn mean(values: &[f64]) -> f64 {
+        values.iter().sum::<f64>() / values.len() as f64
+    }
+
+    // A quiet sine, well away from full scale
+    fn sine(frames: usize) -> Vec<f32> {
+        (0..frames).map(|n| (0.01 * (2.0 * PI * 997.0 * n as f64 / RATE as f64).sin()) as f32).collect()
+    }
+
+    // Average power of `errors` at frequencies spread from `low` to `high` Hz
+    fn band_power(errors: &[f64], low: f64, high: f64) -> f64 {
+        const FREQUENCIES: usize = 32;
+        let total: f64 = (0..FREQUENCIES)
+            .map(|index| {
+                let frequency = low + (high - low) * index as f64 / (FREQUENCIES - 1) as f64;
+                let omega = 2.0 * PI * frequency / RATE as f64;
+                let (re, im) = errors.iter().enumerate().fold((0.0, 0.0), |(re, im), (n, error)| {
+                    (re + error * (omega * n as f64).cos(), im - error * (omega * n as f64).sin())
+                });
+                (re * re + im * im) / errors.len() as f64
+            })
+            .sum();
+        total / FREQUENCIES as f64
+    }
+
+    #[test]
+    fn tpdf_spans_one_lsb_either_way() {
+        let mut state = 0x2545_f491;
+        let noise: Vec<f64> = (0..100_000).map(|_| tpdf(&mut state) as f64).collect();
+        assert!(noise.iter().all(|value| value.abs() < 1.0));
+        assert!(mean(&noise).abs() < 0.01);
+        // A triangle from -1 to 1 has a variance of 1/6
+        let variance = mean(&noise.iter().map(|value| value * value).collect::<Vec<_>>());
+        assert!((variance - 1.0 / 6.0).abs() < 0.01, "{}", variance);
+    }
+
+    #[test]
+    fn dithered_error_is_bounded_and_unbiased() {
+        // A quarter LSB that rounding alone would always lose
+        let input = vec![0.25 / 32_768.0; 100_000];
+        let errors = errors(&mut dither(true, NoiseShaping::None, SampleFormat::S16LE), &input);
+        // One LSB of dither plus half of one from rounding
+        assert!(errors.iter().all(|error| error.abs() <= 1.5));
+        assert!(mean(&errors).abs() < 0.01, "{}", mean(&errors));
+        // 1/6 from the dither and 1/12 from rounding
+        let variance = mean(&errors.iter().map(|error| error * error).collect::<Vec<_>>());
+        assert!((variance - 0.25).abs() < 0.02, "{}", variance);
+    }
+
+    #[test]
+    fn shaping_moves_noise_up() {
+        let input = sine(16_384);
+        for &shaping in &[NoiseShaping::None, NoiseShaping::FirstOrder, NoiseShaping::Lipshitz] {
+            let errors = errors(&mut dither(true, shaping, SampleFormat::S16LE), &input);
+            // The band the ear is most sensitive to against the top of the spectrum
+            let tilt = band_power(&errors, 15_000.0, 20_000.0) / band_power(&errors, 2_000.0, 5_000.0);
+            match shaping {
+                NoiseShaping::None => assert!(tilt > 0.5 && tilt < 2.0, "flat noise tilted by {}", tilt),
+                _ => assert!(tilt > 4.0, "{:?} tilted noise by only {}", shaping, tilt),
+            }
+        }
+    }
+
+    #[test]
+    fn counts_clipped_samples() {
+        let mut dither = dither(false, NoiseShaping::FirstOrder, SampleFormat::S16LE);
+        let output = reduce(&mut dither, &[1.5, -1.5, 0.0, 1.0, -1.0, 0.5]);
+        assert_eq!(output, vec![32_767, -32_768, 0, 32_767, -32_768, 16_384]);
+        assert_eq!(dither.clipped(), 3);
+
+        reduce(&mut dither, &[2.0, 0.0]);
+        assert_eq!(dither.clipped(), 4);
+    }
+
+    #[test]
+    fn rounds_without_dither() {
+        let lsbs = [0.4, 0.6, -0.4, -0.6, 2.5, -2.5, 100.0];
+        let input: Vec<f32> = lsbs.iter().map(|lsb| lsb / 32_768.0).collect();
+        let mut dither = dither(false, NoiseShaping::None, SampleFormat::S16LE);
+        assert_eq!(reduce(&mut dither, &input), vec![0, 1, 0, -1, 3, -3, 100]);
+        // And the same again, with no noise carried over
+        assert_eq!(reduce(&mut dither, &input), vec![0, 1, 0, -1, 3, -3, 100]);
+    }
+
+    #[test]
+    fn leaves_floats_alone() {
+        let input = [0.1, -0.25, 1.5, -3.0, 1e-9];
+        for &format in &[SampleFormat::F32LE, SampleFormat::F32BE] {
+            let mut dither = dither(true, NoiseShaping::Lipshitz, format);
+            let mut bytes = vec![0; input.len() * 4];
+            assert_eq!(dither.process(&input, &mut bytes), input.len());
+
+            let mut output = vec![0.0; input.len()];
+            format.read_samples(&bytes, &mut output);
+            assert_eq!(output, input);
+            assert_eq!(dither.clipped(), 0);
+        }
+    }
+}
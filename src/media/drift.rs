@@ -0,0 +1,149 @@
+// The sender's capture clock and the local playback clock never run at exactly the same rate, so
+// over time playback either drains the jitter buffer or lets it fill up. A PI controller turns the
+// distance between the buffer's fill level and its target into a resampling ratio, and
+// media::resample stretches or squeezes the audio by that ratio on top of any rate conversion.
+
+// Largest correction applied, as a fraction of the rate. Sound cards are well within 100 ppm of
+// each other, which leaves room to pull the fill level back to a new target
+const MAX_CORRECTION: f64 = 0.001;
+
+// Correction for each second the smoothed fill level is off target, and for each second of that
+// error integrated over a second of playback. These critically damp the loop, which settles in a
+// couple of minutes.
+const PROPORTIONAL_GAIN: f64 = 0.02;
+const INTEGRAL_GAIN: f64 = 0.000_1;
+
+// Seconds over which the fill level is averaged. It jumps every time a packet arrives, and only
+// its slow movement is drift
+const FILL_TIME_CONSTANT: f64 = 2.0;
+
+fn clamp(value: f64, limit: f64) -> f64 {
+    value.max(-limit).min(limit)
+}
+
+pub struct DriftController {
+    smoothed_fill: Option<f64>,
+    integral: f64,
+    ratio: f64,
+}
+
+impl DriftController {
+    pub fn new() -> DriftController {
+        DriftController {
+            smoothed_fill: None,
+            integral: 0.0,
+            ratio: 1.0,
+        }
+    }
+
+    // Input frames to read per output frame. Above 1 when the buffer is too full
+    pub fn ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    // Takes the fill level and target in seconds, and the seconds of audio played since the last
+    // update, and returns the new ratio
+    pub fn update(&mut self, fill: f64, target: f64, elapsed: f64) -> f64 {
+        let smoothed_fill = match self.smoothed_fill {
+            Some(smoothed_fill) => smoothed_fill + (fill - smoothed_fill) * elapsed / (FILL_TIME_CONSTANT + elapsed),
+            None => fill,
+        };
+        self.smoothed_fill = Some(smoothed_fill);
+
+        // The integral only has to cover the drift itself, so it's kept from winding up past that
+        let error = smoothed_fill - target;
+        self.integral = clamp(self.integral + error * elapsed, MAX_CORRECTION / INTEGRAL_GAIN);
+
+        let correction = PROPORTIONAL_GAIN * error + INTEGRAL_GAIN * self.integral;
+        self.ratio = 1.0 + clamp(correction, MAX_CORRECTION);
+        self.ratio
+    }
+
+    // Forgets the fill level after an underrun, when the buffer is refilled from scratch. The drift
+    // hasn't changed, so the integral is kept.
+    pub fn reset(&mut self) {
+        self.smoothed_fill = None;
+    }
+}
+
+impl Default for DriftController {
+    fn default() -> Self {
+        DriftController::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Seconds of audio played for each update, as a device callback would
+    const PERIOD: f64 = 0.01;
+
+    // Plays `seconds` of a sender whose clock is off by `offset` from the device's, and returns the
+    // fill level and ratio after each update
+    fn simulate(
+        controller: &mut DriftController,
+        fill: &mut f64,
+        target: f64,
+        offset: f64,
+        seconds: f64,
+    ) -> Vec<(f64, f64)> {
+        (0..(seconds / PERIOD) as usize)
+            .map(|_| {
+                *fill += PERIOD * (1.0 + offset) - PERIOD * controller.ratio();
+                let ratio = controller.update(*fill, target, PERIOD);
+                (*fill, ratio)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn follows_clock_drift() {
+        let target = 0.06;
+        for &offset in &[100e-6, -100e-6] {
+            let mut controller = DriftController::new();
+            let mut fill = target;
+            let history = simulate(&mut controller, &mut fill, target, offset, 1_200.0);
+
+            // The fill level strays while the integral catches up, then comes back without
+            // swinging past the target
+            let errors: Vec<f64> = history.iter().map(|(fill, _)| (fill - target) * offset.signum()).collect();
+            let peak = errors.iter().cloned().fold(0.0, f64::max);
+            assert!(peak > 0.0 && peak < 0.005, "{} ppm strays by {:.1} ms", offset * 1e6, peak * 1e3);
+            assert!(errors.iter().all(|&error| error > -1e-6), "{} ppm swings past the target", offset * 1e6);
+
+            // A PI loop overshoots the drift a little while it pulls the fill level back
+            let overshoot = history.iter().map(|(_, ratio)| (ratio - 1.0) / offset).fold(0.0, f64::max);
+            assert!(overshoot < 1.2, "{} ppm overshoots to {:.2} times", offset * 1e6, overshoot);
+
+            let (fill, ratio) = *history.last().unwrap();
+            let settled = (ratio - 1.0) * 1e6;
+            assert!((ratio - 1.0 - offset).abs() < 0.5e-6, "{} ppm settles at {:.2} ppm", offset * 1e6, settled);
+            assert!((fill - target).abs() < 0.1e-3, "{} ppm settles {:.3} ms off", offset * 1e6, (fill - target) * 1e3);
+        }
+    }
+
+    #[test]
+    fn limits_the_correction() {
+        for &offset in &[0.01, -0.01] {
+            let mut controller = DriftController::new();
+            let mut fill = 0.06;
+            let history = simulate(&mut controller, &mut fill, 0.06, offset, 60.0);
+            let limits = 1.0 - MAX_CORRECTION..=1.0 + MAX_CORRECTION;
+            assert!(history.iter().all(|(_, ratio)| limits.contains(ratio)));
+            assert_eq!(controller.ratio(), 1.0 + MAX_CORRECTION * offset.signum());
+        }
+    }
+
+    #[test]
+    fn keeps_the_drift_over_a_reset() {
+        let mut controller = DriftController::new();
+        let mut fill = 0.06;
+        simulate(&mut controller, &mut fill, 0.06, 100e-6, 1_200.0);
+
+        // Refilled from scratch after an underrun, at the target
+        controller.reset();
+        let ratio = controller.update(0.06, 0.06, PERIOD);
+        assert!((ratio - 1.0 - 100e-6).abs() < 1e-6, "{:.2} ppm", (ratio - 1.0) * 1e6);
+    }
+}
@@ -0,0 +1,388 @@
+// Sample formats and conversion between them. Conversions write into buffers the caller owns and
+// work through a block of samples at a time, so each step is a tight loop over a small array that
+// the compiler can vectorise. Integer formats convert to each other without going through float,
+// so S24 and S32 keep every bit they can.
+
+use std::convert::TryInto;
+
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleFormat {
+    S16LE,
+    S16BE,
+    // 24-bit samples packed into 3 bytes
+    S24LE,
+    S24BE,
+    // 24-bit samples in the low 3 bytes of 4
+    S24_32LE,
+    S24_32BE,
+    S32LE,
+    S32BE,
+    F32LE,
+    F32BE,
+}
+
+// Samples converted per block, small enough to stay in registers and L1
+const BLOCK_SIZE: usize = 256;
+
+impl SampleFormat {
+    pub fn bytes_per_sample(self) -> usize {
+        match self {
+            SampleFormat::S16LE | SampleFormat::S16BE => 2,
+            SampleFormat::S24LE | SampleFormat::S24BE => 3,
+            _ => 4,
+        }
+    }
+
+    // Bits of precision a sample holds
+    pub fn bits(self) -> u32 {
+        match self {
+            SampleFormat::S16LE | SampleFormat::S16BE => 16,
+            SampleFormat::S24LE | SampleFormat::S24BE | SampleFormat::S24_32LE | SampleFormat::S24_32BE => 24,
+            _ => 32,
+        }
+    }
+
+    pub fn is_float(self) -> bool {
+        matches!(self, SampleFormat::F32LE | SampleFormat::F32BE)
+    }
+
+    pub fn is_big_endian(self) -> bool {
+        matches!(
+            self,
+            SampleFormat::S16BE | SampleFormat::S24BE | SampleFormat::S24_32BE | SampleFormat::S32BE | SampleFormat::F32BE
+        )
+    }
+
+    // The same format in the other byte order
+    fn swapped(self) -> SampleFormat {
+        match self {
+            SampleFormat::S16LE => SampleFormat::S16BE,
+            SampleFormat::S16BE => SampleFormat::S16LE,
+            SampleFormat::S24LE => SampleFormat::S24BE,
+            SampleFormat::S24BE => SampleFormat::S24LE,
+            SampleFormat::S24_32LE => SampleFormat::S24_32BE,
+            SampleFormat::S24_32BE => SampleFormat::S24_32LE,
+            SampleFormat::S32LE => SampleFormat::S32BE,
+            SampleFormat::S32BE => SampleFormat::S32LE,
+            SampleFormat::F32LE => SampleFormat::F32BE,
+            SampleFormat::F32BE => SampleFormat::F32LE,
+        }
+    }
+
+    // Reads the sample at the start of `bytes` as a float between -1.0 and 1.0
+    pub fn read_sample(self, bytes: &[u8]) -> f32 {
+        let mut sample = [0.0];
+        self.read_samples(&bytes[..self.bytes_per_sample()], &mut sample);
+        sample[0]
+    }
+
+    pub fn write_sample(self, sample: f32, bytes: &mut [u8]) {
+        let bytes_per_sample = self.bytes_per_sample();
+        self.write_samples(&[sample], &mut bytes[..bytes_per_sample]);
+    }
+
+    // Decodes as many samples from `input` as fit in `output`, and returns how many
+    pub fn read_samples(self, input: &[u8], output: &mut [f32]) -> usize {
+        let count = output.len().min(input.len() / self.bytes_per_sample());
+        let input = &input[..count * self.bytes_per_sample()];
+
+        if self.is_float() {
+            let big_endian = self.is_big_endian();
+            for (bytes, sample) in input.chunks_exact(4).zip(output.iter_mut()) {
+                let bytes = bytes.try_into().unwrap();
+                *sample = if big_endian { f32::from_be_bytes(bytes) } else { f32::from_le_bytes(bytes) };
+            }
+            return count;
+        }
+
+        let mut block = [0i32; BLOCK_SIZE];
+        let input_blocks = input.chunks(BLOCK_SIZE * self.bytes_per_sample());
+        for (input, output) in input_blocks.zip(output[..count].chunks_mut(BLOCK_SIZE)) {
+            let block = &mut block[..output.len()];
+            self.read_i32(input, block);
+            for (sample, output) in block.iter().zip(output.iter_mut()) {
+                *output = *sample as f32 / 2_147_483_648.0;
+            }
+        }
+        count
+    }
+
+    // Encodes as many samples from `input` as fit in `output`, and returns how many. Samples
+    // outside -1.0 to 1.0 are clipped.
+    pub fn write_samples(self, input: &[f32], output: &mut [u8]) -> usize {
+        let count = input.len().min(output.len() / self.bytes_per_sample());
+        let output = &mut output[..count * self.bytes_per_sample()];
+
+        if self.is_float() {
+            let big_endian = self.is_big_endian();
+            for (sample, bytes) in input.iter().zip(output.chunks_exact_mut(4)) {
+                let sample = if big_endian { sample.to_be_bytes() } else { sample.to_le_bytes() };
+                bytes.copy_from_slice(&sample);
+            }
+            return count;
+        }
+
+        // Rounded at the format's own depth, so a value written and read back comes out the same
+        let scale = (1u64 << (self.bits() - 1)) as f64;
+        let shift = 32 - self.bits();
+        let mut block = [0i32; BLOCK_SIZE];
+        let output_blocks = output.chunks_mut(BLOCK_SIZE * self.bytes_per_sample());
+        for (input, output) in input[..count].chunks(BLOCK_SIZE).zip(output_blocks) {
+            let block = &mut block[..input.len()];
+            for (sample, value) in input.iter().zip(block.iter_mut()) {
+                *value = ((*sample as f64 * scale).round().max(-scale).min(scale - 1.0) as i32) << shift;
+            }
+            self.write_i32(block, output);
+        }
+        count
+    }
+
+    // Converts as many samples from `input` as fit in `output`, and returns how many
+    pub fn convert(from: SampleFormat, input: &[u8], to: SampleFormat, output: &mut [u8]) -> usize {
+        let count = (input.len() / from.bytes_per_sample()).min(output.len() / to.bytes_per_sample());
+        let input = &input[..count * from.bytes_per_sample()];
+        let output = &mut output[..count * to.bytes_per_sample()];
+
+        if from == to {
+            output.copy_from_slice(input);
+        } else if from.swapped() == to {
+            let size = from.bytes_per_sample();
+            for (input, output) in input.chunks_exact(size).zip(output.chunks_exact_mut(size)) {
+                for (input, output) in input.iter().rev().zip(output.iter_mut()) {
+                    *output = *input;
+                }
+            }
+        } else if from.is_float() || to.is_float() {
+            let mut block = [0.0f32; BLOCK_SIZE];
+            let input_blocks = input.chunks(BLOCK_SIZE * from.bytes_per_sample());
+            let output_blocks = output.chunks_mut(BLOCK_SIZE * to.bytes_per_sample());
+            for (input, output) in input_blocks.zip(output_blocks) {
+                let samples = from.read_samples(input, &mut block);
+                to.write_samples(&block[..samples], output);
+            }
+        } else {
+            let mut block = [0i32; BLOCK_SIZE];
+            let input_blocks = input.chunks(BLOCK_SIZE * from.bytes_per_sample());
+            let output_blocks = output.chunks_mut(BLOCK_SIZE * to.bytes_per_sample());
+            for (input, output) in input_blocks.zip(output_blocks) {
+                let block = &mut block[..input.len() / from.bytes_per_sample()];
+                from.read_i32(input, block);
+                // Dropping bits rounds to the nearest value rather than towards minus infinity
+                if to.bits() < from.bits() {
+                    let shift = 32 - to.bits();
+                    let half = 1i32 << (shift - 1);
+                    for sample in block.iter_mut() {
+                        *sample = sample.saturating_add(half) >> shift << shift;
+                    }
+                }
+                to.write_i32(block, output);
+            }
+        }
+        count
+    }
+
+    // Decodes integer samples to the top bits of an i32, so every format has the same full scale.
+    // Not for float formats
+    pub fn read_i32(self, input: &[u8], output: &mut [i32]) {
+        match self {
+            SampleFormat::S16LE => decode(input, output, |b: [u8; 2]| (i16::from_le_bytes(b) as i32) << 16),
+            SampleFormat::S16BE => decode(input, output, |b: [u8; 2]| (i16::from_be_bytes(b) as i32) << 16),
+            SampleFormat::S24LE => decode(input, output, |b: [u8; 3]| i32::from_le_bytes([0, b[0], b[1], b[2]])),
+            SampleFormat::S24BE => decode(input, output, |b: [u8; 3]| i32::from_be_bytes([b[0], b[1], b[2], 0])),
+            SampleFormat::S24_32LE => decode(input, output, |b: [u8; 4]| i32::from_le_bytes(b) << 8),
+            SampleFormat::S24_32BE => decode(input, output, |b: [u8; 4]| i32::from_be_bytes(b) << 8),
+            SampleFormat::S32LE => decode(input, output, i32::from_le_bytes),
+            SampleFormat::S32BE => decode(input, output, i32::from_be_bytes),
+            SampleFormat::F32LE | SampleFormat::F32BE => unreachable!("float samples aren't decoded as integers"),
+        }
+    }
+
+    // Encodes samples held in the top bits of an i32, dropping the bits the format has no room for.
+    // Not for float formats
+    pub fn write_i32(self, input: &[i32], output: &mut [u8]) {
+        match self {
+            SampleFormat::S16LE => encode(input, output, |s| ((s >> 16) as i16).to_le_bytes()),
+            SampleFormat::S16BE => encode(input, output, |s| ((s >> 16) as i16).to_be_bytes()),
+            SampleFormat::S24LE => encode(input, output, |s| {
+                let b = s.to_le_bytes();
+                [b[1], b[2], b[3]]
+            }),
+            SampleFormat::S24BE => encode(input, output, |s| {
+                let b = s.to_be_bytes();
+                [b[0], b[1], b[2]]
+            }),
+            SampleFormat::S24_32LE => encode(input, output, |s| (s >> 8).to_le_bytes()),
+            SampleFormat::S24_32BE => encode(input, output, |s| (s >> 8).to_be_bytes()),
+            SampleFormat::S32LE => encode(input, output, i32::to_le_bytes),
+            SampleFormat::S32BE => encode(input, output, i32::to_be_bytes),
+            SampleFormat::F32LE | SampleFormat::F32BE => unreachable!("float samples aren't encoded from integers"),
+        }
+    }
+}
+
+fn decode<const N: usize, F: Fn([u8; N]) -> i32>(input: &[u8], output: &mut [i32], read: F) {
+    for (bytes, sample) in input.chunks_exact(N).zip(output.iter_mut()) {
+        *sample = read(bytes.try_into().unwrap());
+    }
+}
+
+fn encode<const N: usize, F: Fn(i32) -> [u8; N]>(input: &[i32], output: &mut [u8], write: F) {
+    for (sample, bytes) in input.iter().zip(output.chunks_exact_mut(N)) {
+        bytes.copy_from_slice(&write(*sample));
+    }
+}
+
+// Describes the raw audio carried by a stream
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StreamFormat {
+    pub sample_format: SampleFormat,
+    pub rate: u32,
+    pub channels: u16,
+}
+
+impl StreamFormat {
+    pub fn bytes_per_frame(&self) -> usize {
+        self.sample_format.bytes_per_sample() * self.channels as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [SampleFormat; 10] = [
+        SampleFormat::S16LE,
+        SampleFormat::S16BE,
+        SampleFormat::S24LE,
+        SampleFormat::S24BE,
+        SampleFormat::S24_32LE,
+        SampleFormat::S24_32BE,
+        SampleFormat::S32LE,
+        SampleFormat::S32BE,
+        SampleFormat::F32LE,
+        SampleFormat::F32BE,
+    ];
+
+    // Precision a format keeps, counting the 24 bits of a float's mantissa
+    fn precision(format: SampleFormat) -> u32 {
+        if format.is_float() { 24 } else { format.bits() }
+    }
+
+    // Samples in the top `bits` of an i32, including both ends of the range, which every format with
+    // at least that precision holds exactly
+    fn samples(bits: u32) -> Vec<i32> {
+        let mask = !((1i64 << (32 - bits)) - 1) as i32;
+        let mut state: u32 = 0x2545_f491;
+        let mut samples = vec![i32::MIN, i32::MAX & mask, 0, mask, 1 << (32 - bits)];
+        samples.extend((0..1000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as i32 & mask
+        }));
+        samples
+    }
+
+    fn encode(format: SampleFormat, samples: &[i32]) -> Vec<u8> {
+        let mut bytes = vec![0; samples.len() * format.bytes_per_sample()];
+        if format.is_float() {
+            let floats: Vec<f32> = samples.iter().map(|&sample| sample as f32 / 2_147_483_648.0).collect();
+            format.write_samples(&floats, &mut bytes);
+        } else {
+            format.write_i32(samples, &mut bytes);
+        }
+        bytes
+    }
+
+    fn convert(from: SampleFormat, input: &[u8], to: SampleFormat) -> Vec<u8> {
+        let count = input.len() / from.bytes_per_sample();
+        let mut output = vec![0; count * to.bytes_per_sample()];
+        assert_eq!(SampleFormat::convert(from, input, to, &mut output), count);
+        output
+    }
+
+    fn read(format: SampleFormat, bytes: &[u8]) -> Vec<f32> {
+        let mut samples = vec![0.0; bytes.len() / format.bytes_per_sample()];
+        format.read_samples(bytes, &mut samples);
+        samples
+    }
+
+    #[test]
+    fn every_pair_round_trips() {
+        for &from in &FORMATS {
+            for &to in &FORMATS {
+                let input = encode(from, &samples(precision(from).min(precision(to))));
+                let converted = convert(from, &input, to);
+                assert_eq!(read(to, &converted), read(from, &input), "{:?} to {:?} changed the samples", from, to);
+                assert!(convert(to, &converted, from) == input, "{:?} to {:?} and back isn't lossless", from, to);
+            }
+        }
+    }
+
+    #[test]
+    fn byte_orders_are_mirror_images() {
+        for &format in &FORMATS {
+            let little = if format.is_big_endian() { format.swapped() } else { format };
+            let input = encode(little, &samples(precision(little)));
+            let swapped = convert(little, &input, little.swapped());
+            for (little, big) in input.chunks(format.bytes_per_sample()).zip(swapped.chunks(format.bytes_per_sample())) {
+                assert!(little.iter().eq(big.iter().rev()), "{:?} isn't the reverse of {:?}", little, big);
+            }
+        }
+    }
+
+    #[test]
+    fn packs_24_bit_samples() {
+        let samples = [0x1234_5600, -0x0100];
+        assert_eq!(encode(SampleFormat::S24LE, &samples), [0x56, 0x34, 0x12, 0xff, 0xff, 0xff]);
+        assert_eq!(encode(SampleFormat::S24BE, &samples), [0x12, 0x34, 0x56, 0xff, 0xff, 0xff]);
+        assert_eq!(encode(SampleFormat::S24_32LE, &samples), [0x56, 0x34, 0x12, 0x00, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(encode(SampleFormat::S24_32BE, &samples), [0x00, 0x12, 0x34, 0x56, 0xff, 0xff, 0xff, 0xff]);
+
+        // The padding byte of a 32-bit container is ignored
+        let mut decoded = [0; 2];
+        SampleFormat::S24_32LE.read_i32(&[0x56, 0x34, 0x12, 0xab, 0xff, 0xff, 0xff, 0x00], &mut decoded);
+        assert_eq!(decoded, samples);
+        SampleFormat::S24_32BE.read_i32(&[0xab, 0x12, 0x34, 0x56, 0x00, 0xff, 0xff, 0xff], &mut decoded);
+        assert_eq!(decoded, samples);
+    }
+
+    #[test]
+    fn clamps_float_to_full_scale() {
+        let floats = [1.0, -1.0, 1.5, -1.5, 1e9, -1e9];
+        for &format in &[SampleFormat::S16LE, SampleFormat::S24BE, SampleFormat::S24_32LE, SampleFormat::S32BE] {
+            let mut bytes = vec![0; floats.len() * format.bytes_per_sample()];
+            format.write_samples(&floats, &mut bytes);
+            let mut decoded = vec![0; floats.len()];
+            format.read_i32(&bytes, &mut decoded);
+
+            let max = i32::MAX >> (32 - format.bits()) << (32 - format.bits());
+            assert_eq!(decoded, [max, i32::MIN, max, i32::MIN, max, i32::MIN], "{:?}", format);
+        }
+
+        // Float to float passes samples past full scale through untouched
+        let mut bytes = vec![0; floats.len() * 4];
+        SampleFormat::F32LE.write_samples(&floats, &mut bytes);
+        assert_eq!(read(SampleFormat::F32BE, &convert(SampleFormat::F32LE, &bytes, SampleFormat::F32BE)), floats);
+    }
+
+    #[test]
+    fn narrowing_rounds_to_nearest() {
+        // Half of an S16 step up from zero rounds up, just under half rounds down, and the top of
+        // the range saturates instead of wrapping
+        let samples = [0x8000, 0x7f00, -0x8000, i32::MAX & !0xff];
+        let narrowed = convert(SampleFormat::S24LE, &encode(SampleFormat::S24LE, &samples), SampleFormat::S16LE);
+        let mut decoded = [0; 4];
+        SampleFormat::S16LE.read_i32(&narrowed, &mut decoded);
+        assert_eq!(decoded, [0x1_0000, 0, 0, 0x7fff_0000]);
+    }
+
+    #[test]
+    fn converts_only_what_fits() {
+        let input = encode(SampleFormat::S16LE, &samples(16)[..10]);
+        let mut output = vec![0; 7 * 3 + 2];
+        assert_eq!(SampleFormat::convert(SampleFormat::S16LE, &input, SampleFormat::S24LE, &mut output), 7);
+        assert_eq!(output[21..], [0, 0]);
+    }
+}
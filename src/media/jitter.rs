@@ -0,0 +1,404 @@
+// Sits between the thread reading the network and the audio device, so a late packet never blocks
+// the device. The buffer holds back playback until it has the target latency buffered, and the
+// target grows when packets arrive unevenly, using the interarrival jitter estimate from RFC 3550.
+// While playing, the audio is resampled to the rate the device plays at, and very slightly more to
+// hold the fill level at the target against clock drift, see media::drift.
+
+use crate::error::Error;
+use crate::media::drift::DriftController;
+use crate::media::format::StreamFormat;
+use crate::media::resample::{ResampleQuality, Resampler};
+use crate::network::Packet;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// How many times the measured jitter the buffer tries to hold
+const JITTER_MULTIPLIER: f64 = 4.0;
+
+// Upper bound on how far jitter can push the target, in microseconds
+const MAX_ADAPTIVE_LATENCY: u32 = 500_000;
+
+// A jump in transit time larger than this is a restarted sender rather than jitter
+const MAX_TRANSIT_STEP: i64 = 1_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Concealment {
+    // Play silence until enough audio has arrived again
+    Silence,
+    // Repeat the last audio that was played once, then fall back to silence
+    Repeat,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct JitterSettings {
+    // Microseconds of audio to buffer before playing. Jitter can raise this but never lower it
+    pub latency: u32,
+    pub conceal: Concealment,
+}
+
+impl Default for JitterSettings {
+    fn default() -> Self {
+        JitterSettings {
+            latency: 60_000,
+            conceal: Concealment::Silence,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JitterStats {
+    // Times the device asked for audio that hadn't arrived yet
+    pub underruns: u64,
+    // Frames thrown away because too much audio had built up
+    pub dropped_frames: u64,
+    // Interarrival jitter, target latency and current fill, all in microseconds
+    pub jitter: u32,
+    pub target: u32,
+    pub buffered: u32,
+    // Playback speed correction for clock drift, in parts per million
+    pub drift_correction: i32,
+}
+
+struct State {
+    samples: VecDeque<u8>,
+    // False while the buffer is filling up to the target, either at the start or after an underrun
+    playing: bool,
+    // The last audio handed out, and whether it has already been repeated over an underrun
+    last: Vec<u8>,
+    repeated: bool,
+    jitter: f64,
+    last_transit: Option<i64>,
+    // Corrects for the sender's clock running at a different rate from the playback device's
+    drift: DriftController,
+    resampler: Resampler,
+    // Scratch space for resampling, kept so the audio callback doesn't allocate
+    bytes: Vec<u8>,
+    input: Vec<f32>,
+    output: Vec<f32>,
+    closed: bool,
+    error: Option<Error>,
+    stats: JitterStats,
+}
+
+pub struct JitterBuffer {
+    format: StreamFormat,
+    // Rate audio is handed out at
+    rate: u32,
+    settings: JitterSettings,
+    start: Instant,
+    state: Mutex<State>,
+}
+
+impl JitterBuffer {
+    // Buffers a stream in `format` for a device that plays at `rate`
+    pub fn new(format: StreamFormat, rate: u32, settings: JitterSettings, quality: ResampleQuality) -> JitterBuffer {
+        JitterBuffer {
+            format,
+            rate,
+            settings,
+            start: Instant::now(),
+            state: Mutex::new(State {
+                samples: VecDeque::new(),
+                playing: false,
+                last: vec![],
+                repeated: false,
+                jitter: 0.0,
+                last_transit: None,
+                drift: DriftController::new(),
+                resampler: Resampler::new(format.channels as usize, format.rate, rate, quality),
+                bytes: vec![],
+                input: vec![],
+                output: vec![],
+                closed: false,
+                error: None,
+                stats: JitterStats::default(),
+            }),
+        }
+    }
+
+    // Format of the stream that is pushed. What is popped is at the device's rate
+    pub fn format(&self) -> StreamFormat {
+        self.format
+    }
+
+    fn frames_to_microseconds(&self, frames: usize) -> u32 {
+        (frames as u64 * 1_000_000 / self.format.rate as u64) as u32
+    }
+
+    fn microseconds_to_bytes(&self, microseconds: u32) -> usize {
+        let frames = microseconds as u64 * self.format.rate as u64 / 1_000_000;
+        frames as usize * self.format.bytes_per_frame()
+    }
+
+    fn target(&self, state: &State) -> u32 {
+        let adaptive = ((state.jitter * JITTER_MULTIPLIER) as u32).min(MAX_ADAPTIVE_LATENCY);
+        self.settings.latency.max(adaptive)
+    }
+
+    // Adds a decoded packet. Called from the network side. Once the buffer is closed nothing more
+    // is taken, so closing it ends playback even while packets keep arriving
+    pub fn push(&self, packet: &Packet) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return;
+        }
+
+        let arrival = self.start.elapsed();
+        let arrival = arrival.as_secs() as i64 * 1_000_000 + arrival.subsec_micros() as i64;
+        let transit = arrival - packet.timestamp as i64;
+        if let Some(last_transit) = state.last_transit {
+            let step = (transit - last_transit).abs();
+            if step < MAX_TRANSIT_STEP {
+                state.jitter += (step as f64 - state.jitter) / 16.0;
+            }
+        }
+        state.last_transit = Some(transit);
+
+        state.samples.extend(packet.payload.iter());
+
+        // Anything well past the target is latency nobody asked for, so catch up to the target
+        let target = self.microseconds_to_bytes(self.target(&state));
+        if state.samples.len() > target * 2 {
+            let excess = state.samples.len() - target;
+            let excess = excess - excess % self.format.bytes_per_frame();
+            state.samples.drain(..excess);
+            state.stats.dropped_frames += (excess / self.format.bytes_per_frame()) as u64;
+        }
+    }
+
+    // Called when the network side has reconnected. The new stream's timestamps have nothing to do
+    // with the old ones, and playback waits for the target to build up again.
+    pub fn restart(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.last_transit = None;
+        if state.samples.is_empty() {
+            state.playing = false;
+        }
+    }
+
+    // Marks the end of the stream, along with the error that ended it. What is buffered still plays.
+    // Only the first call counts.
+    pub fn close(&self, error: Option<Error>) {
+        let mut state = self.state.lock().unwrap();
+        if !state.closed {
+            state.closed = true;
+            state.error = error;
+        }
+    }
+
+    // Fills `output` in the output format without blocking, concealing whatever hasn't arrived.
+    // Returns false once the stream has been closed and everything buffered has been played.
+    pub fn pop(&self, output: &mut [u8]) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        if state.closed && state.samples.is_empty() {
+            return false;
+        }
+        if !state.playing && (state.closed || state.samples.len() >= self.microseconds_to_bytes(self.target(state))) {
+            state.playing = true;
+        }
+
+        let available = if state.playing { self.resample(state, output) } else { 0 };
+        if available < output.len() {
+            // The resampler starts over once audio is back
+            state.resampler.reset();
+            state.drift.reset();
+        }
+        if available > 0 {
+            state.last.clear();
+            state.last.extend_from_slice(&output[..available]);
+            state.repeated = false;
+        }
+
+        if available < output.len() {
+            if state.playing {
+                state.playing = false;
+                state.stats.underruns += 1;
+            }
+
+            let missing = &mut output[available..];
+            if self.settings.conceal == Concealment::Repeat && !state.repeated && !state.last.is_empty() {
+                for (byte, sample) in missing.iter_mut().zip(state.last.iter().cycle()) {
+                    *byte = *sample;
+                }
+                state.repeated = true;
+            } else {
+                for byte in missing.iter_mut() {
+                    *byte = 0;
+                }
+            }
+        }
+
+        true
+    }
+
+    // Fills as much of `output` as the buffered audio allows, at the rate the drift controller asks
+    // for, and returns the number of bytes filled
+    fn resample(&self, state: &mut State, output: &mut [u8]) -> usize {
+        let sample_format = self.format.sample_format;
+        let bytes_per_sample = sample_format.bytes_per_sample();
+        let bytes_per_frame = self.format.bytes_per_frame();
+        let channels = self.format.channels as usize;
+        let frames = output.len() / bytes_per_frame;
+
+        let ratio = state.drift.ratio();
+        let buffered = state.samples.len() / bytes_per_frame;
+        let wanted = state.resampler.input_frames(frames, ratio);
+        let input_frames = wanted.min(buffered);
+
+        state.bytes.clear();
+        state.bytes.extend(state.samples.drain(..input_frames * bytes_per_frame));
+        state.input.resize(state.bytes.len() / bytes_per_sample, 0.0);
+        sample_format.read_samples(&state.bytes, &mut state.input);
+
+        // Short of audio, whatever is left plays. Once the stream is closed nothing more is coming,
+        // so the filter is flushed with silence
+        let frames = if input_frames == wanted {
+            frames
+        } else if state.closed {
+            state.input.resize(wanted * channels, 0.0);
+            frames
+        } else {
+            state.resampler.output_frames(input_frames, ratio).min(frames)
+        };
+
+        state.output.resize(frames * channels, 0.0);
+        state.resampler.process(&state.input, &mut state.output, ratio);
+        let filled = sample_format.write_samples(&state.output, output) * bytes_per_sample;
+
+        if input_frames == wanted {
+            let fill = (state.samples.len() / bytes_per_frame) as f64 / self.format.rate as f64;
+            let target = self.target(state) as f64 / 1_000_000.0;
+            state.drift.update(fill, target, frames as f64 / self.rate as f64);
+        }
+        filled
+    }
+
+    // Takes the error the stream was closed with
+    pub fn take_error(&self) -> Option<Error> {
+        self.state.lock().unwrap().error.take()
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        let state = self.state.lock().unwrap();
+        JitterStats {
+            jitter: state.jitter as u32,
+            target: self.target(&state),
+            buffered: self.frames_to_microseconds(state.samples.len() / self.format.bytes_per_frame()),
+            drift_correction: ((state.drift.ratio() - 1.0) * 1_000_000.0).round() as i32,
+            ..state.stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::format::SampleFormat;
+
+    const FORMAT: StreamFormat = StreamFormat { sample_format: SampleFormat::S16LE, rate: 48_000, channels: 1 };
+
+    fn buffer(latency: u32, conceal: Concealment) -> JitterBuffer {
+        JitterBuffer::new(FORMAT, FORMAT.rate, JitterSettings { latency, conceal }, ResampleQuality::Low)
+    }
+
+    // A packet of `milliseconds` of a constant quarter of full scale. Every packet claims the same
+    // send time, and they are pushed together, so none of them look like jitter
+    fn packet(milliseconds: usize) -> Packet {
+        Packet::new(0, [0x00, 0x20].repeat(milliseconds * 48))
+    }
+
+    fn pop(buffer: &JitterBuffer, milliseconds: usize) -> Option<Vec<i16>> {
+        let mut output = vec![0xff; milliseconds * 48 * 2];
+        if !buffer.pop(&mut output) {
+            return None;
+        }
+        Some(output.chunks(2).map(|sample| i16::from_le_bytes([sample[0], sample[1]])).collect())
+    }
+
+    fn is_silent(samples: &[i16]) -> bool {
+        samples.iter().all(|&sample| sample == 0)
+    }
+
+    #[test]
+    fn waits_for_the_target_latency() {
+        let buffer = buffer(20_000, Concealment::Silence);
+        buffer.push(&packet(10));
+        assert!(is_silent(&pop(&buffer, 5).unwrap()));
+        assert_eq!(buffer.stats().buffered, 10_000);
+
+        // Still waiting isn't an underrun
+        buffer.push(&packet(10));
+        assert_eq!(buffer.stats().underruns, 0);
+        let samples = pop(&buffer, 5).unwrap();
+        assert_eq!(*samples.last().unwrap(), 0x2000);
+        assert_eq!(buffer.stats().underruns, 0);
+    }
+
+    #[test]
+    fn conceals_underruns_with_silence() {
+        let buffer = buffer(10_000, Concealment::Silence);
+        buffer.push(&packet(10));
+        let samples = pop(&buffer, 20).unwrap();
+        let played = samples.iter().rposition(|&sample| sample != 0).unwrap() + 1;
+        assert!(played < samples.len());
+        assert!(is_silent(&samples[played..]));
+        assert_eq!(buffer.stats().underruns, 1);
+
+        // Only running dry while playing counts
+        assert!(is_silent(&pop(&buffer, 5).unwrap()));
+        assert_eq!(buffer.stats().underruns, 1);
+    }
+
+    #[test]
+    fn conceals_underruns_by_repeating_once() {
+        let buffer = buffer(10_000, Concealment::Repeat);
+        buffer.push(&packet(10));
+        let samples = pop(&buffer, 20).unwrap();
+        // Whatever played is repeated from its start to fill the rest
+        let played = (1..samples.len())
+            .find(|&played| samples[played..].iter().zip(samples.iter().cycle()).all(|(a, b)| a == b))
+            .unwrap();
+        assert!(played < samples.len());
+        assert!(samples[..played].contains(&0x2000));
+        assert_eq!(buffer.stats().underruns, 1);
+
+        // After one repeat it is silence
+        assert!(is_silent(&pop(&buffer, 5).unwrap()));
+    }
+
+    #[test]
+    fn drops_what_builds_up_past_the_target() {
+        let buffer = buffer(10_000, Concealment::Silence);
+        buffer.push(&packet(20));
+        assert_eq!(buffer.stats().dropped_frames, 0);
+
+        // Over twice the target, so back down to it
+        buffer.push(&packet(5));
+        let stats = buffer.stats();
+        assert_eq!(stats.buffered, 10_000);
+        assert_eq!(stats.dropped_frames, 15 * 48);
+    }
+
+    #[test]
+    fn plays_out_what_is_left_once_closed() {
+        let buffer = buffer(20_000, Concealment::Silence);
+        buffer.push(&packet(5));
+        buffer.close(Some(Error::Closed));
+        // Closed, so nothing more is taken
+        buffer.push(&packet(5));
+        assert_eq!(buffer.stats().buffered, 5_000);
+
+        // Short of the target, but nothing more is coming so it plays anyway
+        let samples = pop(&buffer, 10).unwrap();
+        assert!(samples.contains(&0x2000));
+        assert_eq!(buffer.stats().underruns, 0);
+
+        assert_eq!(pop(&buffer, 10), None);
+        assert_eq!(pop(&buffer, 10), None);
+        assert!(matches!(buffer.take_error(), Some(Error::Closed)));
+        assert!(buffer.take_error().is_none());
+    }
+}
@@ -1,21 +1,25 @@
-#[cfg(target_os = "windows")]
-use crate::platform::windows::Interface;
+use crate::config::Config;
+use crate::error::Result;
+use crate::shutdown::Shutdown;
 
-#[cfg(target_os = "linux")]
-use crate::platform::linux::Interface;
+pub mod backend;
+pub mod channels;
+pub mod codec;
+pub mod device;
+pub mod dither;
+pub mod drift;
+pub mod format;
+pub mod jitter;
+pub mod resample;
+
+pub use backend::{backends, create_audio_interface, Backend};
+pub use device::{Device, DeviceKind};
 
 pub trait InterfaceTrait {
     fn init(&self);
-    fn start_playback(&self) -> Result<(), ()>;
-    fn start_recording(&self) -> Result<(), ()>;
-}
-
-#[cfg(target_os = "windows")]
-pub fn create_audio_interface() -> Interface {
-    Interface::new()
-}
-
-#[cfg(target_os = "linux")]
-pub fn create_audio_interface() -> Interface {
-    Interface::new()
+    // Every capture and playback device the backend can use
+    fn devices(&self) -> Result<Vec<Device>>;
+    // Both run until the stream ends or `shutdown` is requested
+    fn start_playback(&self, config: &Config, shutdown: &Shutdown) -> Result<()>;
+    fn start_recording(&self, config: &Config, shutdown: &Shutdown) -> Result<()>;
 }
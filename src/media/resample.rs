@@ -0,0 +1,359 @@
+// Converts interleaved float audio between sample rates with a Kaiser windowed sinc filter. The
+// filter is tabulated at a few hundred fractional offsets between two input frames, and the
+// coefficients for an offset in between two of those phases are interpolated, so the ratio can be
+// anything and can change from one call to the next, which drift correction relies on. The filter
+// cuts off just below the lower of the two Nyquist frequencies, so nothing above it aliases when
+// downsampling and no images of the input appear above it when upsampling.
+
+use std::f64::consts::PI;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResampleQuality {
+    // 32 taps and 70 dB of rejection, passing everything below about 16 kHz at 44.1 kHz
+    Low,
+    // 64 taps and 96 dB, passing up to about 18 kHz
+    Medium,
+    // 160 taps and 120 dB, passing up to about 20 kHz
+    High,
+}
+
+impl ResampleQuality {
+    // Taps, stopband attenuation in dB and number of phases, which has to be high enough that
+    // interpolating between phases is no less accurate than the filter itself
+    fn design(self) -> (usize, f64, usize) {
+        match self {
+            ResampleQuality::Low => (32, 70.0, 64),
+            ResampleQuality::Medium => (64, 96.0, 256),
+            ResampleQuality::High => (160, 120.0, 1024),
+        }
+    }
+}
+
+// Kaiser's formulas for the window shape and the transition band are empirical fits, and at these
+// lengths the first sidelobe past the transition comes out up to a dB higher than they predict.
+// The shorter filters are limited by the transition and the longest by the window, so the margin
+// goes into the attenuation both formulas are given
+const KAISER_MARGIN: f64 = 2.0;
+
+pub struct Resampler {
+    channels: usize,
+    // Input frames per output frame, before any correction
+    ratio: f64,
+    taps: usize,
+    phases: usize,
+    // A row of `taps` coefficients for each phase, and one more for a whole frame's offset
+    table: Vec<f32>,
+    // Input frames the filter still has to see, with the oldest it needs first
+    buffer: Vec<f32>,
+    // Position of the next output frame, in input frames from the start of `buffer`
+    position: f64,
+    // Coefficients interpolated for the output frame being worked out
+    coefficients: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(channels: usize, input_rate: u32, output_rate: u32, quality: ResampleQuality) -> Resampler {
+        let ratio = input_rate as f64 / output_rate as f64;
+        let (taps, attenuation, phases) = quality.design();
+        let attenuation = attenuation + KAISER_MARGIN;
+
+        // Downsampling cuts off below the input's Nyquist frequency, which takes a longer filter
+        // for a transition band just as narrow
+        let scale = ratio.max(1.0);
+        let base_taps = taps;
+        let taps = (taps as f64 * scale / 2.0).ceil() as usize * 2;
+
+        // Kaiser's estimates for the transition band, in fractions of the lower Nyquist frequency,
+        // and the window shape that gives the attenuation. The stopband starts at that frequency
+        let transition = 2.0 * (attenuation - 7.95) / (14.36 * base_taps as f64);
+        let cutoff = (1.0 - transition / 2.0) / scale;
+        let beta = 0.1102 * (attenuation - 8.7);
+
+        let half = taps / 2;
+        let mut table = Vec::with_capacity((phases + 1) * taps);
+        for phase in 0..=phases {
+            let offset = phase as f64 / phases as f64;
+            let row = (0..taps).map(|tap| {
+                // Distance from the output frame to the input frame this tap weighs
+                let distance = tap as f64 - (half - 1) as f64 - offset;
+                cutoff * sinc(cutoff * distance) * kaiser(distance / half as f64, beta)
+            }).collect::<Vec<_>>();
+
+            // Each row on its own passes DC at unity gain, so the phases don't modulate the level
+            let sum: f64 = row.iter().sum();
+            table.extend(row.iter().map(|coefficient| (coefficient / sum) as f32));
+        }
+
+        let mut resampler = Resampler {
+            channels,
+            ratio,
+            taps,
+            phases,
+            table,
+            buffer: vec![],
+            position: 0.0,
+            coefficients: vec![0.0; taps],
+        };
+        resampler.reset();
+        resampler
+    }
+
+    // Starts over from silence, as after a gap in the audio
+    pub fn reset(&mut self) {
+        let history = self.taps / 2 - 1;
+        self.buffer.clear();
+        self.buffer.resize(history * self.channels, 0.0);
+        self.position = history as f64;
+    }
+
+    fn buffered_frames(&self) -> usize {
+        self.buffer.len() / self.channels
+    }
+
+    // Input frames one past the last the output frame at `position` needs
+    fn frames_needed(&self, position: f64) -> usize {
+        position.floor() as usize + self.taps / 2 + 1
+    }
+
+    // Input frames `process` needs to produce `frames` output frames, with the ratio multiplied by
+    // `correction`
+    pub fn input_frames(&self, frames: usize, correction: f64) -> usize {
+        if frames == 0 {
+            return 0;
+        }
+        let ratio = self.ratio * correction;
+        let last = self.position + (frames - 1) as f64 * ratio;
+        self.frames_needed(last).saturating_sub(self.buffered_frames())
+    }
+
+    // Output frames `process` can produce once it has another `input_frames` frames
+    pub fn output_frames(&self, input_frames: usize, correction: f64) -> usize {
+        let ratio = self.ratio * correction;
+        let available = self.buffered_frames() + input_frames;
+        let fits = |frames: usize| frames == 0 || self.frames_needed(self.position + (frames - 1) as f64 * ratio) <= available;
+
+        // Worked out directly, then nudged by however much rounding put it out
+        let limit = available as f64 - (self.taps / 2) as f64;
+        let mut frames = ((limit - self.position) / ratio).ceil().max(0.0) as usize;
+        while !fits(frames) {
+            frames -= 1;
+        }
+        while fits(frames + 1) {
+            frames += 1;
+        }
+        frames
+    }
+
+    // Takes all of `input` and fills `output`, which must not hold more frames than
+    // `output_frames` allows for that input
+    pub fn process(&mut self, input: &[f32], output: &mut [f32], correction: f64) {
+        let channels = self.channels;
+        let taps = self.taps;
+        let ratio = self.ratio * correction;
+        self.buffer.extend_from_slice(input);
+
+        let frames = output.len() / channels;
+        for (k, output) in output.chunks_exact_mut(channels).enumerate() {
+            let position = self.position + k as f64 * ratio;
+            let index = position.floor() as usize;
+
+            let phase = (position - index as f64) * self.phases as f64;
+            let row = (phase as usize).min(self.phases - 1);
+            let weight = (phase - row as f64) as f32;
+            let from = &self.table[row * taps..(row + 1) * taps];
+            let to = &self.table[(row + 1) * taps..(row + 2) * taps];
+            for (coefficient, (from, to)) in self.coefficients.iter_mut().zip(from.iter().zip(to)) {
+                *coefficient = from + (to - from) * weight;
+            }
+
+            let start = (index + 1 - taps / 2) * channels;
+            let window = &self.buffer[start..start + taps * channels];
+            for (channel, output) in output.iter_mut().enumerate() {
+                *output = window[channel..].iter()
+                    .step_by(channels)
+                    .zip(self.coefficients.iter())
+                    .map(|(sample, coefficient)| sample * coefficient)
+                    .sum();
+            }
+        }
+
+        // Keep only what the next output frame will need
+        let end = self.position + frames as f64 * ratio;
+        let consumed = (end.floor() as usize + 1 - taps / 2).min(self.buffered_frames());
+        self.buffer.drain(..consumed * channels);
+        self.position = end - consumed as f64;
+    }
+
+    // Takes all of `input` at the nominal ratio and resizes `output` to hold as much as it makes
+    // for so far. The rest comes out of the next call
+    pub fn resample(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        let frames = self.output_frames(input.len() / self.channels, 1.0);
+        output.resize(frames * self.channels, 0.0);
+        self.process(input, output, 1.0);
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+// The Kaiser window at `x` between -1 and 1
+fn kaiser(x: f64, beta: f64) -> f64 {
+    if x.abs() > 1.0 {
+        return 0.0;
+    }
+    bessel_i0(beta * (1.0 - x * x).sqrt()) / bessel_i0(beta)
+}
+
+// Zeroth order modified Bessel function of the first kind, from its power series
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let mut k = 1.0;
+    while term > sum * 1e-12 {
+        term *= (x / (2.0 * k)) * (x / (2.0 * k));
+        sum += term;
+        k += 1.0;
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUALITIES: [ResampleQuality; 3] = [ResampleQuality::Low, ResampleQuality::Medium, ResampleQuality::High];
+
+    // Rejection each quality promises for whatever would alias or image
+    fn stopband_floor(quality: ResampleQuality) -> f64 {
+        quality.design().1
+    }
+
+    // Highest frequency each quality passes, as a fraction of the lower Nyquist frequency
+    fn passband_edge(quality: ResampleQuality) -> f64 {
+        match quality {
+            ResampleQuality::Low => 0.7,
+            ResampleQuality::Medium => 0.8,
+            ResampleQuality::High => 0.9,
+        }
+    }
+
+    // Resamples a quarter of a second of a sine at `frequency`, fed in uneven pieces
+    fn resample_sine(input_rate: u32, output_rate: u32, quality: ResampleQuality, frequency: f64) -> Vec<f64> {
+        let input: Vec<f32> = (0..input_rate as usize / 4)
+            .map(|frame| (0.5 * (2.0 * PI * frequency * frame as f64 / input_rate as f64).sin()) as f32)
+            .collect();
+
+        let mut resampler = Resampler::new(1, input_rate, output_rate, quality);
+        let (mut output, mut resampled) = (vec![], vec![]);
+        for piece in input.chunks(997) {
+            resampler.resample(piece, &mut resampled);
+            output.extend(resampled.iter().map(|&sample| sample as f64));
+        }
+        // The ends are where the filter runs into the silence either side of the sine
+        output[resampler.taps..output.len() - resampler.taps].to_vec()
+    }
+
+    // Amplitudes of the sines at `frequencies` that best fit `samples`, fitted together so a loud
+    // one doesn't leak into a quiet one
+    fn amplitudes(samples: &[f64], rate: u32, frequencies: &[f64]) -> Vec<f64> {
+        let basis: Vec<Vec<f64>> = frequencies.iter()
+            .flat_map(|&frequency| {
+                let omega = 2.0 * PI * frequency / rate as f64;
+                vec![
+                    samples.iter().enumerate().map(|(n, _)| (omega * n as f64).sin()).collect(),
+                    samples.iter().enumerate().map(|(n, _)| (omega * n as f64).cos()).collect(),
+                ]
+            })
+            .collect();
+
+        // The normal equations, solved by Gaussian elimination
+        let size = basis.len();
+        let dot = |a: &[f64], b: &[f64]| a.iter().zip(b).map(|(a, b)| a * b).sum::<f64>();
+        let mut matrix: Vec<Vec<f64>> = basis.iter()
+            .map(|row| {
+                let mut equation: Vec<f64> = basis.iter().map(|column| dot(row, column)).collect();
+                equation.push(dot(row, samples));
+                equation
+            })
+            .collect();
+        for pivot in 0..size {
+            let (above, below) = matrix.split_at_mut(pivot + 1);
+            let pivot_row = &above[pivot][pivot..];
+            for row in below {
+                let factor = row[pivot] / pivot_row[0];
+                for (value, pivot_value) in row[pivot..].iter_mut().zip(pivot_row) {
+                    *value -= factor * pivot_value;
+                }
+            }
+        }
+        let mut solution = vec![0.0; size];
+        for (row, equation) in matrix.iter().enumerate().rev() {
+            let known: f64 = equation[row + 1..size].iter().zip(&solution[row + 1..]).map(|(a, b)| a * b).sum();
+            solution[row] = (equation[size] - known) / equation[row];
+        }
+
+        solution.chunks(2).map(|pair| pair[0].hypot(pair[1])).collect()
+    }
+
+    fn decibels(ratio: f64) -> f64 {
+        20.0 * ratio.log10()
+    }
+
+    const RATES: [(u32, u32); 3] = [(48_000, 44_100), (44_100, 48_000), (96_000, 48_000)];
+
+    #[test]
+    fn passes_the_passband() {
+        for &quality in &QUALITIES {
+            for &(input_rate, output_rate) in &RATES {
+                let nyquist = input_rate.min(output_rate) as f64 / 2.0;
+                for &frequency in &[1_000.0, nyquist * passband_edge(quality)] {
+                    let output = resample_sine(input_rate, output_rate, quality, frequency);
+                    let gain = decibels(amplitudes(&output, output_rate, &[frequency])[0] / 0.5);
+                    assert!(
+                        gain.abs() < 0.1,
+                        "{:?} {} to {} Hz changes {} Hz by {:.3} dB",
+                        quality, input_rate, output_rate, frequency, gain
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_aliases_and_images() {
+        // Tones across the stopband, which starts at the lower Nyquist frequency and runs to the
+        // higher one
+        const TONES: usize = 8;
+        for &quality in &QUALITIES {
+            for &(input_rate, output_rate) in &RATES {
+                let lower = input_rate.min(output_rate) as f64 / 2.0;
+                let higher = input_rate.max(output_rate) as f64 / 2.0;
+                for tone in 0..TONES {
+                    let stopband = lower + (higher - lower) * (tone as f64 + 0.5) / TONES as f64;
+                    let rejection = if input_rate > output_rate {
+                        // A tone above the output's Nyquist frequency folds back below it, where
+                        // it is the only thing left to fit
+                        let alias = output_rate as f64 - stopband;
+                        let output = resample_sine(input_rate, output_rate, quality, stopband);
+                        -decibels(amplitudes(&output, output_rate, &[alias])[0] / 0.5)
+                    } else {
+                        // A tone below the input's Nyquist frequency images above it
+                        let frequency = input_rate as f64 - stopband;
+                        let output = resample_sine(input_rate, output_rate, quality, frequency);
+                        -decibels(amplitudes(&output, output_rate, &[frequency, stopband])[1] / 0.5)
+                    };
+                    assert!(
+                        rejection > stopband_floor(quality),
+                        "{:?} {} to {} Hz lets {} Hz through at -{:.1} dB",
+                        quality, input_rate, output_rate, stopband, rejection
+                    );
+                }
+            }
+        }
+    }
+}
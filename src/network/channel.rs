@@ -0,0 +1,51 @@
+// Carries packets from the capture side to the network. The receiving end can be registered with an
+// event loop, or read from like an ordinary blocking channel with `Packets`.
+
+use crate::network::Packet;
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use mio_extras::channel;
+use std::io;
+use std::sync::mpsc::TryRecvError;
+
+pub type PacketSender = channel::SyncSender<Packet>;
+pub type PacketReceiver = channel::Receiver<Packet>;
+
+// Packets the capture side can get ahead of the network by before `send` blocks
+const CHANNEL_CAPACITY: usize = 4;
+
+pub fn packet_channel() -> (PacketSender, PacketReceiver) {
+    channel::sync_channel(CHANNEL_CAPACITY)
+}
+
+// Blocks for each packet until every sender has been dropped
+pub struct Packets {
+    receiver: PacketReceiver,
+    poll: Poll,
+    events: Events,
+}
+
+impl Packets {
+    pub fn new(receiver: PacketReceiver) -> io::Result<Packets> {
+        let poll = Poll::new()?;
+        poll.register(&receiver, Token(0), Ready::readable(), PollOpt::edge())?;
+
+        Ok(Packets { receiver, poll, events: Events::with_capacity(1) })
+    }
+}
+
+impl Iterator for Packets {
+    type Item = Packet;
+
+    fn next(&mut self) -> Option<Packet> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(packet) => return Some(packet),
+                Err(TryRecvError::Disconnected) => return None,
+                Err(TryRecvError::Empty) => match self.poll.poll(&mut self.events, None) {
+                    Err(ref error) if error.kind() != io::ErrorKind::Interrupted => return None,
+                    _ => (),
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,251 @@
+// Finds servers on the local network without knowing their addresses. A TCP server advertises an
+// `_audio-share._tcp` DNS-SD service over mDNS, with TXT records describing what it streams:
+//
+//   name | version (protocol version) | codec (pcm, opus, flac) | format (s16le, s24le, f32le, ...) | rate | channels
+//
+// The instance name is the server's name, which defaults to the machine's host name.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::media::codec::Codec;
+use crate::media::format::{SampleFormat, StreamFormat};
+use crate::network::protocol::{Handshake, VERSION};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+pub const SERVICE_TYPE: &str = "_audio-share._tcp.local.";
+
+// How long `resolve` browses for a server before giving up
+pub const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long to wait for the goodbye announcement to go out when a server stops advertising
+const UNREGISTER_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn codec_to_txt(codec: Codec) -> &'static str {
+    match codec {
+        Codec::Pcm => "pcm",
+        Codec::Opus => "opus",
+        Codec::Flac => "flac",
+    }
+}
+
+fn codec_from_txt(value: &str) -> Option<Codec> {
+    match value {
+        "pcm" => Some(Codec::Pcm),
+        "opus" => Some(Codec::Opus),
+        "flac" => Some(Codec::Flac),
+        _ => None,
+    }
+}
+
+fn sample_format_to_txt(sample_format: SampleFormat) -> &'static str {
+    match sample_format {
+        SampleFormat::S16LE => "s16le",
+        SampleFormat::S16BE => "s16be",
+        SampleFormat::S24LE => "s24le",
+        SampleFormat::S24BE => "s24be",
+        SampleFormat::S24_32LE => "s24_32le",
+        SampleFormat::S24_32BE => "s24_32be",
+        SampleFormat::S32LE => "s32le",
+        SampleFormat::S32BE => "s32be",
+        SampleFormat::F32LE => "f32le",
+        SampleFormat::F32BE => "f32be",
+    }
+}
+
+fn sample_format_from_txt(value: &str) -> Option<SampleFormat> {
+    match value {
+        "s16le" => Some(SampleFormat::S16LE),
+        "s16be" => Some(SampleFormat::S16BE),
+        "s24le" => Some(SampleFormat::S24LE),
+        "s24be" => Some(SampleFormat::S24BE),
+        "s24_32le" => Some(SampleFormat::S24_32LE),
+        "s24_32be" => Some(SampleFormat::S24_32BE),
+        "s32le" => Some(SampleFormat::S32LE),
+        "s32be" => Some(SampleFormat::S32BE),
+        "f32le" => Some(SampleFormat::F32LE),
+        "f32be" => Some(SampleFormat::F32BE),
+        _ => None,
+    }
+}
+
+#[cfg(windows)]
+pub fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "audio-share".to_string())
+}
+
+#[cfg(not(windows))]
+pub fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .map(|hostname| hostname.trim().to_string())
+        .ok()
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or_else(|| "audio-share".to_string())
+}
+
+// A server found on the network
+#[derive(Clone, Debug)]
+pub struct ServerInfo {
+    pub name: String,
+    // mDNS host name, ending in `.local.`
+    pub hostname: String,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    // What the server streams. None when its records don't say or were written by a newer version
+    pub handshake: Option<Handshake>,
+}
+
+impl ServerInfo {
+    // Address to connect to, preferring IPv4
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.addresses.iter()
+            .min_by_key(|address| address.is_ipv6())
+            .map(|address| SocketAddr::new(*address, self.port))
+    }
+
+    fn from_service(service: &ServiceInfo) -> ServerInfo {
+        let property = |key| service.get_property_val_str(key);
+
+        // The instance name is everything in front of the service type
+        let fullname = service.get_fullname();
+        let instance = fullname.strip_suffix(SERVICE_TYPE).unwrap_or(fullname).trim_end_matches('.');
+
+        let version = property("version").and_then(|version| version.parse::<u8>().ok());
+        let codec = property("codec").and_then(codec_from_txt);
+        let sample_format = property("format").and_then(sample_format_from_txt);
+        let rate = property("rate").and_then(|rate| rate.parse().ok());
+        let channels = property("channels").and_then(|channels| channels.parse().ok());
+        let handshake = match (version, codec, sample_format, rate, channels) {
+            (Some(VERSION), Some(codec), Some(sample_format), Some(rate), Some(channels)) => {
+                Some(Handshake::new(StreamFormat { sample_format, rate, channels }, codec))
+            }
+            _ => None,
+        };
+
+        let mut addresses: Vec<IpAddr> = service.get_addresses().iter().cloned().collect();
+        addresses.sort();
+
+        ServerInfo {
+            name: property("name").unwrap_or(instance).to_string(),
+            hostname: service.get_hostname().to_string(),
+            addresses,
+            port: service.get_port(),
+            handshake,
+        }
+    }
+}
+
+// Keeps a server advertised until dropped
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Advertisement {
+    pub fn new(config: &Config, handshake: &Handshake, port: u16) -> Result<Advertisement> {
+        let hostname = hostname();
+        let name = config.name.clone().unwrap_or_else(|| hostname.clone());
+
+        let rate = handshake.format.rate.to_string();
+        let channels = handshake.format.channels.to_string();
+        let version = VERSION.to_string();
+        let properties = [
+            ("name", name.as_str()),
+            ("version", version.as_str()),
+            ("codec", codec_to_txt(handshake.codec)),
+            ("format", sample_format_to_txt(handshake.format.sample_format)),
+            ("rate", rate.as_str()),
+            ("channels", channels.as_str()),
+        ];
+
+        // A server bound to one address is only reachable there, otherwise every interface counts
+        let host_address = config.host.parse::<IpAddr>().ok().filter(|address| !address.is_unspecified());
+        let service_hostname = format!("{}.local.", hostname);
+        let service = match host_address {
+            Some(address) => ServiceInfo::new(SERVICE_TYPE, &name, &service_hostname, address, port, &properties[..])?,
+            None => ServiceInfo::new(SERVICE_TYPE, &name, &service_hostname, (), port, &properties[..])?.enable_addr_auto(),
+        };
+
+        let daemon = ServiceDaemon::new()?;
+        let fullname = service.get_fullname().to_string();
+        daemon.register(service)?;
+        Ok(Advertisement { daemon, fullname })
+    }
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        // Tells listeners the server is gone, rather than leaving them to wait for the record to expire
+        if let Ok(status) = self.daemon.unregister(&self.fullname) {
+            let _ = status.recv_timeout(UNREGISTER_TIMEOUT);
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+// Browses for servers for `timeout`, then returns every one that is still around
+pub fn discover(timeout: Duration) -> Result<Vec<ServerInfo>> {
+    let mut servers = HashMap::new();
+    browse(timeout, |event| {
+        match event {
+            ServiceEvent::ServiceResolved(service) => {
+                servers.insert(service.get_fullname().to_string(), ServerInfo::from_service(&service));
+            }
+            ServiceEvent::ServiceRemoved(_, fullname) => {
+                servers.remove(&fullname);
+            }
+            _ => (),
+        }
+        false
+    })?;
+
+    let mut servers: Vec<ServerInfo> = servers.into_values().collect();
+    servers.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(servers)
+}
+
+// Finds the server advertised as `name`, ignoring case
+pub fn resolve(name: &str, timeout: Duration) -> Result<ServerInfo> {
+    let mut found = None;
+    browse(timeout, |event| {
+        if let ServiceEvent::ServiceResolved(service) = event {
+            let server = ServerInfo::from_service(&service);
+            if server.name.eq_ignore_ascii_case(name) && server.address().is_some() {
+                found = Some(server);
+                return true;
+            }
+        }
+        false
+    })?;
+
+    found.ok_or_else(|| Error::Discovery(format!("no server named \"{}\" found on the network", name)))
+}
+
+// Passes browse events to `handle` until it returns true or `timeout` runs out
+fn browse<F: FnMut(ServiceEvent) -> bool>(timeout: Duration, mut handle: F) -> Result<()> {
+    let daemon = ServiceDaemon::new()?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        match events.recv_timeout(deadline - now) {
+            Ok(event) => {
+                if handle(event) {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+
+    let _ = daemon.stop_browse(SERVICE_TYPE);
+    let _ = daemon.shutdown();
+    Ok(())
+}
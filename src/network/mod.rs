@@ -1,34 +1,612 @@
-use std::io::Write;
-use std::net::TcpListener;
-use std::sync::mpsc::Receiver;
-
-pub fn accept_clients(receiver: Receiver<Vec<u8>>) {
-    let listener = TcpListener::bind("0.0.0.0:42795").expect("Could not bind to port");
-    listener.set_nonblocking(true).expect("Could not make listener non-blocking");
-    let mut clients = vec![];
-
-    'accept: loop {
-        match listener.accept() {
-            Ok((stream, _address)) => {
-                println!("New client");
-                clients.push(stream);
-            }
-            _ => ()
-        }
-
-        match receiver.try_recv() {
-            Ok(val) => {
-                clients.retain(|mut client| {
-                    if let Err(_e) = client.write_all(&val) {
-                        println!("Could not write to client. Disconnecting client");
-                        return false;
-                    }
-                    true
-                })
-            },
-            _ => {
-                std::thread::sleep(std::time::Duration::from_millis(5));
-            },
+use crate::config::{Config, Overflow, Transport};
+use crate::error::{Error, Result};
+use crate::media::codec::{self, Codec, Decoder, Encoder};
+use crate::media::format::StreamFormat;
+use crate::media::jitter::JitterBuffer;
+use crate::shutdown::Shutdown;
+use channel::Packets;
+use discovery::Advertisement;
+use reconnect::Backoff;
+use mio::net::TcpListener as MioTcpListener;
+use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use std::collections::HashMap;
+use std::io;
+use std::net::{self, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Weak};
+use std::sync::mpsc::TryRecvError;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use writer::ClientWriter;
+
+pub mod channel;
+pub mod discovery;
+pub mod multicast;
+pub mod protocol;
+pub mod reconnect;
+pub mod rtp;
+pub mod writer;
+
+pub use channel::{packet_channel, PacketReceiver, PacketSender};
+pub use discovery::{discover, ServerInfo};
+pub use protocol::{Handshake, Packet};
+pub use reconnect::ReconnectSettings;
+pub use rtp::{RtpReceiver, RtpSender};
+pub use writer::{ClientStats, ServerStats};
+
+// Where the playback side gets its packets from
+pub trait Source: Send {
+    fn format(&self) -> StreamFormat;
+    fn codec(&self) -> Codec;
+    fn read_packet(&mut self) -> Result<Packet>;
+    // Makes `read_packet` stop waiting for the network and fail once `shutdown` is requested
+    fn stop_on(&mut self, shutdown: &Shutdown) -> Result<()>;
+}
+
+// Accepts clients and sends every packet received from the capture side to all of them. Everything
+// happens on one event loop, and each client is written to from its own queue, so a slow client
+// can't hold up the others or the capture side.
+pub struct Server {
+    listener: MioTcpListener,
+    handshake: Handshake,
+    clients: HashMap<Token, ClientWriter>,
+    next_token: usize,
+    sequence: u32,
+    queue_size: usize,
+    overflow: Overflow,
+    stats: ServerStats,
+    last_drop_report: Instant,
+    shutdown: Registration,
+    shutdown_handle: ShutdownHandle,
+}
+
+// Stops a running `Server` from another thread
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    readiness: SetReadiness,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        let _ = self.readiness.set_readiness(Ready::readable());
+    }
+}
+
+const LISTENER: Token = Token(0);
+const PACKETS: Token = Token(1);
+const SHUTDOWN: Token = Token(2);
+const FIRST_CLIENT: usize = 3;
+
+// A client that takes nothing for this long is disconnected
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long clients get to take what is still queued for them when the server stops
+const GOODBYE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Packets dropped for clients that can't keep up are reported at most this often
+const DROP_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+impl Server {
+    pub fn bind(config: &Config, handshake: Handshake) -> Result<Server> {
+        let listener = MioTcpListener::from_std(TcpListener::bind(config.address())?)?;
+        let (shutdown, readiness) = Registration::new2();
+
+        Ok(Server {
+            listener,
+            handshake,
+            clients: HashMap::new(),
+            next_token: FIRST_CLIENT,
+            sequence: 0,
+            queue_size: config.queue_size,
+            overflow: config.overflow,
+            stats: ServerStats::default(),
+            last_drop_report: Instant::now(),
+            shutdown,
+            shutdown_handle: ShutdownHandle { readiness },
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    // Counters for the connected clients, which keep updating while the server runs
+    pub fn stats(&self) -> ServerStats {
+        self.stats.clone()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown_handle.clone()
+    }
+
+    fn accept(&mut self, poll: &Poll) -> Result<()> {
+        loop {
+            let (stream, address) = match self.listener.accept() {
+                Ok(client) => client,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => {
+                    println!("Could not accept client: {}", e);
+                    return Ok(());
+                }
+            };
+
+            let mut greeting = Vec::with_capacity(Handshake::SIZE);
+            self.handshake.write_to(&mut greeting)?;
+            stream.set_nodelay(true)?;
+
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            let mut client = ClientWriter::new(stream, address, greeting, self.queue_size, self.overflow, &self.stats);
+
+            let registered = poll.register(client.stream(), token, Ready::readable() | Ready::writable(), PollOpt::edge());
+            match registered.and_then(|()| client.flush()) {
+                Ok(()) => {
+                    println!("New client {}", address);
+                    self.clients.insert(token, client);
+                }
+                Err(e) => println!("Could not send handshake to client {}: {}", address, e),
+            }
+        }
+    }
+
+    fn broadcast(&mut self, mut packet: Packet) {
+        // An empty packet would read as a goodbye
+        if packet.payload.is_empty() {
+            return;
+        }
+
+        packet.sequence = self.sequence;
+        self.sequence = self.sequence.wrapping_add(1);
+
+        let bytes = Arc::new(packet.to_bytes());
+        let mut disconnected = vec![];
+        for (token, client) in &mut self.clients {
+            if !client.push(bytes.clone()) {
+                disconnected.push((*token, "its queue is full".to_string()));
+            } else if let Err(e) = client.flush() {
+                disconnected.push((*token, e.to_string()));
+            }
+        }
+
+        for (token, reason) in disconnected {
+            self.disconnect(token, &reason);
+        }
+    }
+
+    fn disconnect(&mut self, token: Token, reason: &str) {
+        if let Some(client) = self.clients.remove(&token) {
+            let stats = client.stats();
+            println!(
+                "Disconnecting client {} because {}, after {} packets sent and {} dropped",
+                stats.address, reason, stats.sent_packets, stats.dropped_packets
+            );
         }
     }
+
+    fn client_ready(&mut self, token: Token, readiness: Ready) {
+        let client = match self.clients.get_mut(&token) {
+            Some(client) => client,
+            None => return,
+        };
+
+        let result = if readiness.is_readable() && client.is_closed() {
+            Err("it hung up".to_string())
+        } else if readiness.is_writable() {
+            client.flush().map_err(|e| e.to_string())
+        } else {
+            Ok(())
+        };
+
+        if let Err(reason) = result {
+            self.disconnect(token, &reason);
+        }
+    }
+
+    fn disconnect_stalled(&mut self) {
+        let now = Instant::now();
+        let stalled: Vec<Token> = self.clients.iter()
+            .filter(|(_, client)| client.deadline(CLIENT_TIMEOUT).is_some_and(|deadline| deadline <= now))
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in stalled {
+            self.disconnect(token, "it stopped reading");
+        }
+    }
+
+    fn report_drops(&mut self) {
+        let now = Instant::now();
+        if now.duration_since(self.last_drop_report) < DROP_REPORT_INTERVAL {
+            return;
+        }
+        self.last_drop_report = now;
+
+        for client in self.clients.values_mut() {
+            let dropped = client.take_unreported_drops();
+            if dropped > 0 {
+                let stats = client.stats();
+                println!(
+                    "Dropped {} packets for client {} because its queue is full ({} in total)",
+                    dropped, stats.address, stats.dropped_packets
+                );
+            }
+        }
+    }
+
+    // How long the event loop can wait before a stalled client times out
+    fn next_timeout(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.clients.values()
+            .filter_map(|client| client.deadline(CLIENT_TIMEOUT))
+            .min()
+            .map(|deadline| if deadline > now { deadline - now } else { Duration::from_secs(0) })
+    }
+
+    // Flushes what is queued for each client followed by a goodbye, giving up on clients that don't
+    // take it in time
+    fn say_goodbye(&mut self, poll: &Poll, events: &mut Events) -> Result<()> {
+        let mut goodbye = Packet::goodbye();
+        goodbye.sequence = self.sequence;
+        let goodbye = Arc::new(goodbye.to_bytes());
+
+        let deadline = Instant::now() + GOODBYE_TIMEOUT;
+        for client in self.clients.values_mut() {
+            client.push_last(goodbye.clone());
+        }
+
+        loop {
+            self.clients.retain(|_, client| client.flush().is_ok() && !client.is_flushed());
+
+            let now = Instant::now();
+            if self.clients.is_empty() || now >= deadline {
+                break;
+            }
+
+            match poll.poll(events, Some(deadline - now)) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                result => {
+                    result?;
+                }
+            }
+        }
+
+        self.clients.clear();
+        Ok(())
+    }
+
+    // Runs until every sender for `receiver` has been dropped or the server is shut down
+    pub fn run(mut self, receiver: PacketReceiver) -> Result<()> {
+        let poll = Poll::new()?;
+        poll.register(&self.listener, LISTENER, Ready::readable(), PollOpt::edge())?;
+        poll.register(&receiver, PACKETS, Ready::readable(), PollOpt::edge())?;
+        poll.register(&self.shutdown, SHUTDOWN, Ready::readable(), PollOpt::edge())?;
+
+        let mut events = Events::with_capacity(256);
+        loop {
+            match poll.poll(&mut events, self.next_timeout()) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => result?,
+            };
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept(&poll)?,
+                    PACKETS => loop {
+                        match receiver.try_recv() {
+                            Ok(packet) => self.broadcast(packet),
+                            Err(TryRecvError::Empty) => break,
+                            Err(TryRecvError::Disconnected) => return self.say_goodbye(&poll, &mut events),
+                        }
+                    },
+                    SHUTDOWN => return self.say_goodbye(&poll, &mut events),
+                    token => self.client_ready(token, event.readiness()),
+                }
+            }
+
+            self.disconnect_stalled();
+            self.report_drops();
+        }
+    }
+}
+
+// Encodes packets from the capture side on their own thread, so a slow encoder doesn't hold up the
+// network
+fn spawn_encoder(
+    receiver: PacketReceiver,
+    mut encoder: Box<dyn Encoder>,
+    format: StreamFormat,
+) -> (PacketReceiver, JoinHandle<Result<()>>) {
+    let (sender, encoded_receiver) = packet_channel();
+    let frames_to_microseconds = move |frames: usize| frames as u64 * 1_000_000 / format.rate as u64;
+
+    let thread = std::thread::spawn(move || {
+        let mut timestamp = 0;
+        for packet in Packets::new(receiver)? {
+            // Samples held over from earlier packets were captured before this one
+            let buffered = frames_to_microseconds(encoder.buffered_frames());
+            timestamp = packet.timestamp.saturating_sub(buffered);
+
+            for encoded in encoder.encode(&packet.payload)? {
+                if sender.send(Packet::new(timestamp, encoded.data)).is_err() {
+                    return Ok(());
+                }
+                timestamp += frames_to_microseconds(encoded.frames);
+            }
+        }
+
+        // Capture has ended, so the samples still held over make up the last frame
+        for encoded in encoder.flush()? {
+            if sender.send(Packet::new(timestamp, encoded.data)).is_err() {
+                break;
+            }
+            timestamp += frames_to_microseconds(encoded.frames);
+        }
+        Ok(())
+    });
+
+    (encoded_receiver, thread)
+}
+
+// Encodes packets from the capture side and sends them using the configured transport until every
+// sender for `receiver` has been dropped
+pub fn serve(receiver: PacketReceiver, config: Config, format: StreamFormat) -> Result<()> {
+    serve_with(receiver, config, format, |_| ())
+}
+
+// Like `serve`, and tells `listening` the address the TCP server is bound to, which is how a caller
+// that asks for port 0 learns which port it got
+pub fn serve_with<F: FnOnce(SocketAddr)>(receiver: PacketReceiver, config: Config, format: StreamFormat, listening: F) -> Result<()> {
+    let handshake = Handshake::new(format, config.codec);
+
+    let mut encode_thread = None;
+    let receiver = match config.codec {
+        Codec::Pcm => receiver,
+        _ => {
+            let encoder = codec::create_encoder(&config, format)?;
+            let (receiver, thread) = spawn_encoder(receiver, encoder, format);
+            encode_thread = Some(thread);
+            receiver
+        }
+    };
+
+    let result = match config.transport {
+        Transport::Tcp => Server::bind(&config, handshake).and_then(|server| {
+            listening(server.local_addr()?);
+
+            // Listeners can still connect by address, so the stream goes on without it
+            let advertisement = if config.advertise {
+                server.local_addr()
+                    .and_then(|address| Advertisement::new(&config, &handshake, address.port()))
+                    .map_err(|error| println!("Could not advertise the server: {}", error))
+                    .ok()
+            } else {
+                None
+            };
+
+            let result = server.run(receiver);
+            drop(advertisement);
+            result
+        }),
+        Transport::Rtp => RtpSender::new(&config, handshake).and_then(|sender| sender.run(receiver)),
+        Transport::Multicast => multicast::sender(&config, handshake).and_then(|sender| sender.run(receiver)),
+    };
+
+    // An encoder error ends the stream early, so it takes precedence
+    match encode_thread.map(|thread| thread.join()) {
+        Some(Ok(Err(error))) => Err(error),
+        Some(Err(_)) => Err(Error::Network(io::Error::other("encoder thread panicked"))),
+        _ => result,
+    }
+}
+
+// Receives the stream sent by a `Server`
+pub struct Client {
+    // Shared with the shutdown callback, which only holds on to it weakly so the connection still
+    // closes when the client is dropped
+    stream: Arc<TcpStream>,
+    handshake: Handshake,
+}
+
+impl Client {
+    // Connects to `config.name` if it is set, which is looked up on every connect in case the server
+    // moved, or otherwise to `config.host`
+    pub fn connect(config: &Config) -> Result<Client> {
+        let mut stream = match &config.name {
+            Some(name) => {
+                let server = discovery::resolve(name, discovery::RESOLVE_TIMEOUT)?;
+                TcpStream::connect(server.address().unwrap())?
+            }
+            None => TcpStream::connect(config.address())?,
+        };
+        stream.set_nodelay(true)?;
+        let handshake = Handshake::read_from(&mut stream)?;
+
+        Ok(Client { stream: Arc::new(stream), handshake })
+    }
+
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+}
+
+impl Source for Client {
+    fn format(&self) -> StreamFormat {
+        self.handshake.format
+    }
+
+    fn codec(&self) -> Codec {
+        self.handshake.codec
+    }
+
+    fn read_packet(&mut self) -> Result<Packet> {
+        let packet = Packet::read_from(&mut &*self.stream)?;
+        if packet.is_goodbye() {
+            return Err(Error::Closed);
+        }
+        Ok(packet)
+    }
+
+    // Shutting the socket down wakes a read that is blocked on it
+    fn stop_on(&mut self, shutdown: &Shutdown) -> Result<()> {
+        let stream: Weak<TcpStream> = Arc::downgrade(&self.stream);
+        shutdown.on_request(move || {
+            if let Some(stream) = stream.upgrade() {
+                let _ = stream.shutdown(net::Shutdown::Both);
+            }
+        });
+        Ok(())
+    }
+}
+
+// The playback side of any transport, which hands out decoded samples
+pub struct Stream {
+    source: Box<dyn Source>,
+    decoder: Box<dyn Decoder>,
+    // Samples that were received but not yet handed out by `read_exact`
+    pending: Vec<u8>,
+}
+
+impl Stream {
+    pub fn connect(config: &Config) -> Result<Stream> {
+        let source: Box<dyn Source> = match config.transport {
+            Transport::Tcp => Box::new(Client::connect(config)?),
+            Transport::Rtp => Box::new(RtpReceiver::bind(config)?),
+            Transport::Multicast => Box::new(multicast::receiver(config)?),
+        };
+        let decoder = codec::create_decoder(source.codec(), source.format())?;
+
+        Ok(Stream { source, decoder, pending: vec![] })
+    }
+
+    pub fn format(&self) -> StreamFormat {
+        self.source.format()
+    }
+
+    // Makes reading fail instead of waiting for the network once `shutdown` is requested
+    pub fn stop_on(&mut self, shutdown: &Shutdown) -> Result<()> {
+        self.source.stop_on(shutdown)
+    }
+
+    // Returns the next packet with its payload decoded to samples in the stream format
+    pub fn read_packet(&mut self) -> Result<Packet> {
+        loop {
+            let mut packet = self.source.read_packet()?;
+            packet.payload = self.decoder.decode(&packet.payload)?;
+
+            // A loss the decoder had nothing to conceal with
+            if !packet.payload.is_empty() {
+                return Ok(packet);
+            }
+        }
+    }
+
+    // Fills `buffer` with samples regardless of how they were split into packets
+    pub fn read_exact(&mut self, buffer: &mut [u8]) -> Result<()> {
+        while self.pending.len() < buffer.len() {
+            let packet = self.read_packet()?;
+            self.pending.extend_from_slice(&packet.payload);
+        }
+
+        buffer.copy_from_slice(&self.pending[..buffer.len()]);
+        self.pending.drain(..buffer.len());
+        Ok(())
+    }
+}
+
+// Reads `stream` into `buffer`. When the stream is lost the server is reconnected to, and the buffer
+// plays silence in the meantime. The buffer is closed once the stream can't be recovered, or when
+// the server or `shutdown` ends it.
+pub fn spawn_receiver(mut stream: Stream, buffer: Arc<JitterBuffer>, config: &Config, shutdown: &Shutdown) -> JoinHandle<()> {
+    let config = config.clone();
+    let shutdown = shutdown.clone();
+    let mut backoff = Backoff::new(config.reconnect);
+
+    std::thread::spawn(move || loop {
+        let error = match stream.read_packet() {
+            Ok(packet) => {
+                buffer.push(&packet);
+                continue;
+            }
+            Err(error) => error,
+        };
+
+        if shutdown.is_requested() || !reconnect::is_recoverable(&error) {
+            buffer.close(if shutdown.is_requested() { None } else { Some(error) });
+            return;
+        }
+
+        println!("Lost the stream: {}. Reconnecting", error);
+        stream = match reconnect::connect(&config, &shutdown, &mut backoff) {
+            Ok(stream) => stream,
+            Err(error) => {
+                buffer.close(if shutdown.is_requested() { None } else { Some(error) });
+                return;
+            }
+        };
+
+        // The sink was set up for the first stream's format and can't change now
+        if stream.format() != buffer.format() {
+            buffer.close(Some(Error::Format(format!(
+                "the stream came back as {:?} instead of {:?}",
+                stream.format(),
+                buffer.format(),
+            ))));
+            return;
+        }
+        println!("Reconnected to {}", reconnect::server_name(&config));
+        buffer.restart();
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_config() -> Config {
+        Config {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn server_says_goodbye_on_shutdown() {
+        let mut config = local_config();
+        let server = Server::bind(&config, Handshake::new(config.stream_format(), Codec::Pcm)).unwrap();
+        config.port = server.local_addr().unwrap().port();
+
+        let shutdown = Shutdown::new();
+        let handle = server.shutdown_handle();
+        shutdown.on_request(move || handle.shutdown());
+
+        // The sender stays alive, so only the shutdown can stop the server
+        let (sender, receiver) = packet_channel();
+        let server_thread = std::thread::spawn(move || server.run(receiver));
+
+        // The handshake is only sent once the server has accepted the client
+        let mut client = Client::connect(&config).unwrap();
+        assert_eq!(client.format(), config.stream_format());
+        sender.send(Packet::new(0, vec![1; 64])).unwrap();
+        assert_eq!(client.read_packet().unwrap().payload, vec![1; 64]);
+
+        shutdown.request();
+        assert!(server_thread.join().unwrap().is_ok());
+        assert!(matches!(client.read_packet(), Err(Error::Closed)));
+        drop(sender);
+    }
+
+    #[test]
+    fn server_stops_when_capture_ends() {
+        let mut config = local_config();
+        let server = Server::bind(&config, Handshake::new(config.stream_format(), Codec::Pcm)).unwrap();
+        config.port = server.local_addr().unwrap().port();
+
+        let (sender, receiver) = packet_channel();
+        let server_thread = std::thread::spawn(move || server.run(receiver));
+        let mut client = Client::connect(&config).unwrap();
+
+        drop(sender);
+        assert!(server_thread.join().unwrap().is_ok());
+        assert!(matches!(client.read_packet(), Err(Error::Closed)));
+    }
 }
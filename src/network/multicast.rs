@@ -0,0 +1,55 @@
+// RTP sent once to a multicast group, so the sender's bandwidth doesn't depend on how many
+// receivers there are. Receivers join the group and never talk to the sender.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::network::{Handshake, RtpReceiver, RtpSender};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+
+fn check_group(group: Ipv4Addr) -> Result<()> {
+    if !group.is_multicast() {
+        return Err(Error::Network(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is not a multicast address", group),
+        )));
+    }
+    Ok(())
+}
+
+pub fn sender(config: &Config, handshake: Handshake) -> Result<RtpSender> {
+    check_group(config.group)?;
+
+    let socket = UdpSocket::bind((config.host.as_str(), 0))?;
+    socket.set_multicast_ttl_v4(config.ttl)?;
+    // Lets a receiver on the sending machine hear the stream too
+    socket.set_multicast_loop_v4(true)?;
+
+    let destination = SocketAddr::V4(SocketAddrV4::new(config.group, config.port));
+    RtpSender::from_socket(socket, vec![destination], config, handshake)
+}
+
+// Joins the group on the interface with the address in `config.host`, or on the default one when
+// that is unspecified
+pub fn receiver(config: &Config) -> Result<RtpReceiver> {
+    check_group(config.group)?;
+
+    let interface: Ipv4Addr = config.host.parse().map_err(|_| {
+        Error::Network(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is not an IPv4 interface address", config.host),
+        ))
+    })?;
+
+    // Several receivers on one machine need to share the port
+    let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SockAddr::from(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, config.port)))?;
+
+    let socket = socket.into_udp_socket();
+    socket.join_multicast_v4(&config.group, &interface)?;
+
+    RtpReceiver::from_socket(socket, config.stream_format(), config.codec)
+}
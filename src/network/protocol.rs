@@ -0,0 +1,259 @@
+// Wire format of a TCP stream. All integers are big-endian.
+//
+// A server starts every connection with a handshake:
+//
+//   magic "ASHR" (4) | version (1) | codec (1) | sample format (1) | reserved (1) |
+//   channels (2) | rate (4)
+//
+// followed by any number of packets:
+//
+//   payload length (4) | sequence number (4) | capture timestamp in microseconds (8) | payload
+//
+// A packet with an empty payload says goodbye: the server is shutting down and sends nothing more.
+
+use crate::error::{Error, Result};
+use crate::media::codec::Codec;
+use crate::media::format::{SampleFormat, StreamFormat};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Read, Write};
+
+pub const MAGIC: [u8; 4] = *b"ASHR";
+pub const VERSION: u8 = 1;
+
+// Packets larger than this are treated as a corrupt stream rather than allocated
+pub const MAX_PAYLOAD_SIZE: usize = 1 << 20;
+
+fn codec_to_wire(codec: Codec) -> u8 {
+    match codec {
+        Codec::Pcm => 0,
+        Codec::Opus => 1,
+        Codec::Flac => 2,
+    }
+}
+
+fn codec_from_wire(value: u8) -> Result<Codec> {
+    match value {
+        0 => Ok(Codec::Pcm),
+        1 => Ok(Codec::Opus),
+        2 => Ok(Codec::Flac),
+        _ => Err(Error::Format(format!("unknown codec {}", value))),
+    }
+}
+
+fn sample_format_to_wire(sample_format: SampleFormat) -> u8 {
+    match sample_format {
+        SampleFormat::S16LE => 0,
+        SampleFormat::F32LE => 1,
+        SampleFormat::S16BE => 2,
+        SampleFormat::S24LE => 3,
+        SampleFormat::S24BE => 4,
+        SampleFormat::S24_32LE => 5,
+        SampleFormat::S24_32BE => 6,
+        SampleFormat::S32LE => 7,
+        SampleFormat::S32BE => 8,
+        SampleFormat::F32BE => 9,
+    }
+}
+
+fn sample_format_from_wire(value: u8) -> Result<SampleFormat> {
+    match value {
+        0 => Ok(SampleFormat::S16LE),
+        1 => Ok(SampleFormat::F32LE),
+        2 => Ok(SampleFormat::S16BE),
+        3 => Ok(SampleFormat::S24LE),
+        4 => Ok(SampleFormat::S24BE),
+        5 => Ok(SampleFormat::S24_32LE),
+        6 => Ok(SampleFormat::S24_32BE),
+        7 => Ok(SampleFormat::S32LE),
+        8 => Ok(SampleFormat::S32BE),
+        9 => Ok(SampleFormat::F32BE),
+        _ => Err(Error::Format(format!("unknown sample format {}", value))),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Handshake {
+    pub format: StreamFormat,
+    pub codec: Codec,
+}
+
+impl Handshake {
+    pub const SIZE: usize = 14;
+
+    pub fn new(format: StreamFormat, codec: Codec) -> Self {
+        Handshake { format, codec }
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut bytes = Vec::with_capacity(Handshake::SIZE);
+        bytes.extend_from_slice(&MAGIC);
+        bytes.write_u8(VERSION)?;
+        bytes.write_u8(codec_to_wire(self.codec))?;
+        bytes.write_u8(sample_format_to_wire(self.format.sample_format))?;
+        bytes.write_u8(0)?;
+        bytes.write_u16::<BigEndian>(self.format.channels)?;
+        bytes.write_u32::<BigEndian>(self.format.rate)?;
+
+        writer.write_all(&bytes)?;
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Handshake> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(Error::Format("peer is not an audio-share server".to_string()));
+        }
+
+        let version = reader.read_u8()?;
+        if version != VERSION {
+            return Err(Error::Format(format!(
+                "unsupported protocol version {} (expected {})",
+                version, VERSION
+            )));
+        }
+
+        let codec = codec_from_wire(reader.read_u8()?)?;
+        let sample_format = sample_format_from_wire(reader.read_u8()?)?;
+        let _reserved = reader.read_u8()?;
+        let channels = reader.read_u16::<BigEndian>()?;
+        let rate = reader.read_u32::<BigEndian>()?;
+
+        if channels == 0 || rate == 0 {
+            return Err(Error::Format(format!("invalid format: {} channels at {} Hz", channels, rate)));
+        }
+
+        Ok(Handshake {
+            format: StreamFormat { sample_format, rate, channels },
+            codec,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Packet {
+    pub sequence: u32,
+    // Microseconds on the sender's capture clock
+    pub timestamp: u64,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub const HEADER_SIZE: usize = 16;
+
+    // The sequence number is filled in when the packet is sent
+    pub fn new(timestamp: u64, payload: Vec<u8>) -> Self {
+        Packet { sequence: 0, timestamp, payload }
+    }
+
+    pub fn goodbye() -> Self {
+        Packet::new(0, vec![])
+    }
+
+    pub fn is_goodbye(&self) -> bool {
+        self.payload.is_empty()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Packet::HEADER_SIZE + self.payload.len());
+        bytes.write_u32::<BigEndian>(self.payload.len() as u32).unwrap();
+        bytes.write_u32::<BigEndian>(self.sequence).unwrap();
+        bytes.write_u64::<BigEndian>(self.timestamp).unwrap();
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Packet> {
+        let length = reader.read_u32::<BigEndian>()? as usize;
+        let sequence = reader.read_u32::<BigEndian>()?;
+        let timestamp = reader.read_u64::<BigEndian>()?;
+
+        if length > MAX_PAYLOAD_SIZE {
+            return Err(Error::Format(format!("packet of {} bytes is too large", length)));
+        }
+
+        let mut payload = vec![0; length];
+        reader.read_exact(&mut payload)?;
+
+        Ok(Packet { sequence, timestamp, payload })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake_bytes() -> Vec<u8> {
+        let format = StreamFormat { sample_format: SampleFormat::S24_32BE, rate: 96_000, channels: 6 };
+        let mut bytes = vec![];
+        Handshake::new(format, Codec::Flac).write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn handshake_round_trips() {
+        let bytes = handshake_bytes();
+        assert_eq!(bytes.len(), Handshake::SIZE);
+        assert_eq!(bytes[..4], MAGIC);
+
+        let handshake = Handshake::read_from(&mut &bytes[..]).unwrap();
+        assert_eq!(handshake.codec, Codec::Flac);
+        assert_eq!(handshake.format, StreamFormat { sample_format: SampleFormat::S24_32BE, rate: 96_000, channels: 6 });
+    }
+
+    #[test]
+    fn rejects_other_handshakes() {
+        let mut bytes = handshake_bytes();
+        bytes[0] = b'X';
+        assert!(matches!(Handshake::read_from(&mut &bytes[..]), Err(Error::Format(_))));
+
+        let mut bytes = handshake_bytes();
+        bytes[4] = VERSION + 1;
+        assert!(matches!(Handshake::read_from(&mut &bytes[..]), Err(Error::Format(_))));
+
+        // Unknown codec, then unknown sample format
+        for &position in &[5, 6] {
+            let mut bytes = handshake_bytes();
+            bytes[position] = 0xff;
+            assert!(matches!(Handshake::read_from(&mut &bytes[..]), Err(Error::Format(_))));
+        }
+
+        // Cut off before the rate
+        let bytes = handshake_bytes();
+        assert!(matches!(Handshake::read_from(&mut &bytes[..10]), Err(Error::Network(_))));
+    }
+
+    #[test]
+    fn packet_round_trips() {
+        let packet = Packet { sequence: 7, timestamp: 1_234_567_890_123, payload: vec![1, 2, 3, 4, 5] };
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes.len(), Packet::HEADER_SIZE + 5);
+        assert_eq!(bytes[..4], [0, 0, 0, 5]);
+
+        let read = Packet::read_from(&mut &bytes[..]).unwrap();
+        assert_eq!(read.sequence, 7);
+        assert_eq!(read.timestamp, 1_234_567_890_123);
+        assert_eq!(read.payload, packet.payload);
+        assert!(!read.is_goodbye());
+    }
+
+    #[test]
+    fn goodbye_is_an_empty_packet() {
+        let bytes = Packet::goodbye().to_bytes();
+        assert_eq!(bytes.len(), Packet::HEADER_SIZE);
+
+        let read = Packet::read_from(&mut &bytes[..]).unwrap();
+        assert!(read.is_goodbye());
+    }
+
+    #[test]
+    fn rejects_oversized_packets() {
+        let mut bytes = Packet::new(0, vec![0; 4]).to_bytes();
+        bytes[..4].copy_from_slice(&(MAX_PAYLOAD_SIZE as u32 + 1).to_be_bytes());
+        assert!(matches!(Packet::read_from(&mut &bytes[..]), Err(Error::Format(_))));
+
+        // The largest payload allowed only fails for want of the data
+        bytes[..4].copy_from_slice(&(MAX_PAYLOAD_SIZE as u32).to_be_bytes());
+        assert!(matches!(Packet::read_from(&mut &bytes[..]), Err(Error::Network(_))));
+    }
+}
@@ -0,0 +1,196 @@
+// Keeps the playback side connected. When the stream is lost, connecting is retried with
+// exponential backoff, and the jitter buffer plays silence until the stream is back.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::network::Stream;
+use crate::shutdown::Shutdown;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReconnectSettings {
+    // Attempts to make after losing the stream before giving up. None keeps trying forever
+    pub retries: Option<u32>,
+    // Wait before the first attempt, which doubles after every failed attempt up to `max_delay`
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for ReconnectSettings {
+    fn default() -> Self {
+        ReconnectSettings {
+            retries: None,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+pub struct Backoff {
+    settings: ReconnectSettings,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(settings: ReconnectSettings) -> Backoff {
+        Backoff { settings, attempts: 0 }
+    }
+
+    // How long to wait before the next attempt, or None once every retry has been used
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if let Some(retries) = self.settings.retries {
+            if self.attempts >= retries {
+                return None;
+            }
+        }
+
+        let factor = 1 << self.attempts.min(31);
+        let delay = self.settings.initial_delay.checked_mul(factor).unwrap_or(self.settings.max_delay);
+        self.attempts += 1;
+        Some(delay.min(self.settings.max_delay))
+    }
+
+    // Called once connected, so the next outage starts over from the initial delay
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+// Whether reconnecting could help
+pub fn is_recoverable(error: &Error) -> bool {
+    match error {
+        // The server went away, isn't advertised yet, or the network dropped out
+        Error::Network(_) | Error::Closed | Error::Discovery(_) => true,
+        // These would only fail the same way again
+        Error::Device(_) | Error::Backend(_) | Error::Format(_) => false,
+    }
+}
+
+// How the server being connected to is shown in messages
+pub fn server_name(config: &Config) -> String {
+    match &config.name {
+        Some(name) => format!("\"{}\"", name),
+        None => config.address(),
+    }
+}
+
+// Connects to the server, retrying while it can't be reached. Returns the last error once the
+// retries run out or shutdown is requested.
+pub fn connect(config: &Config, shutdown: &Shutdown, backoff: &mut Backoff) -> Result<Stream> {
+    loop {
+        // Shutdown has to be able to interrupt the stream, so playback doesn't wait on the network
+        let connected = Stream::connect(config).and_then(|mut stream| {
+            stream.stop_on(shutdown)?;
+            Ok(stream)
+        });
+        let error = match connected {
+            Ok(stream) => {
+                backoff.reset();
+                return Ok(stream);
+            }
+            Err(error) => error,
+        };
+
+        if !is_recoverable(&error) || shutdown.is_requested() {
+            return Err(error);
+        }
+        let delay = match backoff.next_delay() {
+            Some(delay) => delay,
+            None => return Err(error),
+        };
+
+        println!("Could not connect to {}: {}. Retrying in {:.1} s", server_name(config), error, delay.as_secs_f32());
+        if shutdown.wait_timeout(delay) {
+            return Err(error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::codec::Codec;
+    use crate::network::{packet_channel, Handshake, Server};
+    use std::net::TcpListener;
+
+    fn settings(retries: Option<u32>) -> ReconnectSettings {
+        ReconnectSettings {
+            retries,
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+        }
+    }
+
+    fn local_config(port: u16, retries: Option<u32>) -> Config {
+        Config {
+            host: "127.0.0.1".to_string(),
+            port,
+            reconnect: settings(retries),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_cap() {
+        let mut backoff = Backoff::new(settings(None));
+        let delays: Vec<u64> = (0..6).map(|_| backoff.next_delay().unwrap().as_millis() as u64).collect();
+        assert_eq!(delays, vec![10, 20, 40, 50, 50, 50]);
+
+        // Far past the point where the factor would overflow
+        for _ in 0..100 {
+            assert_eq!(backoff.next_delay(), Some(Duration::from_millis(50)));
+        }
+    }
+
+    #[test]
+    fn retries_run_out() {
+        let mut backoff = Backoff::new(settings(Some(3)));
+        assert!((0..3).all(|_| backoff.next_delay().is_some()));
+        assert_eq!(backoff.next_delay(), None);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Some(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn connecting_resets_the_backoff() {
+        let mut config = local_config(0, Some(3));
+        let server = Server::bind(&config, Handshake::new(config.stream_format(), Codec::Pcm)).unwrap();
+        config.port = server.local_addr().unwrap().port();
+        let handle = server.shutdown_handle();
+        let (sender, receiver) = packet_channel();
+        let server_thread = std::thread::spawn(move || server.run(receiver));
+
+        let mut backoff = Backoff::new(config.reconnect);
+        assert!((0..3).all(|_| backoff.next_delay().is_some()));
+        let stream = connect(&config, &Shutdown::new(), &mut backoff).unwrap();
+        assert_eq!(backoff.next_delay(), Some(Duration::from_millis(10)));
+
+        drop(stream);
+        handle.shutdown();
+        assert!(server_thread.join().unwrap().is_ok());
+        drop(sender);
+    }
+
+    #[test]
+    fn gives_up_once_retries_run_out() {
+        // Nothing listens on a port that was just released
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let config = local_config(port, Some(2));
+
+        let mut backoff = Backoff::new(config.reconnect);
+        assert!(matches!(connect(&config, &Shutdown::new(), &mut backoff), Err(Error::Network(_))));
+        assert_eq!(backoff.next_delay(), None);
+    }
+
+    #[test]
+    fn gives_up_on_shutdown() {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let config = local_config(port, None);
+        let shutdown = Shutdown::new();
+        shutdown.request();
+
+        let mut backoff = Backoff::new(config.reconnect);
+        assert!(matches!(connect(&config, &shutdown, &mut backoff), Err(Error::Network(_))));
+    }
+}
@@ -0,0 +1,549 @@
+// RTP (RFC 3550) transport over UDP. Audio is carried as L16 (RFC 3551) or L24 (RFC 3190), which
+// are S16 and S24 in network byte order, or as Opus (RFC 7587). RTP has no handshake, so both ends
+// have to be configured with the same format and codec.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::media::codec::Codec;
+use crate::media::format::{SampleFormat, StreamFormat};
+use crate::network::channel::Packets;
+use crate::network::{Handshake, Packet, PacketReceiver, Source};
+use crate::shutdown::Shutdown;
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::VecDeque;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::ops::Range;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const VERSION: u8 = 2;
+pub const HEADER_SIZE: usize = 12;
+
+// Keeps datagrams under a typical 1500 byte MTU once IP and UDP headers are added
+pub const MAX_PAYLOAD_SIZE: usize = 1200;
+
+// The static L16 payload types are only defined for 44.1 kHz, so dynamic ones are used instead
+pub const PAYLOAD_TYPE_L16: u8 = 96;
+pub const PAYLOAD_TYPE_OPUS: u8 = 97;
+pub const PAYLOAD_TYPE_L24: u8 = 98;
+
+// RFC 7587 fixes the Opus timestamp clock regardless of the rate that was encoded
+const OPUS_CLOCK_RATE: u64 = 48_000;
+
+// Gaps longer than these are treated as the sender restarting rather than as loss to conceal
+const MAX_CONCEALED_SECONDS: u64 = 1;
+const MAX_CONCEALED_PACKETS: u64 = 50;
+
+// How often a receiver waiting for datagrams checks whether shutdown has been requested, as
+// nothing can wake a blocked UDP socket on every platform
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RtpHeader {
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+}
+
+impl RtpHeader {
+    pub fn write(&self, bytes: &mut [u8]) {
+        bytes[0] = VERSION << 6;
+        bytes[1] = ((self.marker as u8) << 7) | (self.payload_type & 0x7f);
+        BigEndian::write_u16(&mut bytes[2..4], self.sequence);
+        BigEndian::write_u32(&mut bytes[4..8], self.timestamp);
+        BigEndian::write_u32(&mut bytes[8..12], self.ssrc);
+    }
+
+    // Returns the header and where the payload is, which leaves out any padding after it
+    pub fn parse(bytes: &[u8]) -> Result<(RtpHeader, Range<usize>)> {
+        if bytes.len() < HEADER_SIZE || bytes[0] >> 6 != VERSION {
+            return Err(Error::Format("datagram is not an RTP packet".to_string()));
+        }
+
+        let has_padding = bytes[0] & 0x20 != 0;
+        let has_extension = bytes[0] & 0x10 != 0;
+        let csrc_count = (bytes[0] & 0x0f) as usize;
+
+        let mut offset = HEADER_SIZE + csrc_count * 4;
+        if has_extension {
+            if bytes.len() < offset + 4 {
+                return Err(Error::Format("truncated RTP header extension".to_string()));
+            }
+            let extension_words = BigEndian::read_u16(&bytes[offset + 2..offset + 4]) as usize;
+            offset += 4 + extension_words * 4;
+        }
+
+        let mut end = bytes.len();
+        if has_padding {
+            end = end.saturating_sub(bytes[bytes.len() - 1] as usize);
+        }
+        if offset > end {
+            return Err(Error::Format("truncated RTP packet".to_string()));
+        }
+
+        let header = RtpHeader {
+            marker: bytes[1] & 0x80 != 0,
+            payload_type: bytes[1] & 0x7f,
+            sequence: BigEndian::read_u16(&bytes[2..4]),
+            timestamp: BigEndian::read_u32(&bytes[4..8]),
+            ssrc: BigEndian::read_u32(&bytes[8..12]),
+        };
+        Ok((header, offset..end))
+    }
+}
+
+fn payload_type(codec: Codec, format: StreamFormat) -> Result<u8> {
+    // Uncompressed frames are never split across packets, so each one has to fit in a payload
+    if codec == Codec::Pcm && format.bytes_per_frame() > MAX_PAYLOAD_SIZE {
+        return Err(Error::Format(format!(
+            "{} channels of {:?} don't fit in an RTP packet",
+            format.channels, format.sample_format
+        )));
+    }
+
+    match (codec, format.sample_format) {
+        (Codec::Pcm, SampleFormat::S16LE) => Ok(PAYLOAD_TYPE_L16),
+        (Codec::Pcm, SampleFormat::S24LE) => Ok(PAYLOAD_TYPE_L24),
+        (Codec::Opus, _) => Ok(PAYLOAD_TYPE_OPUS),
+        (codec, sample_format) => Err(Error::Format(format!(
+            "{:?} audio in {:?} can't be sent over RTP",
+            codec, sample_format
+        ))),
+    }
+}
+
+fn clock_rate(codec: Codec, format: StreamFormat) -> u64 {
+    match codec {
+        Codec::Opus => OPUS_CLOCK_RATE,
+        _ => format.rate as u64,
+    }
+}
+
+// Not cryptographically random, but enough to tell apart senders and restarts
+fn random_u32() -> u32 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.subsec_nanos() ^ time.as_secs() as u32)
+        .unwrap_or(0);
+    nanos.wrapping_mul(2_654_435_761) ^ std::process::id()
+}
+
+// How samples in `format` are sent on the wire
+fn network_format(format: StreamFormat) -> SampleFormat {
+    match format.sample_format {
+        SampleFormat::S16LE => SampleFormat::S16BE,
+        SampleFormat::S24LE => SampleFormat::S24BE,
+        sample_format => sample_format,
+    }
+}
+
+// Sends every packet received from the capture side to a fixed list of receivers
+pub struct RtpSender {
+    socket: UdpSocket,
+    destinations: Vec<SocketAddr>,
+    format: StreamFormat,
+    codec: Codec,
+    payload_type: u8,
+    // RTP timestamp units covered by one encoded frame, for codecs that can't be split up
+    frame_ticks: u32,
+    ssrc: u32,
+    sequence: u16,
+    timestamp: u32,
+    first_packet: bool,
+}
+
+impl RtpSender {
+    pub fn new(config: &Config, handshake: Handshake) -> Result<RtpSender> {
+        if config.receivers.is_empty() {
+            return Err(Error::Format("RTP needs at least one receiver address".to_string()));
+        }
+
+        let socket = UdpSocket::bind((config.host.as_str(), 0))?;
+        RtpSender::from_socket(socket, config.receivers.clone(), config, handshake)
+    }
+
+    pub fn from_socket(
+        socket: UdpSocket,
+        destinations: Vec<SocketAddr>,
+        config: &Config,
+        handshake: Handshake,
+    ) -> Result<RtpSender> {
+        let codec = handshake.codec;
+        let frame_ticks = clock_rate(codec, handshake.format) * config.opus.frame_duration as u64 / 1_000_000;
+
+        Ok(RtpSender {
+            socket,
+            destinations,
+            format: handshake.format,
+            codec,
+            payload_type: payload_type(codec, handshake.format)?,
+            frame_ticks: frame_ticks as u32,
+            ssrc: random_u32(),
+            sequence: random_u32() as u16,
+            timestamp: random_u32(),
+            first_packet: true,
+        })
+    }
+
+    fn send_datagram(&mut self, payload: &[u8], ticks: u32) {
+        let mut datagram = vec![0; HEADER_SIZE + payload.len()];
+        let header = RtpHeader {
+            marker: self.first_packet,
+            payload_type: self.payload_type,
+            sequence: self.sequence,
+            timestamp: self.timestamp,
+            ssrc: self.ssrc,
+        };
+        header.write(&mut datagram);
+        datagram[HEADER_SIZE..].copy_from_slice(payload);
+
+        for destination in &self.destinations {
+            // A receiver that isn't listening shouldn't stop the others
+            if let Err(e) = self.socket.send_to(&datagram, destination) {
+                println!("Could not send to {}: {}", destination, e);
+            }
+        }
+
+        self.first_packet = false;
+        self.sequence = self.sequence.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(ticks);
+    }
+
+    pub fn send(&mut self, packet: &Packet) -> Result<()> {
+        if self.codec == Codec::Opus {
+            self.send_datagram(&packet.payload, self.frame_ticks);
+            return Ok(());
+        }
+
+        let bytes_per_frame = self.format.bytes_per_frame();
+        let max_payload = MAX_PAYLOAD_SIZE - MAX_PAYLOAD_SIZE % bytes_per_frame;
+
+        let mut payload = vec![0; max_payload];
+        for chunk in packet.payload.chunks(max_payload) {
+            let payload = &mut payload[..chunk.len()];
+            SampleFormat::convert(self.format.sample_format, chunk, network_format(self.format), payload);
+            self.send_datagram(payload, (chunk.len() / bytes_per_frame) as u32);
+        }
+        Ok(())
+    }
+
+    // Runs until every sender for `receiver` has been dropped
+    pub fn run(mut self, receiver: PacketReceiver) -> Result<()> {
+        for packet in Packets::new(receiver)? {
+            self.send(&packet)?;
+        }
+        Ok(())
+    }
+}
+
+// Receives an RTP stream and drops late and duplicate packets. Gaps left by lost L16 packets are
+// filled with silence, while lost Opus packets are passed on as empty packets for the decoder to
+// conceal.
+pub struct RtpReceiver {
+    socket: UdpSocket,
+    format: StreamFormat,
+    codec: Codec,
+    payload_type: u8,
+    clock_rate: u64,
+    datagram: Vec<u8>,
+    ssrc: Option<u32>,
+    // Extended sequence number and RTP timestamp expected for the next packet
+    next_sequence: u64,
+    next_timestamp: u64,
+    // Packets that are ready to be returned, behind markers for the ones lost before them
+    queued: VecDeque<Packet>,
+    shutdown: Option<Shutdown>,
+}
+
+impl RtpReceiver {
+    pub fn bind(config: &Config) -> Result<RtpReceiver> {
+        let socket = UdpSocket::bind(config.address())?;
+        RtpReceiver::from_socket(socket, config.stream_format(), config.codec)
+    }
+
+    pub fn from_socket(socket: UdpSocket, format: StreamFormat, codec: Codec) -> Result<RtpReceiver> {
+        Ok(RtpReceiver {
+            socket,
+            format,
+            codec,
+            payload_type: payload_type(codec, format)?,
+            clock_rate: clock_rate(codec, format),
+            datagram: vec![0; 65_536],
+            ssrc: None,
+            next_sequence: 0,
+            next_timestamp: 0,
+            queued: VecDeque::new(),
+            shutdown: None,
+        })
+    }
+
+    // Extends a 16-bit sequence number to the value closest to the one expected
+    fn extend_sequence(&self, sequence: u16) -> u64 {
+        let delta = sequence.wrapping_sub(self.next_sequence as u16) as i16 as i64;
+        (self.next_sequence as i64 + delta).max(0) as u64
+    }
+
+    fn extend_timestamp(&self, timestamp: u32) -> u64 {
+        let delta = timestamp.wrapping_sub(self.next_timestamp as u32) as i32 as i64;
+        (self.next_timestamp as i64 + delta).max(0) as u64
+    }
+
+    fn to_microseconds(&self, timestamp: u64) -> u64 {
+        timestamp * 1_000_000 / self.clock_rate
+    }
+
+    fn receive(&mut self) -> Result<()> {
+        let bytes_per_frame = self.format.bytes_per_frame();
+
+        loop {
+            let (length, _address) = match self.socket.recv_from(&mut self.datagram) {
+                Ok(received) => received,
+                // Timeouts show up as either kind, depending on the platform
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock || error.kind() == io::ErrorKind::TimedOut => {
+                    if self.shutdown.as_ref().is_some_and(Shutdown::is_requested) {
+                        return Err(Error::Closed);
+                    }
+                    continue;
+                }
+                Err(error) => return Err(error.into()),
+            };
+            let (header, payload) = match RtpHeader::parse(&self.datagram[..length]) {
+                Ok(parsed) => parsed,
+                Err(_) => continue,
+            };
+            if header.payload_type != self.payload_type {
+                continue;
+            }
+
+            if self.ssrc != Some(header.ssrc) {
+                // A new sender, or the old one restarted
+                self.ssrc = Some(header.ssrc);
+                self.next_sequence = header.sequence as u64;
+                self.next_timestamp = header.timestamp as u64;
+            }
+
+            let sequence = self.extend_sequence(header.sequence);
+            if sequence < self.next_sequence {
+                // Late or duplicated, and its place has already been filled
+                continue;
+            }
+            let timestamp = self.extend_timestamp(header.timestamp);
+            let mut payload = self.datagram[payload].to_vec();
+
+            if self.codec == Codec::Opus {
+                if sequence - self.next_sequence <= MAX_CONCEALED_PACKETS {
+                    for lost in self.next_sequence..sequence {
+                        self.queued.push_back(Packet {
+                            sequence: lost as u32,
+                            timestamp: self.to_microseconds(timestamp),
+                            payload: vec![],
+                        });
+                    }
+                }
+
+                self.next_sequence = sequence + 1;
+                self.next_timestamp = timestamp;
+                self.queued.push_back(Packet {
+                    sequence: sequence as u32,
+                    timestamp: self.to_microseconds(timestamp),
+                    payload,
+                });
+                return Ok(());
+            }
+
+            payload.truncate(payload.len() - payload.len() % bytes_per_frame);
+            let frames = (payload.len() / bytes_per_frame) as u64;
+
+            let missing_frames = timestamp.saturating_sub(self.next_timestamp);
+            let mut start = timestamp;
+            let mut samples = Vec::with_capacity(payload.len());
+            if missing_frames > 0 && missing_frames <= MAX_CONCEALED_SECONDS * self.clock_rate {
+                start = self.next_timestamp;
+                samples.resize(missing_frames as usize * bytes_per_frame, 0);
+            }
+            let offset = samples.len();
+            samples.resize(offset + payload.len(), 0);
+            SampleFormat::convert(network_format(self.format), &payload, self.format.sample_format, &mut samples[offset..]);
+
+            self.next_sequence = sequence + 1;
+            self.next_timestamp = timestamp + frames;
+            self.queued.push_back(Packet {
+                sequence: sequence as u32,
+                timestamp: self.to_microseconds(start),
+                payload: samples,
+            });
+            return Ok(());
+        }
+    }
+}
+
+impl Source for RtpReceiver {
+    fn format(&self) -> StreamFormat {
+        self.format
+    }
+
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    fn read_packet(&mut self) -> Result<Packet> {
+        loop {
+            if let Some(packet) = self.queued.pop_front() {
+                return Ok(packet);
+            }
+            self.receive()?;
+        }
+    }
+
+    fn stop_on(&mut self, shutdown: &Shutdown) -> Result<()> {
+        self.socket.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL))?;
+        self.shutdown = Some(shutdown.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(sample_format: SampleFormat, channels: u16) -> StreamFormat {
+        StreamFormat { sample_format, rate: 48_000, channels }
+    }
+
+    #[test]
+    fn rejects_frames_larger_than_a_packet() {
+        // 600 channels of S16 make exactly one full payload, 601 overflow it
+        let full = payload_type(Codec::Pcm, format(SampleFormat::S16LE, 600));
+        assert_eq!(full.unwrap(), PAYLOAD_TYPE_L16);
+        assert!(matches!(
+            payload_type(Codec::Pcm, format(SampleFormat::S16LE, 601)),
+            Err(Error::Format(_))
+        ));
+        assert!(matches!(
+            payload_type(Codec::Pcm, format(SampleFormat::S24LE, 401)),
+            Err(Error::Format(_))
+        ));
+
+        // Opus frames are encoded as a whole, whatever the layout
+        let opus = payload_type(Codec::Opus, format(SampleFormat::S16LE, 601));
+        assert_eq!(opus.unwrap(), PAYLOAD_TYPE_OPUS);
+    }
+
+    // An RTP packet with `padding` bytes after the payload, the last of which counts them
+    fn datagram(payload_type: u8, sequence: u16, timestamp: u32, payload: &[u8], padding: u8) -> Vec<u8> {
+        let header = RtpHeader { marker: false, payload_type, sequence, timestamp, ssrc: 7 };
+        let mut datagram = vec![0; HEADER_SIZE];
+        header.write(&mut datagram);
+        datagram.extend_from_slice(payload);
+        if padding > 0 {
+            datagram[0] |= 0x20;
+            datagram.resize(datagram.len() + padding as usize - 1, 0);
+            datagram.push(padding);
+        }
+        datagram
+    }
+
+    // A receiver on a loopback port, and a socket that sends to it
+    fn receiver(codec: Codec) -> (RtpReceiver, UdpSocket) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.connect(socket.local_addr().unwrap()).unwrap();
+        let receiver = RtpReceiver::from_socket(socket, format(SampleFormat::S16LE, 1), codec).unwrap();
+        (receiver, sender)
+    }
+
+    // Sends mono L16 samples, which the receiver hands out as S16LE
+    fn send_l16(sender: &UdpSocket, sequence: u16, timestamp: u32, samples: &[i16], padding: u8) {
+        let mut payload = vec![0; samples.len() * 2];
+        BigEndian::write_i16_into(samples, &mut payload);
+        sender.send(&datagram(PAYLOAD_TYPE_L16, sequence, timestamp, &payload, padding)).unwrap();
+    }
+
+    fn samples(packet: &Packet) -> Vec<i16> {
+        packet.payload.chunks(2).map(|sample| i16::from_le_bytes([sample[0], sample[1]])).collect()
+    }
+
+    #[test]
+    fn parses_headers_with_extensions_and_padding() {
+        let mut bytes = datagram(PAYLOAD_TYPE_L16, 1, 2, &[1, 2, 3, 4], 3);
+        let (header, payload) = RtpHeader::parse(&bytes).unwrap();
+        assert_eq!((header.sequence, header.timestamp, header.ssrc), (1, 2, 7));
+        assert_eq!(&bytes[payload], &[1, 2, 3, 4]);
+
+        // One contributing source and a one word extension ahead of the payload
+        bytes[0] |= 0x11;
+        bytes.splice(HEADER_SIZE..HEADER_SIZE, vec![0, 0, 0, 9, 0xbe, 0xde, 0, 1, 0, 0, 0, 0]);
+        let (_, payload) = RtpHeader::parse(&bytes).unwrap();
+        assert_eq!(&bytes[payload], &[1, 2, 3, 4]);
+
+        // Padding that claims more than the packet holds
+        let mut bytes = datagram(PAYLOAD_TYPE_L16, 1, 2, &[1, 2], 1);
+        *bytes.last_mut().unwrap() = 20;
+        assert!(RtpHeader::parse(&bytes).is_err());
+        assert!(RtpHeader::parse(&bytes[..HEADER_SIZE - 1]).is_err());
+    }
+
+    #[test]
+    fn leaves_out_padding() {
+        let (mut receiver, sender) = receiver(Codec::Pcm);
+        send_l16(&sender, 0, 0, &[1, -2], 3);
+        send_l16(&sender, 1, 2, &[3, -4], 4);
+        assert_eq!(samples(&receiver.read_packet().unwrap()), vec![1, -2]);
+        assert_eq!(samples(&receiver.read_packet().unwrap()), vec![3, -4]);
+    }
+
+    #[test]
+    fn fills_gaps_and_drops_late_packets() {
+        let (mut receiver, sender) = receiver(Codec::Pcm);
+        send_l16(&sender, 10, 1_000, &[1, 2], 0);
+        send_l16(&sender, 12, 1_004, &[5, 6], 0);
+        // Arrives after the packet that followed it, so its place is already filled with silence
+        send_l16(&sender, 11, 1_002, &[3, 4], 0);
+        send_l16(&sender, 13, 1_006, &[7, 8], 0);
+
+        let first = receiver.read_packet().unwrap();
+        assert_eq!((first.sequence, samples(&first)), (10, vec![1, 2]));
+        let second = receiver.read_packet().unwrap();
+        assert_eq!((second.sequence, samples(&second)), (12, vec![0, 0, 5, 6]));
+        assert_eq!(second.timestamp, 1_002 * 1_000_000 / 48_000);
+        assert_eq!(receiver.read_packet().unwrap().sequence, 13);
+    }
+
+    #[test]
+    fn drops_duplicates() {
+        let (mut receiver, sender) = receiver(Codec::Pcm);
+        send_l16(&sender, 0, 0, &[1], 0);
+        send_l16(&sender, 0, 0, &[1], 0);
+        send_l16(&sender, 1, 1, &[2], 0);
+        assert_eq!(receiver.read_packet().unwrap().sequence, 0);
+        assert_eq!(receiver.read_packet().unwrap().sequence, 1);
+    }
+
+    #[test]
+    fn marks_lost_opus_packets_for_concealment() {
+        let (mut receiver, sender) = receiver(Codec::Opus);
+        let send = |sequence: u16, payload: &[u8]| {
+            let timestamp = sequence as u32 * 960;
+            sender.send(&datagram(PAYLOAD_TYPE_OPUS, sequence, timestamp, payload, 0)).unwrap();
+        };
+        send(5, &[1, 2, 3]);
+        send(8, &[4]);
+        // Too long a gap to conceal, so playback just carries on from here
+        let resumed = 9 + MAX_CONCEALED_PACKETS as u16 + 1;
+        send(resumed, &[5]);
+
+        let packets: Vec<(u32, Vec<u8>)> = (0..5)
+            .map(|_| receiver.read_packet().unwrap())
+            .map(|packet| (packet.sequence, packet.payload))
+            .collect();
+        assert_eq!(packets, vec![
+            (5, vec![1, 2, 3]),
+            (6, vec![]),
+            (7, vec![]),
+            (8, vec![4]),
+            (resumed as u32, vec![5]),
+        ]);
+    }
+}
@@ -0,0 +1,312 @@
+// Writes to one TCP client from the server's event loop. Packets wait in a bounded queue until the
+// socket can take them, so a slow client only holds up itself, and the overflow policy decides what
+// happens when its queue fills up.
+
+use crate::config::Overflow;
+use mio::net::TcpStream;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug)]
+pub struct ClientStats {
+    pub address: SocketAddr,
+    pub sent_packets: u64,
+    // Packets the client never got because its queue was full
+    pub dropped_packets: u64,
+}
+
+struct Counters {
+    address: SocketAddr,
+    sent_packets: AtomicU64,
+    dropped_packets: AtomicU64,
+}
+
+impl Counters {
+    fn snapshot(&self) -> ClientStats {
+        ClientStats {
+            address: self.address,
+            sent_packets: self.sent_packets.load(Ordering::Relaxed),
+            dropped_packets: self.dropped_packets.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Counters for every client connected to a `Server`, which can be read while it runs
+#[derive(Clone, Default)]
+pub struct ServerStats {
+    clients: Arc<Mutex<Vec<Arc<Counters>>>>,
+}
+
+impl ServerStats {
+    pub fn clients(&self) -> Vec<ClientStats> {
+        self.clients.lock().unwrap().iter().map(|counters| counters.snapshot()).collect()
+    }
+
+    fn add(&self, counters: Arc<Counters>) {
+        self.clients.lock().unwrap().push(counters);
+    }
+
+    fn remove(&self, counters: &Arc<Counters>) {
+        self.clients.lock().unwrap().retain(|client| !Arc::ptr_eq(client, counters));
+    }
+}
+
+pub struct ClientWriter {
+    stream: TcpStream,
+    queue: VecDeque<Arc<Vec<u8>>>,
+    // Bytes of the packet at the front of the queue that have already been written
+    written: usize,
+    // Whether the front of the queue is still the greeting rather than a packet
+    greeting: bool,
+    // When the client last stopped taking data, if it hasn't caught up since
+    stalled_since: Option<Instant>,
+    capacity: usize,
+    overflow: Overflow,
+    counters: Arc<Counters>,
+    // Dropped packets the server has already reported
+    reported_drops: u64,
+    stats: ServerStats,
+}
+
+impl ClientWriter {
+    // `greeting` is written before any packet and is never dropped
+    pub fn new(
+        stream: TcpStream,
+        address: SocketAddr,
+        greeting: Vec<u8>,
+        capacity: usize,
+        overflow: Overflow,
+        stats: &ServerStats,
+    ) -> ClientWriter {
+        let counters = Arc::new(Counters {
+            address,
+            sent_packets: AtomicU64::new(0),
+            dropped_packets: AtomicU64::new(0),
+        });
+        stats.add(counters.clone());
+
+        let mut queue = VecDeque::with_capacity(capacity + 1);
+        queue.push_back(Arc::new(greeting));
+
+        ClientWriter {
+            stream,
+            queue,
+            written: 0,
+            greeting: true,
+            stalled_since: None,
+            capacity: capacity.max(1),
+            overflow,
+            counters,
+            reported_drops: 0,
+            stats: stats.clone(),
+        }
+    }
+
+    pub fn stream(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    pub fn stats(&self) -> ClientStats {
+        self.counters.snapshot()
+    }
+
+    // Packets dropped since the last call
+    pub fn take_unreported_drops(&mut self) -> u64 {
+        let dropped = self.counters.dropped_packets.load(Ordering::Relaxed);
+        let unreported = dropped - self.reported_drops;
+        self.reported_drops = dropped;
+        unreported
+    }
+
+    // Queues a packet for the client. Returns false when the overflow policy says to disconnect it
+    pub fn push(&mut self, packet: Arc<Vec<u8>>) -> bool {
+        if self.queue.len() - self.greeting as usize >= self.capacity {
+            match self.overflow {
+                // A packet that is partly written has to be finished, or the stream loses its framing
+                Overflow::DropOldest => {
+                    let oldest = if self.written > 0 || self.greeting { 1 } else { 0 };
+                    if self.queue.remove(oldest).is_some() {
+                        self.counters.dropped_packets.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                Overflow::DropNewest => {
+                    self.counters.dropped_packets.fetch_add(1, Ordering::Relaxed);
+                    return true;
+                }
+                Overflow::Disconnect => return false,
+            }
+        }
+
+        self.queue.push_back(packet);
+        true
+    }
+
+    // Queues the last packet the client will get, regardless of the overflow policy
+    pub fn push_last(&mut self, packet: Arc<Vec<u8>>) {
+        self.queue.push_back(packet);
+    }
+
+    pub fn is_flushed(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    // Writes as much of the queue as the socket takes without blocking
+    pub fn flush(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.queue.front() {
+            match self.stream.write(&packet[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(length) => {
+                    self.stalled_since = None;
+                    self.written += length;
+                    if self.written == packet.len() {
+                        self.queue.pop_front();
+                        self.written = 0;
+                        if self.greeting {
+                            self.greeting = false;
+                        } else {
+                            self.counters.sent_packets.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+                Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => {
+                    if self.stalled_since.is_none() {
+                        self.stalled_since = Some(Instant::now());
+                    }
+                    return Ok(());
+                }
+                Err(ref error) if error.kind() == io::ErrorKind::Interrupted => (),
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+
+    // Clients never send anything, so this only finds out whether the connection was closed
+    pub fn is_closed(&mut self) -> bool {
+        let mut buffer = [0; 256];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return true,
+                Ok(_) => (),
+                Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => return false,
+                Err(ref error) if error.kind() == io::ErrorKind::Interrupted => (),
+                Err(_) => return true,
+            }
+        }
+    }
+
+    // When the client will have been stalled for `timeout`, if it is stalled at all
+    pub fn deadline(&self, timeout: Duration) -> Option<Instant> {
+        self.stalled_since.map(|since| since + timeout)
+    }
+}
+
+impl Drop for ClientWriter {
+    fn drop(&mut self) {
+        self.stats.remove(&self.counters);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream as StdTcpStream};
+
+    const GREETING: &[u8] = b"hello";
+
+    // A writer for one end of a loopback connection, and the other end to read what it writes
+    fn writer(capacity: usize, overflow: Overflow, stats: &ServerStats) -> (ClientWriter, StdTcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = StdTcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, address) = listener.accept().unwrap();
+        let stream = TcpStream::from_stream(stream).unwrap();
+        (ClientWriter::new(stream, address, GREETING.to_vec(), capacity, overflow, stats), peer)
+    }
+
+    fn packet(byte: u8) -> Arc<Vec<u8>> {
+        Arc::new(vec![byte; 4])
+    }
+
+    // Flushes the writer and reads the greeting and then `count` packets from the other end,
+    // returning the byte each packet was made of
+    fn received(writer: &mut ClientWriter, peer: &mut StdTcpStream, count: usize) -> Vec<u8> {
+        writer.flush().unwrap();
+        assert!(writer.is_flushed());
+        let mut bytes = vec![0; GREETING.len() + count * 4];
+        peer.read_exact(&mut bytes).unwrap();
+        assert_eq!(&bytes[..GREETING.len()], GREETING);
+        bytes[GREETING.len()..].chunks(4).map(|packet| packet[0]).collect()
+    }
+
+    #[test]
+    fn drops_the_oldest() {
+        let stats = ServerStats::default();
+        let (mut writer, mut peer) = writer(3, Overflow::DropOldest, &stats);
+        assert!((1..=5).all(|byte| writer.push(packet(byte))));
+
+        // The greeting is never dropped, only the packets after it
+        assert_eq!(received(&mut writer, &mut peer, 3), vec![3, 4, 5]);
+        let client = writer.stats();
+        assert_eq!((client.sent_packets, client.dropped_packets), (3, 2));
+    }
+
+    #[test]
+    fn drops_the_newest() {
+        let stats = ServerStats::default();
+        let (mut writer, mut peer) = writer(3, Overflow::DropNewest, &stats);
+        assert!((1..=5).all(|byte| writer.push(packet(byte))));
+
+        assert_eq!(received(&mut writer, &mut peer, 3), vec![1, 2, 3]);
+        let client = writer.stats();
+        assert_eq!((client.sent_packets, client.dropped_packets), (3, 2));
+    }
+
+    #[test]
+    fn disconnects_when_full() {
+        let stats = ServerStats::default();
+        let (mut writer, mut peer) = writer(3, Overflow::Disconnect, &stats);
+        assert!((1..=3).all(|byte| writer.push(packet(byte))));
+        assert!(!writer.push(packet(4)));
+        assert_eq!(writer.stats().dropped_packets, 0);
+
+        // The goodbye goes out however full the queue is
+        writer.push_last(packet(0));
+        assert_eq!(received(&mut writer, &mut peer, 4), vec![1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn counts_every_client() {
+        let stats = ServerStats::default();
+        let (mut first, _first_peer) = writer(1, Overflow::DropNewest, &stats);
+        let (mut second, mut second_peer) = writer(1, Overflow::DropOldest, &stats);
+        assert_eq!(stats.clients().len(), 2);
+
+        assert!(first.push(packet(1)) && first.push(packet(2)) && first.push(packet(3)));
+        assert!(second.push(packet(1)));
+        assert_eq!(received(&mut second, &mut second_peer, 1), vec![1]);
+
+        let clients = stats.clients();
+        let count = |writer: &ClientWriter| {
+            let client = clients.iter().find(|client| client.address == writer.stats().address).unwrap();
+            (client.sent_packets, client.dropped_packets)
+        };
+        assert_eq!(count(&first), (0, 2));
+        assert_eq!(count(&second), (1, 0));
+
+        // Drops are only reported once
+        assert_eq!(first.take_unreported_drops(), 2);
+        assert_eq!(first.take_unreported_drops(), 0);
+        first.push(packet(4));
+        assert_eq!(first.take_unreported_drops(), 1);
+
+        // Clients leave the stats when they disconnect
+        drop(first);
+        assert_eq!(stats.clients().len(), 1);
+        drop(second);
+        assert!(stats.clients().is_empty());
+    }
+}
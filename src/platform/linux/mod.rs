@@ -1,14 +1,36 @@
+use crate::config::{Config, Sink};
+use crate::error::{Error, Result};
+use crate::media::channels::{ChannelLayout, ChannelMixer};
+use crate::media::device::{self, Device, DeviceKind};
+use crate::media::dither::Dither;
 use crate::media::InterfaceTrait;
-use crate::network::accept_clients;
+use crate::media::format::{SampleFormat, StreamFormat};
+use crate::media::jitter::JitterBuffer;
+use crate::media::resample::Resampler;
+use crate::network::{packet_channel, serve, spawn_receiver, Packet, PacketSender};
+use crate::network::reconnect::{self, Backoff};
+use crate::shutdown::Shutdown;
 use byte_slice_cast::*;
 use gstreamer::prelude::*;
-use gstreamer::{Caps, FlowSuccess, Pipeline, State};
+use gstreamer::{Caps, ClockTime, DeviceMonitor, Element, FlowError, FlowSuccess, Pipeline, State};
 use gstreamer_app::{AppSink, AppSrc};
-use gstreamer_audio::AUDIO_FORMAT_S16;
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
-use std::sync::mpsc::{Receiver, sync_channel};
-use std::net::{TcpListener, TcpStream};
+use pipewire::{AppStream, AppTarget};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+mod pipewire;
+
+// Microseconds of audio handed to appsrc at a time
+const PLAYBACK_CHUNK_DURATION: u64 = 10_000;
+
+// How often the PipeWire graph is checked for the captured application restarting its stream
+const APP_WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+// GStreamer has to be installed with at least the base plugins
+pub fn is_available() -> bool {
+    gstreamer::init().is_ok() && gstreamer::ElementFactory::find("audioconvert").is_some()
+}
 
 pub struct Interface;
 
@@ -18,119 +40,474 @@ impl Interface {
     }
 }
 
+impl Interface {
+    // The sink for the configured API and playback device. Without either, autoaudiosink picks both
+    fn create_sink(&self, config: &Config) -> Result<Element> {
+        let api = match config.sink {
+            Sink::Auto => None,
+            Sink::Pulse => Some("pulse"),
+            Sink::Alsa => Some("alsa"),
+            Sink::PipeWire => Some("pipewire"),
+        };
+
+        let device = match &config.device {
+            Some(query) => {
+                let devices = self.devices()?;
+                Some(device::select(&devices, DeviceKind::Playback, api, query)?.clone())
+            }
+            None => None,
+        };
+
+        let sink = match device.as_ref().map(|device| device.api).or(api) {
+            Some("pulse") => make_element("pulsesink")?,
+            Some("alsa") => make_element("alsasink")?,
+            Some("pipewire") => make_element("pipewiresink")?,
+            _ => return make_element("autoaudiosink"),
+        };
+
+        if let Some(device) = device {
+            println!("Playing to {}", device.name);
+            // pipewiresink is pointed at a node, where the others take a device
+            let property = if device.api == "pipewire" { "target-object" } else { "device" };
+            sink.set_property(property, &device.id)?;
+        }
+        Ok(sink)
+    }
+}
+
 impl InterfaceTrait for Interface {
     fn init(&self) {
     }
 
-    fn start_playback(&self) -> Result<(), ()> {
-        gstreamer::init().expect("Could not init gstreamer");
+    fn devices(&self) -> Result<Vec<Device>> {
+        gstreamer::init()?;
+
+        let monitor = DeviceMonitor::new();
+        monitor.add_filter(Some("Audio/Source"), None);
+        monitor.add_filter(Some("Audio/Sink"), None);
+        monitor.start()?;
+        let devices = monitor.get_devices();
+        monitor.stop();
+
+        // The monitor lists the most recently added devices first
+        Ok(devices.iter().rev().filter_map(to_device).collect())
+    }
+
+    fn start_playback(&self, config: &Config, shutdown: &Shutdown) -> Result<()> {
+        gstreamer::init()?;
 
         let pipeline = Pipeline::new(None);
-        let src = gstreamer::ElementFactory::make("appsrc", None).expect("Could not make audiotestsrc");
-        let sink = gstreamer::ElementFactory::make("autoaudiosink", None).expect("Could not make appsink");
+        let src = make_element("appsrc")?;
+        let convert = make_element("audioconvert")?;
+        let resample = make_element("audioresample")?;
+        let sink = self.create_sink(config)?;
+
+        pipeline.add_many(&[&src, &convert, &resample, &sink])?;
+        Element::link_many(&[&src, &convert, &resample, &sink])?;
+
+        let stream = reconnect::connect(config, shutdown, &mut Backoff::new(config.reconnect))?;
+        let format = stream.format();
+
+        // The network is read on its own thread, so need_data never waits on it. audioresample takes
+        // the stream to whatever rate the sink runs at
+        let jitter_buffer = Arc::new(JitterBuffer::new(format, format.rate, config.jitter, config.resample));
+        spawn_receiver(stream, jitter_buffer.clone(), config, shutdown);
 
-        pipeline.add_many(&[&src, &sink]).expect("Add elements to pipeline");
-        src.link(&sink).expect("Could not link src to sink");
+        // Closing the buffer plays out what it holds and then ends the stream
+        let shutdown_buffer = jitter_buffer.clone();
+        shutdown.on_request(move || shutdown_buffer.close(None));
 
-        let app_src = src.dynamic_cast::<AppSrc>().expect("Could not make AppSrc");
-        app_src.set_caps(Some(&Caps::new_simple(
-            "audio/x-raw",
-            &[
-                ("format", &AUDIO_FORMAT_S16.to_string()),
-                ("layout", &"interleaved"),
-                ("channels", &(2i32)),
-                ("rate", &(48_000)),
-            ],
-        )));
+        // audioconvert mixes the stream to the sink's channels, unless there is a channel map, which
+        // is applied here and hands audioconvert float audio with as many channels as it has outputs
+        let mixer = match &config.channel_map {
+            Some(map) => Some(ChannelMixer::with_map(
+                Some(map),
+                &ChannelLayout::standard(format.channels),
+                &ChannelLayout::standard(map.outputs() as u16),
+            )?),
+            None => None,
+        };
+        let src_format = match &mixer {
+            Some(mixer) => StreamFormat {
+                sample_format: SampleFormat::F32LE,
+                rate: format.rate,
+                channels: mixer.outputs() as u16,
+            },
+            None => format,
+        };
 
-        let mut stream = TcpStream::connect("192.168.0.37:42795").expect("Could not connect to TCP stream");
+        let app_src = src.dynamic_cast::<AppSrc>()
+            .map_err(|_| Error::gstreamer("appsrc element is not an AppSrc"))?;
+        app_src.set_caps(Some(&create_caps(&src_format)));
+        app_src.set_property("format", &gstreamer::Format::Time)?;
 
+        // Anything queued in appsrc is latency the jitter buffer can't see, so keep it to a couple
+        // of chunks
+        let chunk_frames = format.rate as u64 * PLAYBACK_CHUNK_DURATION / 1_000_000;
+        let chunk_size = chunk_frames as usize * format.bytes_per_frame();
+        app_src.set_max_bytes(chunk_frames * src_format.bytes_per_frame() as u64 * 2);
+
+        let callback_buffer = jitter_buffer.clone();
+        let mut frames_played: u64 = 0;
         app_src.set_callbacks(
             gstreamer_app::AppSrcCallbacks::new()
                 .need_data(move |app_src, _| {
-                    let buffer_size = 1920;
-                    let mut buffer = gstreamer::Buffer::with_size(buffer_size).unwrap();
+                    let mut samples = vec![0; chunk_size];
+                    if !callback_buffer.pop(&mut samples) {
+                        let _ = app_src.end_of_stream();
+                        return;
+                    }
+                    if let Some(mixer) = &mixer {
+                        samples = mix_samples(mixer, format.sample_format, &samples);
+                    }
+
+                    let mut buffer = gstreamer::Buffer::from_mut_slice(samples);
                     {
                         let buffer = buffer.get_mut().unwrap();
-                        let mut data = buffer.map_writable().unwrap();
-
-                        let mut input = vec![0; buffer_size];
-                        stream.read_exact(&mut input).unwrap();
-
-                        let mut i = 0;
-                        for p in data.as_mut_slice() {
-                            *p = input[i];
-                            i += 1;
-                        }
+                        let nanoseconds = |frames: u64| frames * 1_000_000_000 / format.rate as u64;
+                        buffer.set_pts(ClockTime::from_nseconds(nanoseconds(frames_played)));
+                        buffer.set_duration(ClockTime::from_nseconds(nanoseconds(chunk_frames)));
                     }
+                    frames_played += chunk_frames;
 
                     let _ = app_src.push_buffer(buffer);
                 }).build()
         );
 
-        gst_main_loop(pipeline);
+        gst_main_loop(pipeline)?;
+
+        match jitter_buffer.take_error() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
     }
 
-    fn start_recording(&self) -> Result<(), ()> {
-        let (pipeline, receiver) = create_pipeline();
+    fn start_recording(&self, config: &Config, shutdown: &Shutdown) -> Result<()> {
+        gstreamer::init()?;
+
+        // Capture stops when shutdown is requested or when the server stops on its own
+        let stop = Shutdown::new();
+        let stop_on_shutdown = stop.clone();
+        shutdown.on_request(move || stop_on_shutdown.request());
+
+        let (sender, receiver) = packet_channel();
+        let serve_config = config.clone();
+        let serve_stop = stop.clone();
+        let format = config.stream_format();
         let serve_thread = std::thread::spawn(move || {
-            accept_clients(receiver);
+            let result = serve(receiver, serve_config, format);
+            serve_stop.request();
+            result
         });
 
-        gst_main_loop(pipeline);
-        serve_thread.join();
+        // Once capture ends every sender is gone, so the server says goodbye to its clients and returns
+        let result = match &config.app {
+            Some(app) => capture_app(config, &AppTarget::parse(app), sender, &stop),
+            None => create_device_source(config)
+                .and_then(|src| create_pipeline(src, config, sender))
+                .and_then(|pipeline| {
+                    // End of stream drains the pipeline, which drops the appsink and with it the sender
+                    let weak_pipeline = pipeline.downgrade();
+                    stop.on_request(move || {
+                        if let Some(pipeline) = weak_pipeline.upgrade() {
+                            pipeline.send_event(gstreamer::Event::new_eos().build());
+                        }
+                    });
+                    gst_main_loop(pipeline)
+                }),
+        };
+
+        // When the server fails the appsink callback stops the pipeline, so the server's error is
+        // the more useful one to report
+        match serve_thread.join() {
+            Ok(Err(error)) => Err(error),
+            Ok(Ok(())) => result,
+            Err(_) => Err(Error::Network(io::Error::other("network thread panicked"))),
+        }
     }
 }
 
-fn create_pipeline() -> (Pipeline, Receiver<Vec<u8>>) {
-    gstreamer::init().expect("Could not init gstreamer");
+fn to_device(device: &gstreamer::Device) -> Option<Device> {
+    let properties = device.get_properties()?;
+    let property = |name| properties.get::<String>(name);
 
-    let pipeline = Pipeline::new(None);
-    let src = gstreamer::ElementFactory::make("pulsesrc", None).expect("Could not make audiotestsrc");
-    let sink = gstreamer::ElementFactory::make("appsink", None).expect("Could not make appsink");
+    // Each device provider has its own device type
+    let api = match device.get_type().name().as_str() {
+        "GstPulseDevice" => "pulse",
+        "GstAlsaDevice" => "alsa",
+        "GstPipeWireDevice" => "pipewire",
+        _ => return None,
+    };
+
+    let class = device.get_device_class();
+    let kind = if class.contains("Audio/Source") {
+        DeviceKind::Capture
+    } else if class.contains("Audio/Sink") {
+        DeviceKind::Playback
+    } else {
+        return None;
+    };
 
-    src.set_property("device", &"alsa_output.pci-0000_00_1b.0.analog-stereo.monitor").expect("Could not set device");
+    // Whatever the API's elements open the device by: the PulseAudio name, the ALSA device string,
+    // or the PipeWire node name
+    let id = device.get_property("internal-name").ok()
+        .and_then(|name| name.get::<String>())
+        .or_else(|| property("node.name"))
+        .or_else(|| property("device.name"))?;
 
-    pipeline.add_many(&[&src, &sink]).expect("Add elements to pipeline");
-    src.link(&sink).expect("Could not link src to sink");
+    Some(Device {
+        id,
+        name: device.get_display_name().to_string(),
+        kind,
+        api,
+        monitor: property("device.class").as_deref() == Some("monitor"),
+        default: properties.get::<bool>("is-default").unwrap_or(false),
+    })
+}
+
+fn make_element(factory_name: &str) -> Result<Element> {
+    gstreamer::ElementFactory::make(factory_name, None)
+        .ok_or_else(|| Error::gstreamer(format!("could not create {} element", factory_name)))
+}
 
-    let app_sink = sink.dynamic_cast::<AppSink>().expect("Sink element is expected to be an appsink");
-    app_sink.set_caps(Some(&Caps::new_simple(
+fn create_caps(format: &StreamFormat) -> Caps {
+    let sample_format = match format.sample_format {
+        SampleFormat::S16LE => "S16LE",
+        SampleFormat::S16BE => "S16BE",
+        SampleFormat::S24LE => "S24LE",
+        SampleFormat::S24BE => "S24BE",
+        SampleFormat::S24_32LE => "S24_32LE",
+        SampleFormat::S24_32BE => "S24_32BE",
+        SampleFormat::S32LE => "S32LE",
+        SampleFormat::S32BE => "S32BE",
+        SampleFormat::F32LE => "F32LE",
+        SampleFormat::F32BE => "F32BE",
+    };
+
+    Caps::new_simple(
         "audio/x-raw",
         &[
-            ("format", &AUDIO_FORMAT_S16.to_string()),
+            ("format", &sample_format),
             ("layout", &"interleaved"),
-            ("channels", &(2i32)),
-            ("rate", &(48_000)),
+            ("channels", &(format.channels as i32)),
+            ("rate", &(format.rate as i32)),
         ],
-    )));
+    )
+}
+
+// Mixes `samples` in `sample_format` to the mixer's outputs, as float
+fn mix_samples(mixer: &ChannelMixer, sample_format: SampleFormat, samples: &[u8]) -> Vec<u8> {
+    let mut input = vec![0.0; samples.len() / sample_format.bytes_per_sample()];
+    sample_format.read_samples(samples, &mut input);
+    let mut mixed = vec![];
+    mixer.mix(&input, &mut mixed);
+    let mut output = vec![0; mixed.len() * SampleFormat::F32LE.bytes_per_sample()];
+    SampleFormat::F32LE.write_samples(&mixed, &mut output);
+    output
+}
+
+// The monitor of the default output, so that whatever is playing gets recorded. PulseAudio names a
+// sink's monitor after the sink
+fn default_monitor(devices: &[Device]) -> Result<&Device> {
+    let monitors = || devices.iter()
+        .filter(|device| device.kind == DeviceKind::Capture && device.api == "pulse" && device.monitor);
+    let default_sink = devices.iter()
+        .find(|device| device.kind == DeviceKind::Playback && device.api == "pulse" && device.default);
+
+    default_sink
+        .and_then(|sink| monitors().find(|monitor| monitor.id == format!("{}.monitor", sink.id)))
+        .or_else(|| monitors().find(|monitor| monitor.default))
+        .or_else(|| monitors().next())
+        .ok_or_else(|| Error::Device("there is no output to record, see `audio-share devices`".to_string()))
+}
+
+// Capture always goes through PulseAudio, so only its devices can be recorded
+fn create_device_source(config: &Config) -> Result<Element> {
+    let src = make_element("pulsesrc")?;
+
+    let devices = Interface.devices()?;
+    let device = match &config.device {
+        Some(query) => device::select(&devices, DeviceKind::Capture, Some("pulse"), query)?,
+        None => default_monitor(&devices)?,
+    };
+    println!("Recording {}", device.name);
+    src.set_property("device", &device.id)?;
+
+    Ok(src)
+}
+
+fn create_app_source(stream: &AppStream) -> Result<Element> {
+    let src = make_element("pipewiresrc")?;
+
+    // Linking to a node by its serial needs PipeWire 0.3.44 or later
+    src.set_property("target-object", &stream.serial.to_string())?;
+
+    Ok(src)
+}
+
+// Turns the float audio the appsink hands over into what is sent
+struct Reduction {
+    mixer: Option<ChannelMixer>,
+    resampler: Option<Resampler>,
+    dither: Dither,
+    mix: Vec<f32>,
+    mixed: Vec<f32>,
+    resampled: Vec<f32>,
+}
+
+// Records from `src` in the configured format, sending what it captures to `sender`
+fn create_pipeline(src: Element, config: &Config, sender: PacketSender) -> Result<Pipeline> {
+    let pipeline = Pipeline::new(None);
+    let convert = make_element("audioconvert")?;
+    let resample = make_element("audioresample")?;
+    let sink = make_element("appsink")?;
+
+    pipeline.add_many(&[&src, &convert, &resample, &sink])?;
+    Element::link_many(&[&src, &convert, &resample, &sink])?;
+
+    let app_sink = sink.dynamic_cast::<AppSink>()
+        .map_err(|_| Error::gstreamer("appsink element is not an AppSink"))?;
+    // Sound servers mix in float, so integer formats are captured as float and dithered down here,
+    // after resampling to the transport rate. audioconvert mixes to the configured channels, unless
+    // there is a channel map, which is applied here to as many channels as it has inputs
+    let format = config.stream_format();
+    let mut capture_format = StreamFormat { sample_format: SampleFormat::F32LE, ..config.capture_format() };
+    let mixer = match &config.channel_map {
+        Some(map) => {
+            capture_format.channels = map.inputs() as u16;
+            Some(ChannelMixer::with_map(
+                Some(map),
+                &ChannelLayout::standard(capture_format.channels),
+                &ChannelLayout::standard(format.channels),
+            )?)
+        }
+        None => None,
+    };
+    app_sink.set_caps(Some(&create_caps(&capture_format)));
+    let reduction = Mutex::new(Reduction {
+        mixer,
+        resampler: if format.rate != capture_format.rate {
+            Some(Resampler::new(format.channels as usize, capture_format.rate, format.rate, config.resample))
+        } else {
+            None
+        },
+        dither: Dither::new(config.dither, format),
+        mix: vec![],
+        mixed: vec![],
+        resampled: vec![],
+    });
 
-    let (sender, receiver) = sync_channel(1);
     app_sink.set_callbacks(
         gstreamer_app::AppSinkCallbacks::new()
             .new_sample(move |appsink| {
-                let sample = appsink.pull_sample().expect("Could not get sample");
-                let buffer = sample.get_buffer().expect("Could not get buffer");
+                let sample = appsink.pull_sample().ok_or(FlowError::Eos)?;
+                let buffer = sample.get_buffer().ok_or(FlowError::Error)?;
+
+                let map = buffer.map_readable().ok_or(FlowError::Error)?;
+                let samples = map.as_slice_of::<u8>().map_err(|_| FlowError::Error)?;
+                let timestamp = buffer.get_pts().nseconds().unwrap_or(0) / 1_000;
 
-                let map = buffer.map_readable().expect("Could not map buffer to readable memory");
-                let samples = map.as_slice_of::<u8>().expect("Could not get samples");
+                let mut reduction = reduction.lock().unwrap();
+                let Reduction { mixer, resampler, dither, mix, mixed, resampled } = &mut *reduction;
+                mix.resize(samples.len() / 4, 0.0);
+                SampleFormat::F32LE.read_samples(samples, mix);
+                let mix = match mixer {
+                    Some(mixer) => {
+                        mixer.mix(mix, mixed);
+                        mixed
+                    }
+                    None => mix,
+                };
+                let mix = match resampler {
+                    Some(resampler) => {
+                        resampler.resample(mix, resampled);
+                        resampled
+                    }
+                    None => mix,
+                };
+                let mut output = vec![0; mix.len() * format.sample_format.bytes_per_sample()];
+                dither.process(mix, &mut output);
 
-                sender.send(samples.to_vec());
+                // The server has stopped, so stop capturing too
+                sender.send(Packet::new(timestamp, output)).map_err(|_| FlowError::Error)?;
 
                 Ok(FlowSuccess::Ok)
             })
             .build()
     );
 
-    (pipeline, receiver)
+    Ok(pipeline)
+}
+
+// Captures whichever stream `target` is playing, moving to the new one whenever the application
+// restarts its stream, until `stop` is requested. Nothing is sent while the application is silent.
+fn capture_app(config: &Config, target: &AppTarget, sender: PacketSender, stop: &Shutdown) -> Result<()> {
+    let mut capture = None;
+    let result = follow_app(config, target, &sender, stop, &mut capture);
+
+    if let Some((_, pipeline)) = capture {
+        pipeline.set_state(State::Null)?;
+    }
+    result
+}
+
+fn follow_app(
+    config: &Config,
+    target: &AppTarget,
+    sender: &PacketSender,
+    stop: &Shutdown,
+    capture: &mut Option<(AppStream, Pipeline)>,
+) -> Result<()> {
+    loop {
+        let stream = pipewire::find_stream(target)?;
+        let serial = stream.as_ref().map(|stream| stream.serial);
+
+        if serial != capture.as_ref().map(|(stream, _)| stream.serial) {
+            if let Some((_, pipeline)) = capture.take() {
+                pipeline.set_state(State::Null)?;
+            }
+
+            match stream {
+                Some(stream) => {
+                    println!("Capturing {} (stream {})", stream.application, stream.serial);
+                    let pipeline = create_pipeline(create_app_source(&stream)?, config, sender.clone())?;
+                    pipeline.set_state(State::Playing)?;
+                    *capture = Some((stream, pipeline));
+                }
+                None => {
+                    let playing: Vec<String> = pipewire::app_streams()?.into_iter().map(|stream| stream.application).collect();
+                    println!("Waiting for {} to play audio (playing now: {})", target, playing.join(", "));
+                }
+            }
+        }
+
+        // End of stream only means the application closed its stream, which the next look at the
+        // graph picks up, but an error ends the capture
+        if let Some((_, pipeline)) = capture.as_ref() {
+            let bus = pipeline.get_bus().ok_or_else(|| Error::gstreamer("pipeline has no bus"))?;
+            while let Some(msg) = bus.pop() {
+                if let gstreamer::MessageView::Error(err) = msg.view() {
+                    return Err(pipeline_error(err));
+                }
+            }
+        }
+
+        if stop.wait_timeout(APP_WATCH_INTERVAL) {
+            return Ok(());
+        }
+    }
+}
+
+fn pipeline_error(err: &gstreamer::message::Error) -> Error {
+    Error::gstreamer(format!("{} ({})", err.get_error(), err.get_debug().unwrap_or_default()))
 }
 
-fn gst_main_loop(pipeline: Pipeline) {
-    pipeline.set_state(State::Playing).expect("Could not set gstreamer state to Playing");
+fn gst_main_loop(pipeline: Pipeline) -> Result<()> {
+    pipeline.set_state(State::Playing)?;
 
     let bus = pipeline
         .get_bus()
-        .expect("Pipeline without bus. Shouldn't happen!");
+        .ok_or_else(|| Error::gstreamer("pipeline has no bus"))?;
 
     for msg in bus.iter_timed(gstreamer::CLOCK_TIME_NONE) {
         use gstreamer::MessageView;
@@ -138,13 +515,13 @@ fn gst_main_loop(pipeline: Pipeline) {
         match msg.view() {
             MessageView::Eos(..) => break,
             MessageView::Error(err) => {
-                pipeline.set_state(State::Null).expect("Could not set gstreamer state to Null");
-                eprintln!("gstreamer error ({:?})", err);
-                return;
+                pipeline.set_state(State::Null)?;
+                return Err(pipeline_error(err));
             }
             _ => (),
         }
     }
 
-    pipeline.set_state(State::Null).expect("Could not set gstreamer state to Null");
+    pipeline.set_state(State::Null)?;
+    Ok(())
 }
@@ -0,0 +1,103 @@
+// Finds the audio a single application is playing, by reading the PipeWire graph with `pw-dump`.
+// PulseAudio applications show up too when pipewire-pulse is the sound server.
+
+use crate::error::{Error, Result};
+use serde_json::Value;
+use std::fmt;
+use std::process::Command;
+
+// An application's output stream
+#[derive(Clone, Debug, PartialEq)]
+pub struct AppStream {
+    // Unlike node ids, serials are never reused, so a restarted stream always gets a new one
+    pub serial: u64,
+    pub application: String,
+    pub pid: Option<u32>,
+}
+
+// Which application to capture, by PID or by name
+#[derive(Clone, Debug, PartialEq)]
+pub enum AppTarget {
+    Pid(u32),
+    Name(String),
+}
+
+impl AppTarget {
+    pub fn parse(value: &str) -> AppTarget {
+        match value.parse() {
+            Ok(pid) => AppTarget::Pid(pid),
+            Err(_) => AppTarget::Name(value.to_string()),
+        }
+    }
+
+    fn matches(&self, props: &Value) -> bool {
+        match self {
+            AppTarget::Pid(pid) => number(&props["application.process.id"]) == Some(*pid as u64),
+            AppTarget::Name(name) => ["application.name", "application.process.binary", "node.name"]
+                .iter()
+                .filter_map(|key| props[*key].as_str())
+                .any(|value| value.eq_ignore_ascii_case(name)),
+        }
+    }
+}
+
+impl fmt::Display for AppTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppTarget::Pid(pid) => write!(f, "PID {}", pid),
+            AppTarget::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+// Properties are numbers or strings depending on who set them
+fn number(value: &Value) -> Option<u64> {
+    value.as_u64().or_else(|| value.as_str().and_then(|value| value.parse().ok()))
+}
+
+// Properties of every node playing audio into the graph
+fn output_streams() -> Result<Vec<Value>> {
+    let output = Command::new("pw-dump").output().map_err(|error| {
+        Error::Device(format!("could not run pw-dump, capturing an application needs PipeWire: {}", error))
+    })?;
+    if !output.status.success() {
+        return Err(Error::Device(format!("pw-dump failed: {}", String::from_utf8_lossy(&output.stderr).trim())));
+    }
+
+    let objects: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|error| Error::Device(format!("could not parse the output of pw-dump: {}", error)))?;
+    let objects = match objects {
+        Value::Array(objects) => objects,
+        _ => return Err(Error::Device("pw-dump did not return a list of objects".to_string())),
+    };
+
+    Ok(objects.into_iter()
+        .filter(|object| object["type"] == "PipeWire:Interface:Node")
+        .map(|mut object| object["info"]["props"].take())
+        .filter(|props| props["media.class"] == "Stream/Output/Audio")
+        .collect())
+}
+
+fn to_stream(props: &Value) -> Option<AppStream> {
+    Some(AppStream {
+        serial: number(&props["object.serial"])?,
+        application: props["application.name"].as_str()
+            .or_else(|| props["node.name"].as_str())
+            .unwrap_or("unknown")
+            .to_string(),
+        pid: number(&props["application.process.id"]).map(|pid| pid as u32),
+    })
+}
+
+// Every application stream currently playing
+pub fn app_streams() -> Result<Vec<AppStream>> {
+    Ok(output_streams()?.iter().filter_map(to_stream).collect())
+}
+
+// The newest stream of the target application, if it is playing anything
+pub fn find_stream(target: &AppTarget) -> Result<Option<AppStream>> {
+    Ok(output_streams()?.iter()
+        .filter(|props| target.matches(props))
+        .filter_map(to_stream)
+        .max_by_key(|stream| stream.serial))
+}
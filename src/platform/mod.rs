@@ -1,5 +1,9 @@
-#[cfg(target_os = "windows")]
+#[cfg(all(target_os = "windows", feature = "backend-wasapi"))]
 pub mod windows;
 
-#[cfg(target_os = "linux")]
+#[cfg(all(target_os = "linux", feature = "backend-gstreamer"))]
 pub mod linux;
+
+// Test signals in, files or memory out, for running without audio hardware
+#[cfg(feature = "backend-null")]
+pub mod null;
@@ -0,0 +1,148 @@
+// Produces the audio the null backend records: a WAV file or a synthetic test signal
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::media::format::StreamFormat;
+use hound::WavReader;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Level of the synthetic signals, half of full scale so resampling and mixing never clip them
+const LEVEL: f64 = 0.5;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Input {
+    // Played once. The stream takes the file's rate and channel count instead of the configured ones
+    Wav(PathBuf),
+    Sine { frequency: f64 },
+    // Sweeps logarithmically from `start` to `end` Hz over `period`, then starts over
+    Sweep { start: f64, end: f64, period: Duration },
+    // White noise
+    Noise,
+}
+
+pub struct Generator {
+    format: StreamFormat,
+    signal: Signal,
+    // Frames produced so far
+    position: u64,
+}
+
+enum Signal {
+    Wav { reader: WavReader<BufReader<File>>, bits: u16, float: bool },
+    Sine { frequency: f64, phase: f64 },
+    Sweep { start: f64, end: f64, period: f64, phase: f64 },
+    Noise { state: u32 },
+}
+
+impl Generator {
+    pub fn new(input: &Input, config: &Config) -> Result<Generator> {
+        let mut format = config.capture_format();
+
+        let signal = match input {
+            Input::Wav(path) => {
+                let reader = WavReader::open(path)
+                    .map_err(|error| Error::Device(format!("could not open {}: {}", path.display(), error)))?;
+                let spec = reader.spec();
+                format.rate = spec.sample_rate;
+                format.channels = spec.channels;
+                Signal::Wav {
+                    reader,
+                    bits: spec.bits_per_sample,
+                    float: spec.sample_format == hound::SampleFormat::Float,
+                }
+            }
+            Input::Sine { frequency } => Signal::Sine { frequency: *frequency, phase: 0.0 },
+            Input::Sweep { start, end, period } => Signal::Sweep {
+                start: *start,
+                end: *end,
+                period: period.as_secs_f64(),
+                phase: 0.0,
+            },
+            Input::Noise => Signal::Noise { state: 0x9e37_79b9 },
+        };
+
+        Ok(Generator { format, signal, position: 0 })
+    }
+
+    pub fn format(&self) -> StreamFormat {
+        self.format
+    }
+
+    // Bit depth of an integer WAV file. Everything else is float
+    pub fn bits(&self) -> Option<u32> {
+        match &self.signal {
+            Signal::Wav { bits, float: false, .. } => Some(*bits as u32),
+            _ => None,
+        }
+    }
+
+    // Fills as many whole frames of `output` as there is audio for, and returns how many. Only a
+    // WAV file ever runs out.
+    pub fn fill(&mut self, output: &mut [f32]) -> Result<usize> {
+        let channels = self.format.channels as usize;
+        let rate = self.format.rate as f64;
+
+        let mut frames = 0;
+        for frame in output.chunks_exact_mut(channels) {
+            match &mut self.signal {
+                Signal::Wav { reader, bits, float } => {
+                    // The frame goes first so zip never takes a sample it has no room for
+                    let mut read = 0;
+                    if *float {
+                        for (output, sample) in frame.iter_mut().zip(reader.samples::<f32>()) {
+                            *output = wav_error(sample)?;
+                            read += 1;
+                        }
+                    } else {
+                        let scale = (1u64 << (*bits - 1)) as f32;
+                        for (output, sample) in frame.iter_mut().zip(reader.samples::<i32>()) {
+                            *output = wav_error(sample)? as f32 / scale;
+                            read += 1;
+                        }
+                    }
+                    if read < channels {
+                        break;
+                    }
+                }
+                Signal::Sine { frequency, phase } => {
+                    write_frame(frame, (phase.sin() * LEVEL) as f32);
+                    *phase = (*phase + 2.0 * PI * *frequency / rate) % (2.0 * PI);
+                }
+                Signal::Sweep { start, end, period, phase } => {
+                    let progress = (self.position as f64 / rate % *period) / *period;
+                    let frequency = *start * (*end / *start).powf(progress);
+                    write_frame(frame, (phase.sin() * LEVEL) as f32);
+                    *phase = (*phase + 2.0 * PI * frequency / rate) % (2.0 * PI);
+                }
+                Signal::Noise { state } => {
+                    // xorshift32 is plenty random for a test signal
+                    *state ^= *state << 13;
+                    *state ^= *state >> 17;
+                    *state ^= *state << 5;
+                    let sample = (*state as f64 / u32::MAX as f64 * 2.0 - 1.0) * LEVEL;
+                    write_frame(frame, sample as f32);
+                }
+            }
+
+            frames += 1;
+            self.position += 1;
+        }
+
+        Ok(frames)
+    }
+}
+
+// Writes the same sample to every channel of `frame`
+fn write_frame(frame: &mut [f32], sample: f32) {
+    for output in frame.iter_mut() {
+        *output = sample;
+    }
+}
+
+fn wav_error<T>(result: hound::Result<T>) -> Result<T> {
+    result.map_err(|error| Error::Device(format!("could not read WAV file: {}", error)))
+}
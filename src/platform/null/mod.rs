@@ -0,0 +1,474 @@
+// A backend without any audio hardware. Recording streams a WAV file or a generated test signal,
+// and playback writes what it receives to a WAV file, to memory, or nowhere. Unless `realtime` is
+// set, nothing is paced to a clock, so the whole record, network and playback path can be run
+// headless and as fast as the machine allows.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::media::channels::{ChannelLayout, ChannelMixer};
+use crate::media::device::{Device, DeviceKind};
+use crate::media::dither::Dither;
+use crate::media::format::{SampleFormat, StreamFormat};
+use crate::media::jitter::JitterBuffer;
+use crate::media::resample::{ResampleQuality, Resampler};
+use crate::media::InterfaceTrait;
+use crate::network::{packet_channel, serve_with, spawn_receiver, Packet, Stream};
+use crate::network::reconnect::{self, Backoff};
+use crate::shutdown::Shutdown;
+use hound::{WavSpec, WavWriter};
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod generator;
+
+pub use generator::Input;
+use generator::Generator;
+
+// Milliseconds of audio in each packet recorded, and in each write played
+const CHUNK_MILLIS: u32 = 10;
+
+#[derive(Clone, Debug)]
+pub enum Output {
+    Discard,
+    // Written as a WAV file in the stream's format
+    File(PathBuf),
+    Memory(MemoryOutput),
+}
+
+// Collects played samples so the caller can inspect them. Clones share the same buffer.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryOutput {
+    inner: Arc<Mutex<MemoryInner>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryInner {
+    samples: Vec<u8>,
+    format: Option<StreamFormat>,
+}
+
+impl MemoryOutput {
+    pub fn new() -> MemoryOutput {
+        MemoryOutput::default()
+    }
+
+    // Everything played so far, in the stream format
+    pub fn samples(&self) -> Vec<u8> {
+        self.inner.lock().unwrap().samples.clone()
+    }
+
+    // The format of the stream that was played, once playback has started
+    pub fn format(&self) -> Option<StreamFormat> {
+        self.inner.lock().unwrap().format
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub input: Input,
+    pub output: Output,
+    // Stops recording or playback after this much audio. None records until the input runs out
+    // and plays until the stream ends
+    pub duration: Option<Duration>,
+    // Paces recording and playback like a sound card would, rather than running flat out. Playback
+    // then goes through the jitter buffer and reconnects when the server goes away, like the other
+    // backends, so it only stops after `duration` or on shutdown
+    pub realtime: bool,
+    // How long recording waits for players to connect before it sends anything, as a server
+    // doesn't keep audio for clients that connect late
+    pub start_delay: Duration,
+    // Rate playback runs at, like a device with a rate of its own. None plays at the stream's rate
+    pub rate: Option<u32>,
+    // Channels playback mixes to, like a device with speakers of its own. None plays as many as
+    // the channel map mixes to, or else as many as the stream has
+    pub channels: Option<u16>,
+    // Told the address recording serves TCP clients on once it is listening, so players can find a
+    // server that was given port 0
+    pub listening: Option<mpsc::Sender<SocketAddr>>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            input: Input::Sine { frequency: 440.0 },
+            output: Output::Discard,
+            duration: None,
+            realtime: false,
+            start_delay: Duration::from_secs(0),
+            rate: None,
+            channels: None,
+            listening: None,
+        }
+    }
+}
+
+pub struct Interface {
+    settings: Settings,
+}
+
+impl Interface {
+    pub fn new(settings: Settings) -> Self {
+        Interface { settings }
+    }
+
+    // Number of frames `duration` allows at `rate`
+    fn frame_limit(&self, rate: u32) -> Option<u64> {
+        self.settings.duration.map(|duration| (duration.as_secs_f64() * rate as f64).round() as u64)
+    }
+}
+
+impl InterfaceTrait for Interface {
+    fn init(&self) {
+    }
+
+    fn devices(&self) -> Result<Vec<Device>> {
+        let device = |kind, name: &str| Device {
+            id: "null".to_string(),
+            name: name.to_string(),
+            kind,
+            api: "null",
+            monitor: false,
+            default: true,
+        };
+        Ok(vec![device(DeviceKind::Capture, "Test signal"), device(DeviceKind::Playback, "Null output")])
+    }
+
+    fn start_playback(&self, config: &Config, shutdown: &Shutdown) -> Result<()> {
+        let mut stream = reconnect::connect(config, shutdown, &mut Backoff::new(config.reconnect))?;
+        let stream_format = stream.format();
+        let channels = self.settings.channels
+            .or_else(|| config.channel_map.as_ref().map(|map| map.outputs() as u16))
+            .unwrap_or(stream_format.channels);
+        let format = StreamFormat {
+            rate: self.settings.rate.unwrap_or(stream_format.rate),
+            channels,
+            ..stream_format
+        };
+        let mixer = ChannelMixer::with_map(
+            config.channel_map.as_ref(),
+            &ChannelLayout::standard(stream_format.channels),
+            &ChannelLayout::standard(format.channels),
+        )?;
+        let mut writer = Writer::new(&self.settings.output, format)?;
+        let frame_limit = self.frame_limit(format.rate);
+
+        let result = if self.settings.realtime {
+            let jitter_buffer = Arc::new(JitterBuffer::new(stream_format, format.rate, config.jitter, config.resample));
+            spawn_receiver(stream, jitter_buffer.clone(), config, shutdown);
+
+            let shutdown_buffer = jitter_buffer.clone();
+            shutdown.on_request(move || shutdown_buffer.close(None));
+
+            let chunk_frames = (format.rate * CHUNK_MILLIS / 1000) as u64;
+            let clock = Clock::new(format.rate);
+            let mut frames = 0;
+            let mut chunk = vec![0; chunk_frames as usize * stream_format.bytes_per_frame()];
+            let mut mixed = vec![];
+            loop {
+                let wanted = frame_limit.map_or(chunk_frames, |limit| chunk_frames.min(limit - frames));
+                if wanted == 0 {
+                    break;
+                }
+
+                let chunk = &mut chunk[..wanted as usize * stream_format.bytes_per_frame()];
+                let playing = jitter_buffer.pop(chunk);
+                if mixer.is_identity() {
+                    writer.write(chunk)?;
+                } else {
+                    mix_bytes(&mixer, format.sample_format, chunk, &mut mixed);
+                    writer.write(&mixed)?;
+                }
+                frames += wanted;
+                if !playing {
+                    break;
+                }
+                clock.wait_until(frames);
+            }
+
+            match jitter_buffer.take_error() {
+                Some(error) => Err(error),
+                None => Ok(()),
+            }
+        } else {
+            // Straight from the stream, so unless it is mixed or resampled the output holds exactly
+            // the samples that were sent
+            play_packets(&mut stream, &mut writer, &mixer, format.rate, config.resample, frame_limit, shutdown)
+        };
+
+        writer.finalize()?;
+        result
+    }
+
+    fn start_recording(&self, config: &Config, shutdown: &Shutdown) -> Result<()> {
+        let mut generator = Generator::new(&self.settings.input, config)?;
+        let capture_format = generator.format();
+        // What is generated is mixed to the configured channels, as the other backends mix what
+        // their devices capture
+        let format = StreamFormat {
+            rate: config.transport_rate.unwrap_or(capture_format.rate),
+            channels: config.channels,
+            ..capture_format
+        };
+        let mixer = ChannelMixer::with_map(
+            config.channel_map.as_ref(),
+            &ChannelLayout::standard(capture_format.channels),
+            &ChannelLayout::standard(format.channels),
+        )?;
+        let frame_limit = self.frame_limit(capture_format.rate);
+        let chunk_frames = (capture_format.rate * CHUNK_MILLIS / 1000) as u64;
+
+        // Integer WAV files that fit in the stream format are sent as they are, and everything
+        // else is mixed, resampled and dithered like a float mix would be
+        let mut resampler = if format.rate != capture_format.rate {
+            Some(Resampler::new(format.channels as usize, capture_format.rate, format.rate, config.resample))
+        } else {
+            None
+        };
+        let sample_format = format.sample_format;
+        let exact = resampler.is_none() && mixer.is_identity()
+            && matches!(generator.bits(), Some(bits) if !sample_format.is_float() && bits <= sample_format.bits());
+        let mut dither = Dither::new(config.dither, format);
+        let mut signal = vec![0.0; chunk_frames as usize * capture_format.channels as usize];
+        let (mut mixed, mut resampled) = (vec![], vec![]);
+
+        let (sender, receiver) = packet_channel();
+        let serve_config = config.clone();
+        let listening = self.settings.listening.clone();
+        let serve_thread = thread::spawn(move || {
+            serve_with(receiver, serve_config, format, |address| {
+                // Nobody waiting for the address is no reason to stop serving
+                if let Some(listening) = listening {
+                    let _ = listening.send(address);
+                }
+            })
+        });
+
+        let mut frames = 0;
+        let mut result = Ok(());
+        let delayed = shutdown.wait_timeout(self.settings.start_delay);
+        let clock = Clock::new(capture_format.rate);
+        while !delayed && !shutdown.is_requested() {
+            let wanted = frame_limit.map_or(chunk_frames, |limit| chunk_frames.min(limit - frames));
+            if wanted == 0 {
+                break;
+            }
+
+            let signal = &mut signal[..wanted as usize * capture_format.channels as usize];
+            let filled = match generator.fill(signal) {
+                Ok(filled) => filled,
+                Err(error) => {
+                    result = Err(error);
+                    break;
+                }
+            };
+            if filled == 0 {
+                break;
+            }
+
+            let signal = &signal[..filled * capture_format.channels as usize];
+            let signal = if mixer.is_identity() {
+                signal
+            } else {
+                mixer.mix(signal, &mut mixed);
+                &mixed
+            };
+            let signal = match &mut resampler {
+                Some(resampler) => {
+                    resampler.resample(signal, &mut resampled);
+                    &resampled
+                }
+                None => signal,
+            };
+            let mut chunk = vec![0; signal.len() * sample_format.bytes_per_sample()];
+            if exact {
+                sample_format.write_samples(signal, &mut chunk);
+            } else {
+                dither.process(signal, &mut chunk);
+            }
+
+            let timestamp = frames * 1_000_000 / capture_format.rate as u64;
+            if sender.send(Packet::new(timestamp, chunk)).is_err() {
+                // The server only hangs up on us when it has failed
+                break;
+            }
+
+            frames += filled as u64;
+            if self.settings.realtime {
+                clock.wait_until(frames);
+            }
+        }
+
+        // Dropping the sender tells the server to say goodbye to its clients and return
+        drop(sender);
+        let served = match serve_thread.join() {
+            Ok(result) => result,
+            Err(_) => Err(Error::Network(io::Error::other("network thread panicked"))),
+        };
+        result.and(served)
+    }
+}
+
+// Writes packets as they arrive, mixed by `mixer` and resampled to `rate` if the stream isn't
+// already at it
+fn play_packets(
+    stream: &mut Stream,
+    writer: &mut Writer,
+    mixer: &ChannelMixer,
+    rate: u32,
+    quality: ResampleQuality,
+    frame_limit: Option<u64>,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    let format = stream.format();
+    let sample_format = format.sample_format;
+    let bytes_per_frame = sample_format.bytes_per_sample() * mixer.outputs();
+    let mut resampler = if rate != format.rate {
+        Some(Resampler::new(mixer.outputs(), format.rate, rate, quality))
+    } else {
+        None
+    };
+    let mix = !mixer.is_identity();
+    let (mut input, mut mixed, mut output, mut converted) = (vec![], vec![], vec![], vec![]);
+
+    let mut frames = 0;
+    while !shutdown.is_requested() {
+        let packet = match stream.read_packet() {
+            Ok(packet) => packet,
+            // The server said goodbye
+            Err(Error::Closed) => return Ok(()),
+            Err(error) => return Err(error),
+        };
+
+        let mut payload = &packet.payload[..];
+        if mix || resampler.is_some() {
+            input.resize(payload.len() / sample_format.bytes_per_sample(), 0.0);
+            sample_format.read_samples(payload, &mut input);
+            let mut samples = &input;
+            if mix {
+                mixer.mix(samples, &mut mixed);
+                samples = &mixed;
+            }
+            if let Some(resampler) = &mut resampler {
+                resampler.resample(samples, &mut output);
+                samples = &output;
+            }
+            converted.resize(samples.len() * sample_format.bytes_per_sample(), 0);
+            sample_format.write_samples(samples, &mut converted);
+            payload = &converted;
+        }
+        if let Some(limit) = frame_limit {
+            let remaining = (limit - frames) as usize * bytes_per_frame;
+            payload = &payload[..payload.len().min(remaining)];
+        }
+        writer.write(payload)?;
+
+        frames += (payload.len() / bytes_per_frame) as u64;
+        if frame_limit == Some(frames) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Mixes `samples` in `sample_format` by `mixer` into `output`, in the same format
+fn mix_bytes(mixer: &ChannelMixer, sample_format: SampleFormat, samples: &[u8], output: &mut Vec<u8>) {
+    let mut input = vec![0.0; samples.len() / sample_format.bytes_per_sample()];
+    sample_format.read_samples(samples, &mut input);
+    let mut mixed = vec![];
+    mixer.mix(&input, &mut mixed);
+    output.resize(mixed.len() * sample_format.bytes_per_sample(), 0);
+    sample_format.write_samples(&mixed, output);
+}
+
+// Sleeps to keep a count of frames in step with the wall clock
+struct Clock {
+    start: Instant,
+    rate: u32,
+}
+
+impl Clock {
+    fn new(rate: u32) -> Clock {
+        Clock { start: Instant::now(), rate }
+    }
+
+    fn wait_until(&self, frames: u64) {
+        let due = self.start + Duration::from_micros(frames * 1_000_000 / self.rate as u64);
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+    }
+}
+
+enum Writer {
+    Discard,
+    File(WavWriter<BufWriter<File>>, SampleFormat),
+    Memory(MemoryOutput),
+}
+
+impl Writer {
+    fn new(output: &Output, format: StreamFormat) -> Result<Writer> {
+        match output {
+            Output::Discard => Ok(Writer::Discard),
+            Output::File(path) => {
+                let spec = WavSpec {
+                    channels: format.channels,
+                    sample_rate: format.rate,
+                    bits_per_sample: format.sample_format.bits() as u16,
+                    sample_format: if format.sample_format.is_float() {
+                        hound::SampleFormat::Float
+                    } else {
+                        hound::SampleFormat::Int
+                    },
+                };
+                let writer = WavWriter::create(path, spec)
+                    .map_err(|error| Error::Device(format!("could not create {}: {}", path.display(), error)))?;
+                Ok(Writer::File(writer, format.sample_format))
+            }
+            Output::Memory(memory) => {
+                let mut inner = memory.inner.lock().unwrap();
+                inner.samples.clear();
+                inner.format = Some(format);
+                Ok(Writer::Memory(memory.clone()))
+            }
+        }
+    }
+
+    fn write(&mut self, samples: &[u8]) -> Result<()> {
+        match self {
+            Writer::Discard => (),
+            Writer::File(writer, sample_format) => {
+                // Integer samples go through S32 so none of their bits are lost
+                let shift = 32 - sample_format.bits();
+                for bytes in samples.chunks_exact(sample_format.bytes_per_sample()) {
+                    let result = if sample_format.is_float() {
+                        writer.write_sample(sample_format.read_sample(bytes))
+                    } else {
+                        let mut sample = [0; 4];
+                        SampleFormat::convert(*sample_format, bytes, SampleFormat::S32LE, &mut sample);
+                        writer.write_sample(i32::from_le_bytes(sample) >> shift)
+                    };
+                    result.map_err(wav_error)?;
+                }
+            }
+            Writer::Memory(memory) => memory.inner.lock().unwrap().samples.extend_from_slice(samples),
+        }
+        Ok(())
+    }
+
+    fn finalize(self) -> Result<()> {
+        match self {
+            Writer::File(writer, _) => writer.finalize().map_err(wav_error),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn wav_error(error: hound::Error) -> Error {
+    Error::Device(format!("could not write WAV file: {}", error))
+}
@@ -1,14 +1,35 @@
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::media::channels::{ChannelLayout, ChannelMixer};
+use crate::media::device::{self, Device, DeviceKind};
+use crate::media::dither::Dither;
 use crate::media::InterfaceTrait;
-use crate::network::accept_clients;
-use byteorder::{ByteOrder, LittleEndian};
+use crate::media::format::{SampleFormat, StreamFormat};
+use crate::media::jitter::JitterBuffer;
+use crate::media::resample::Resampler;
+use crate::network::{packet_channel, serve, spawn_receiver, Packet};
+use crate::network::reconnect::{self, Backoff};
+use crate::shutdown::Shutdown;
 use wasapi::{COM, DeviceEnumerator};
 use winapi::um::audiosessiontypes::AUDCLNT_STREAMFLAGS_LOOPBACK;
-use std::io::prelude::*;
-use std::net::TcpStream;
-use std::sync::mpsc::sync_channel;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 mod wasapi;
 
+// Milliseconds between checks for free space in the render buffer
+const RENDER_POLL_INTERVAL: u64 = 5;
+
+// Checks for captured audio in each device period when none is pending
+const CAPTURE_POLLS_PER_PERIOD: u32 = 4;
+
+// Audio endpoints can be enumerated, which fails when the audio service isn't running
+pub fn is_available() -> bool {
+    COM::init().and_then(|_| DeviceEnumerator::create()).is_ok()
+}
+
 pub struct Interface;
 
 impl Interface {
@@ -21,31 +42,88 @@ impl InterfaceTrait for Interface {
     fn init(&self) {
     }
 
-    fn start_playback(&self) -> Result<(), ()> {
-        let mut stream = TcpStream::connect("192.168.0.63:42795").expect("Could not connect to TCP stream");
+    fn devices(&self) -> Result<Vec<Device>> {
+        COM::init()?;
+
+        let device_enumerator = DeviceEnumerator::create()?;
+        let default_id = device_enumerator.get_default_audio_endpoint().and_then(|device| device.id()).ok();
+
+        let mut devices = vec![];
+        for endpoint in device_enumerator.enum_audio_endpoints()? {
+            let id = endpoint.id()?;
+            let name = endpoint.friendly_name()?;
+            let default = default_id.as_ref() == Some(&id);
+
+            // Recording captures a playback device in loopback mode, so each one is listed as both
+            devices.push(Device {
+                id: id.clone(),
+                name: name.clone(),
+                kind: DeviceKind::Capture,
+                api: "wasapi",
+                monitor: true,
+                default,
+            });
+            devices.push(Device { id, name, kind: DeviceKind::Playback, api: "wasapi", monitor: false, default });
+        }
+        Ok(devices)
+    }
+
+    fn start_playback(&self, config: &Config, shutdown: &Shutdown) -> Result<()> {
+        let stream = reconnect::connect(config, shutdown, &mut Backoff::new(config.reconnect))?;
 
         COM::init()?;
 
         let device_enumerator = DeviceEnumerator::create()?;
-        let device = device_enumerator.get_default_audio_endpoint()?;
+        let device = match &config.device {
+            Some(query) => {
+                let devices = self.devices()?;
+                device_enumerator.get_device(&device::select(&devices, DeviceKind::Playback, None, query)?.id)?
+            }
+            None => device_enumerator.get_default_audio_endpoint()?,
+        };
 
         let audio_client = device.activate()?;
         let mix_format = audio_client.get_mix_format()?;
-        let bytes_per_frame = unsafe { (*mix_format.ptr).nBlockAlign };
+        let bytes_per_frame = mix_format.block_align();
+
+        // Any sample format converts to the float the device mixes in, any rate is resampled to the
+        // device's and any layout is mixed to the speakers it has
+        let stream_format = stream.format();
+        if stream_format.rate != mix_format.rate() {
+            println!("Resampling from {} Hz to the device's {} Hz", stream_format.rate, mix_format.rate());
+        }
+        let device_layout = mix_format.channel_layout();
+        let mixer = ChannelMixer::with_map(
+            config.channel_map.as_ref(),
+            &ChannelLayout::standard(stream_format.channels),
+            &device_layout,
+        )?;
+        if stream_format.channels as usize != device_layout.len() {
+            println!("Mixing {} channels to the device's {}", stream_format.channels, device_layout.len());
+        }
+        let rate = mix_format.rate();
         audio_client.initialize(0, mix_format)?;
 
+        // The network is read on its own thread, so the render loop never waits on it
+        let jitter_buffer = Arc::new(JitterBuffer::new(stream_format, rate, config.jitter, config.resample));
+        spawn_receiver(stream, jitter_buffer.clone(), config, shutdown);
+
+        // Closing the buffer plays out what it holds and then ends the render loop
+        let shutdown_buffer = jitter_buffer.clone();
+        shutdown.on_request(move || shutdown_buffer.close(None));
+
         let buffer_size = audio_client.get_buffer_size()?;
         let render_client = audio_client.get_render_service()?;
 
         let buffer = render_client.get_buffer(buffer_size, bytes_per_frame)?;
 
-        let mut input = vec![0; buffer.len() / 2];
-        stream.read_exact(&mut input).expect("Could not read samples from stream");
-        let floating_point_input = convert_signed_pcm_to_floating_point(input);
-
-        for i in 0..floating_point_input.len() {
-            buffer[i] = floating_point_input[i];
-        }
+        // The device mixes in float, so samples are popped here, mixed to its channels and written
+        // into its buffer
+        let mut input = vec![0; buffer_size as usize * stream_format.bytes_per_frame()];
+        let mut samples = vec![0.0; buffer_size as usize * stream_format.channels as usize];
+        let mut mixed = vec![0.0; buffer_size as usize * mixer.outputs()];
+        jitter_buffer.pop(&mut input);
+        render(stream_format.sample_format, &input, &mixer, &mut samples, &mut mixed, buffer);
 
         render_client.release_buffer(buffer_size)?;
         audio_client.start()?;
@@ -55,81 +133,145 @@ impl InterfaceTrait for Interface {
             let num_frames_available = buffer_size - num_frames_padding;
             if num_frames_available > 0 {
                 let buffer = render_client.get_buffer(num_frames_available, bytes_per_frame)?;
-                input = vec![0; buffer.len() / 2];
-                stream.read_exact(&mut input).expect("Could not read samples from stream");
-                let floating_point_input = convert_signed_pcm_to_floating_point(input);
-
-                for i in 0..floating_point_input.len() {
-                    buffer[i] = floating_point_input[i];
-                }
+                let input = &mut input[..num_frames_available as usize * stream_format.bytes_per_frame()];
+                let playing = jitter_buffer.pop(input);
+                render(stream_format.sample_format, input, &mixer, &mut samples, &mut mixed, buffer);
 
                 render_client.release_buffer(num_frames_available)?;
+
+                if !playing {
+                    break;
+                }
             }
+
+            thread::sleep(Duration::from_millis(RENDER_POLL_INTERVAL));
+        }
+
+        audio_client.stop()?;
+        match jitter_buffer.take_error() {
+            Some(error) => Err(error),
+            None => Ok(()),
         }
     }
 
-    fn start_recording(&self) -> Result<(), ()> {
+    fn start_recording(&self, config: &Config, shutdown: &Shutdown) -> Result<()> {
+        if config.app.is_some() {
+            return Err(Error::Device("capturing a single application is only supported on Linux with PipeWire".to_string()));
+        }
+
         COM::init()?;
 
         let device_enumerator = DeviceEnumerator::create()?;
-        let device = device_enumerator.get_default_audio_endpoint()?;
+        let device = match &config.device {
+            Some(query) => {
+                let devices = self.devices()?;
+                device_enumerator.get_device(&device::select(&devices, DeviceKind::Capture, None, query)?.id)?
+            }
+            None => device_enumerator.get_default_audio_endpoint()?,
+        };
 
         let audio_client = device.activate()?;
         let mix_format = audio_client.get_mix_format()?;
-        let bytes_per_frame = unsafe { (*mix_format.ptr).nBlockAlign };
+        let bytes_per_frame = mix_format.block_align();
+        // Loopback capture gets the float mix as is, which is mixed down or up to the configured
+        // channels, resampled to the transport rate and converted to the configured sample format
+        let capture_rate = mix_format.rate();
+        let format = StreamFormat {
+            sample_format: config.sample_format,
+            rate: config.transport_rate.unwrap_or(capture_rate),
+            channels: config.channels,
+        };
+        let mixer = ChannelMixer::with_map(
+            config.channel_map.as_ref(),
+            &mix_format.channel_layout(),
+            &ChannelLayout::standard(format.channels),
+        )?;
+        if mix_format.channels() != format.channels {
+            println!("Mixing the device's {} channels to {}", mix_format.channels(), format.channels);
+        }
         audio_client.initialize(AUDCLNT_STREAMFLAGS_LOOPBACK, mix_format.clone())?;
 
         let capture_client = audio_client.get_capture_service()?;
+        // A new packet is ready every period, so waiting a fraction of one never lets the buffer fill
+        let poll_interval = audio_client.get_device_period()? / CAPTURE_POLLS_PER_PERIOD;
 
         audio_client.start()?;
 
-        let (sender, receiver) = sync_channel(1);
-        let _serve_thread = std::thread::spawn(move || {
-            accept_clients(receiver);
+        // The float mix is resampled to the transport rate and dithered down to integer formats
+        let mut resampler = if format.rate != capture_rate {
+            Some(Resampler::new(format.channels as usize, capture_rate, format.rate, config.resample))
+        } else {
+            None
+        };
+        let mut dither = Dither::new(config.dither, format);
+        let mut mix = vec![];
+        let mut mixed = vec![];
+        let mut resampled = vec![];
+        let mut samples = vec![];
+
+        let (sender, receiver) = packet_channel();
+        let serve_config = config.clone();
+        let serve_thread = std::thread::spawn(move || {
+            serve(receiver, serve_config, format)
         });
 
-        'main: loop {
+        while !shutdown.is_requested() {
             let mut packet_size = capture_client.get_next_packet_size()?;
+            if packet_size == 0 {
+                thread::sleep(poll_interval);
+                continue;
+            }
 
             while packet_size > 0 {
-                let (audio, num_frames_available) = capture_client.get_buffer(bytes_per_frame)?;
-                let signed_pcm = convert_floating_point_to_signed_pcm(&audio);
-
-                sender.send(signed_pcm).expect("Could not send PCM data");
+                let (audio, num_frames_available, qpc_position) = capture_client.get_buffer(bytes_per_frame)?;
+                mix.resize(audio.len() / 4, 0.0);
+                SampleFormat::F32LE.read_samples(audio, &mut mix);
+                let mix = if mixer.is_identity() {
+                    &mix
+                } else {
+                    mixer.mix(&mix, &mut mixed);
+                    &mixed
+                };
+                let mix = match &mut resampler {
+                    Some(resampler) => {
+                        resampler.resample(mix, &mut resampled);
+                        &resampled
+                    }
+                    None => mix,
+                };
+                samples.resize(mix.len() * format.sample_format.bytes_per_sample(), 0);
+                dither.process(mix, &mut samples);
+
+                // The performance counter position is in 100 ns units. The packet owns its payload, so it
+                // takes an exact copy and the scratch buffer is kept for the next one
+                if sender.send(Packet::new(qpc_position / 10, samples.clone())).is_err() {
+                    // The server only hangs up on us when it has failed
+                    return match serve_thread.join() {
+                        Ok(result) => result,
+                        Err(_) => Err(Error::Network(io::Error::other("network thread panicked"))),
+                    };
+                }
 
                 capture_client.release_buffer(num_frames_available)?;
                 packet_size = capture_client.get_next_packet_size()?;
             }
         }
-    }
-}
-
-fn convert_signed_pcm_to_floating_point(signed_pcm: Vec<u8>) -> Vec<u8> {
-    let mut floating_point_input = Vec::with_capacity(signed_pcm.len() * 2);
-    for i in (0..signed_pcm.len()).step_by(2) {
-        let bytes = [signed_pcm[i], signed_pcm[i + 1]];
-        let signed_pcm = LittleEndian::read_i16(&bytes);
-        let normalised_signed_pcm = signed_pcm as f32 / 32_767.0;
 
-        let mut buffer = [0; 4];
-        LittleEndian::write_f32(&mut buffer, normalised_signed_pcm);
-
-        floating_point_input.extend_from_slice(&buffer);
+        // Dropping the sender tells the server to say goodbye to its clients and return
+        audio_client.stop()?;
+        drop(sender);
+        match serve_thread.join() {
+            Ok(result) => result,
+            Err(_) => Err(Error::Network(io::Error::other("network thread panicked"))),
+        }
     }
-    floating_point_input
 }
 
-fn convert_floating_point_to_signed_pcm(floating_point: &[u8]) -> Vec<u8> {
-    let mut signed_pcm = Vec::with_capacity(floating_point.len() / 2);
-    for i in (0..floating_point.len()).step_by(4) {
-        let bytes = [floating_point[i], floating_point[i + 1], floating_point[i + 2], floating_point[i + 3]];
-        let floating_point = LittleEndian::read_f32(&bytes);
-        let normalised_floating_point = (floating_point * 32_767.0) as i16;
-
-        let mut buffer = [0; 2];
-        LittleEndian::write_i16(&mut buffer, normalised_floating_point);
-
-        signed_pcm.extend_from_slice(&buffer);
-    }
-    signed_pcm
+// Converts the popped `input` to float, mixes it to the device's channels and writes it into the
+// render `buffer`
+fn render(sample_format: SampleFormat, input: &[u8], mixer: &ChannelMixer, samples: &mut [f32], mixed: &mut [f32], buffer: &mut [u8]) {
+    let samples = &mut samples[..input.len() / sample_format.bytes_per_sample()];
+    sample_format.read_samples(input, samples);
+    let frames = mixer.process(samples, mixed);
+    SampleFormat::F32LE.write_samples(&mixed[..frames * mixer.outputs()], buffer);
 }
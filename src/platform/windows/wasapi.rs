@@ -1,24 +1,48 @@
+use std::ffi::{OsStr, OsString};
+use std::iter::once;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
 use std::ptr;
+use std::time::Duration;
+use crate::error::{BackendError, Error, Result};
+use crate::media::channels::ChannelLayout;
 use winapi::Interface;
 use winapi::shared::minwindef::{BYTE, DWORD};
-use winapi::shared::winerror::SUCCEEDED;
+use winapi::shared::winerror::{HRESULT, SUCCEEDED};
 use winapi::um::audioclient::{IID_IAudioClient, IAudioClient, IAudioCaptureClient, IID_IAudioCaptureClient, IID_IAudioRenderClient, IAudioRenderClient};
 use winapi::um::audiosessiontypes::AUDCLNT_SHAREMODE_SHARED;
 use winapi::um::objbase::CoInitialize;
-use winapi::um::mmdeviceapi::{CLSID_MMDeviceEnumerator, IMMDeviceEnumerator, IMMDevice, eRender, eConsole};
-use winapi::um::combaseapi::{CoCreateInstance, CLSCTX_ALL, CoTaskMemFree};
-use winapi::shared::mmreg::WAVEFORMATEX;
+use winapi::um::mmdeviceapi::{CLSID_MMDeviceEnumerator, IMMDeviceEnumerator, IMMDevice, IMMDeviceCollection, eRender, eConsole, DEVICE_STATE_ACTIVE};
+use winapi::um::combaseapi::{CoCreateInstance, CLSCTX_ALL, CoTaskMemFree, PropVariantClear};
+use winapi::um::coml2api::STGM_READ;
+use winapi::um::functiondiscoverykeys_devpkey::PKEY_Device_FriendlyName;
+use winapi::um::propidl::PROPVARIANT;
+use winapi::um::propsys::IPropertyStore;
+use winapi::um::winnt::LPWSTR;
+use winapi::shared::mmreg::{WAVEFORMATEX, WAVEFORMATEXTENSIBLE, WAVE_FORMAT_EXTENSIBLE};
 use winapi::um::strmif::REFERENCE_TIME;
 
+fn check(call: &'static str, result: HRESULT) -> Result<()> {
+    if !SUCCEEDED(result) {
+        return Err(Error::Backend(BackendError::Hresult { call, code: result }));
+    }
+    Ok(())
+}
+
+// Copies a null terminated wide string
+unsafe fn from_wide(string: LPWSTR) -> String {
+    let mut length = 0;
+    while *string.add(length) != 0 {
+        length += 1;
+    }
+    OsString::from_wide(std::slice::from_raw_parts(string, length)).to_string_lossy().into_owned()
+}
+
 pub struct COM();
 
 impl COM {
-    pub fn init() -> Result<(), ()> {
+    pub fn init() -> Result<()> {
         let result = unsafe { CoInitialize(ptr::null_mut()) };
-        if !SUCCEEDED(result) {
-            eprintln!("CoInitialize failed! {:#x}", result);
-            return Err(());
-        }
+        check("CoInitialize", result)?;
         Ok(())
     }
 }
@@ -28,29 +52,61 @@ pub struct DeviceEnumerator {
 }
 
 impl DeviceEnumerator {
-    pub fn create() -> Result<DeviceEnumerator, ()> {
+    pub fn create() -> Result<DeviceEnumerator> {
         let mut ptr: *mut IMMDeviceEnumerator = ptr::null_mut();
         let result = unsafe {
             CoCreateInstance(&CLSID_MMDeviceEnumerator, ptr::null_mut(), CLSCTX_ALL, &IMMDeviceEnumerator::uuidof(), &mut ptr as *mut _ as *mut _)
         };
 
-        if !SUCCEEDED(result) {
-            eprintln!("CoCreateInstance failed! {:#x}", result);
-            return Err(());
-        }
+        check("CoCreateInstance", result)?;
 
         Ok(DeviceEnumerator { ptr })
     }
 
-    pub fn get_default_audio_endpoint(&self) -> Result<AudioDevice, ()> {
+    pub fn get_default_audio_endpoint(&self) -> Result<AudioDevice> {
         let mut ptr: *mut IMMDevice = ptr::null_mut();
         let result = unsafe {
             (*self.ptr).GetDefaultAudioEndpoint(eRender, eConsole, &mut ptr)
         };
 
+        check("IMMDeviceEnumerator->GetDefaultAudioEndpoint", result)?;
+        Ok(AudioDevice { ptr })
+    }
+
+    // Every playback device that is plugged in and enabled
+    pub fn enum_audio_endpoints(&self) -> Result<Vec<AudioDevice>> {
+        let mut collection: *mut IMMDeviceCollection = ptr::null_mut();
+        let result = unsafe {
+            (*self.ptr).EnumAudioEndpoints(eRender, DEVICE_STATE_ACTIVE, &mut collection)
+        };
+        check("IMMDeviceEnumerator->EnumAudioEndpoints", result)?;
+
+        let mut count: u32 = 0;
+        let result = unsafe { (*collection).GetCount(&mut count) };
+        let mut devices = Vec::with_capacity(count as usize);
+        if SUCCEEDED(result) {
+            for index in 0..count {
+                let mut ptr: *mut IMMDevice = ptr::null_mut();
+                if SUCCEEDED(unsafe { (*collection).Item(index, &mut ptr) }) {
+                    devices.push(AudioDevice { ptr });
+                }
+            }
+        }
+
+        unsafe { (*collection).Release(); }
+        check("IMMDeviceCollection->GetCount", result)?;
+        Ok(devices)
+    }
+
+    pub fn get_device(&self, id: &str) -> Result<AudioDevice> {
+        let wide_id: Vec<u16> = OsStr::new(id).encode_wide().chain(once(0)).collect();
+        let mut ptr: *mut IMMDevice = ptr::null_mut();
+        let result = unsafe {
+            (*self.ptr).GetDevice(wide_id.as_ptr(), &mut ptr)
+        };
+
         if !SUCCEEDED(result) {
-            eprintln!("IMMDeviceEnumerator->GetDefaultAudioEndpoint failed! {:#x}", result);
-            return Err(());
+            return Err(Error::Device(format!("no audio device with id {} ({:#x})", id, result)));
         }
         Ok(AudioDevice { ptr })
     }
@@ -68,18 +124,48 @@ pub struct AudioDevice {
 }
 
 impl AudioDevice {
-    pub fn activate(&self) -> Result<AudioClient, ()> {
+    pub fn activate(&self) -> Result<AudioClient> {
         let mut ptr: *mut IAudioClient = ptr::null_mut();
         let result = unsafe {
             (*self.ptr).Activate(&IID_IAudioClient, CLSCTX_ALL, ptr::null_mut(), &mut ptr as *mut _ as *mut _)
         };
 
-        if !SUCCEEDED(result) {
-            eprintln!("IMMDevice->Activate failed! {:#x}", result);
-            return Err(());
-        }
+        check("IMMDevice->Activate", result)?;
         Ok(AudioClient { ptr })
     }
+
+    pub fn id(&self) -> Result<String> {
+        let mut id: LPWSTR = ptr::null_mut();
+        let result = unsafe { (*self.ptr).GetId(&mut id) };
+        check("IMMDevice->GetId", result)?;
+
+        unsafe {
+            let string = from_wide(id);
+            CoTaskMemFree(id as *mut _);
+            Ok(string)
+        }
+    }
+
+    pub fn friendly_name(&self) -> Result<String> {
+        let mut store: *mut IPropertyStore = ptr::null_mut();
+        let result = unsafe { (*self.ptr).OpenPropertyStore(STGM_READ, &mut store) };
+        check("IMMDevice->OpenPropertyStore", result)?;
+
+        unsafe {
+            let mut value: PROPVARIANT = std::mem::zeroed();
+            let result = (*store).GetValue(&PKEY_Device_FriendlyName, &mut value);
+            let name = if SUCCEEDED(result) && !value.data.pwszVal().is_null() {
+                from_wide(*value.data.pwszVal())
+            } else {
+                String::new()
+            };
+            PropVariantClear(&mut value);
+            (*store).Release();
+
+            check("IPropertyStore->GetValue", result)?;
+            Ok(name)
+        }
+    }
 }
 
 impl Drop for AudioDevice {
@@ -94,105 +180,93 @@ pub struct AudioClient {
 }
 
 impl AudioClient {
-    pub fn get_mix_format(&self) -> Result<MixFormat, ()> {
+    pub fn get_mix_format(&self) -> Result<MixFormat> {
         let mut ptr: *mut WAVEFORMATEX = ptr::null_mut();
         let result = unsafe {
             (*self.ptr).GetMixFormat(&mut ptr)
         };
 
-        if !SUCCEEDED(result) {
-            eprintln!("IAudioClient->GetMixFormat failed! {:#x}", result);
-            return Err(());
-        }
+        check("IAudioClient->GetMixFormat", result)?;
         Ok(MixFormat { ptr })
     }
 
-    pub fn initialize(&self, stream_flags: u32, mix_format: MixFormat) -> Result<(), ()> {
+    pub fn initialize(&self, stream_flags: u32, mix_format: MixFormat) -> Result<()> {
         let buffer_duration: REFERENCE_TIME = 1000000;
         let result = unsafe {
             (*self.ptr).Initialize(AUDCLNT_SHAREMODE_SHARED, stream_flags, buffer_duration, 0, mix_format.ptr, ptr::null_mut())
         };
 
-        if !SUCCEEDED(result) {
-            eprintln!("IAudioClient->Initialize failed! {:#x}", result);
-            return Err(());
-        }
+        check("IAudioClient->Initialize", result)?;
         Ok(())
     }
 
-    pub fn get_buffer_size(&self) -> Result<u32, ()> {
+    pub fn get_buffer_size(&self) -> Result<u32> {
         let mut buffer_size: u32 = 0;
         let result = unsafe {
             (*self.ptr).GetBufferSize(&mut buffer_size as *mut _)
         };
 
-        if !SUCCEEDED(result) {
-            eprintln!("IAudioClient->GetBufferSize failed! {:#x}", result);
-            return Err(());
-        }
+        check("IAudioClient->GetBufferSize", result)?;
         Ok(buffer_size)
     }
 
-    pub fn get_render_service(&self) -> Result<AudioRenderClient, ()> {
+    // How often the audio engine processes the stream, in shared mode
+    pub fn get_device_period(&self) -> Result<Duration> {
+        let mut default_period: REFERENCE_TIME = 0;
+        let result = unsafe {
+            (*self.ptr).GetDevicePeriod(&mut default_period as *mut _, ptr::null_mut())
+        };
+
+        check("IAudioClient->GetDevicePeriod", result)?;
+        // Reference times are in 100 ns units
+        Ok(Duration::from_nanos(default_period as u64 * 100))
+    }
+
+    pub fn get_render_service(&self) -> Result<AudioRenderClient> {
         let mut ptr: *mut IAudioRenderClient = ptr::null_mut();
         let result = unsafe {
             (*self.ptr).GetService(&IID_IAudioRenderClient, &mut ptr as *mut _ as *mut _)
         };
 
-        if !SUCCEEDED(result) {
-            eprintln!("IAudioClient->GetService failed! {:#x}", result);
-            return Err(());
-        }
+        check("IAudioClient->GetService", result)?;
         Ok(AudioRenderClient { ptr })
     }
 
-    pub fn get_capture_service(&self) -> Result<AudioCaptureClient, ()> {
+    pub fn get_capture_service(&self) -> Result<AudioCaptureClient> {
         let mut ptr: *mut IAudioCaptureClient = ptr::null_mut();
         let result = unsafe {
             (*self.ptr).GetService(&IID_IAudioCaptureClient, &mut ptr as *mut _ as *mut _)
         };
 
-        if !SUCCEEDED(result) {
-            eprintln!("IAudioClient->GetService failed! {:#x}", result);
-            return Err(());
-        }
+        check("IAudioClient->GetService", result)?;
         Ok(AudioCaptureClient { ptr })
     }
 
-    pub fn start(&self) -> Result<(), ()> {
+    pub fn start(&self) -> Result<()> {
         let result = unsafe {
             (*self.ptr).Start()
         };
 
-        if !SUCCEEDED(result) {
-            eprintln!("IAudioClient->Start failed! {:#x}", result);
-            return Err(());
-        }
+        check("IAudioClient->Start", result)?;
         Ok(())
     }
 
-    pub fn get_current_padding(&self) -> Result<u32, ()> {
+    pub fn get_current_padding(&self) -> Result<u32> {
         let mut num_frames_padding: u32 = 0;
         let result = unsafe {
             (*self.ptr).GetCurrentPadding(&mut num_frames_padding as *mut _)
         };
 
-        if !SUCCEEDED(result) {
-            eprintln!("IAudioClient->GetCurrentPadding failed! {:#x}", result);
-            return Err(());
-        }
+        check("IAudioClient->GetCurrentPadding", result)?;
         Ok(num_frames_padding)
     }
 
-    pub fn stop(&self) -> Result<(), ()> {
+    pub fn stop(&self) -> Result<()> {
         let result = unsafe {
             (*self.ptr).Stop()
         };
 
-        if !SUCCEEDED(result) {
-            eprintln!("IAudioClient->Stop failed! {:#x}", result);
-            return Err(());
-        }
+        check("IAudioClient->Stop", result)?;
 
         Ok(())
     }
@@ -211,6 +285,38 @@ pub struct MixFormat {
     pub ptr: *mut WAVEFORMATEX,
 }
 
+impl MixFormat {
+    pub fn rate(&self) -> u32 {
+        unsafe { (*self.ptr).nSamplesPerSec }
+    }
+
+    pub fn channels(&self) -> u16 {
+        unsafe { (*self.ptr).nChannels }
+    }
+
+    pub fn block_align(&self) -> u16 {
+        unsafe { (*self.ptr).nBlockAlign }
+    }
+
+    // Which speakers the channels are for, which only an extensible format says
+    pub fn channel_mask(&self) -> Option<u32> {
+        unsafe {
+            if (*self.ptr).wFormatTag == WAVE_FORMAT_EXTENSIBLE {
+                Some((*(self.ptr as *const WAVEFORMATEXTENSIBLE)).dwChannelMask)
+            } else {
+                None
+            }
+        }
+    }
+
+    pub fn channel_layout(&self) -> ChannelLayout {
+        match self.channel_mask() {
+            Some(mask) => ChannelLayout::from_mask(mask, self.channels()),
+            None => ChannelLayout::standard(self.channels()),
+        }
+    }
+}
+
 impl Drop for MixFormat {
     fn drop(&mut self) {
         println!("Dropping MixFormat");
@@ -223,30 +329,24 @@ pub struct AudioRenderClient {
 }
 
 impl AudioRenderClient {
-    pub fn get_buffer(&self, buffer_size: u32, bytes_per_frame: u16) -> Result<&mut [u8], ()> {
+    pub fn get_buffer(&self, buffer_size: u32, bytes_per_frame: u16) -> Result<&mut [u8]> {
         let mut data: *mut BYTE = ptr::null_mut();
         let result = unsafe {
             (*self.ptr).GetBuffer(buffer_size, &mut data)
         };
 
-        if !SUCCEEDED(result) {
-            eprintln!("IAudioRenderClient->GetBuffer failed! {:#x}", result);
-            return Err(());
-        }
+        check("IAudioRenderClient->GetBuffer", result)?;
 
         let slice = unsafe { std::slice::from_raw_parts_mut(data, buffer_size as usize * bytes_per_frame as usize) };
         Ok(slice)
     }
 
-    pub fn release_buffer(&self, buffer_size: u32) -> Result<(), ()> {
+    pub fn release_buffer(&self, buffer_size: u32) -> Result<()> {
         let result = unsafe {
             (*self.ptr).ReleaseBuffer(buffer_size, 0)
         };
 
-        if !SUCCEEDED(result) {
-            eprintln!("IAudioRenderClient->ReleaseBuffer failed! {:#x}", result);
-            return Err(());
-        }
+        check("IAudioRenderClient->ReleaseBuffer", result)?;
         Ok(())
     }
 }
@@ -263,49 +363,41 @@ pub struct AudioCaptureClient {
 }
 
 impl AudioCaptureClient {
-    pub fn get_next_packet_size(&self) -> Result<u32, ()> {
+    pub fn get_next_packet_size(&self) -> Result<u32> {
         let mut packet_size: u32 = 0;
         let result = unsafe {
             (*self.ptr).GetNextPacketSize(&mut packet_size as *mut _)
         };
 
-        if !SUCCEEDED(result) {
-            eprintln!("IAudioCaptureClient->GetNextPacketSize failed! {:#x}", result);
-            return Err(());
-        }
+        check("IAudioCaptureClient->GetNextPacketSize", result)?;
 
         Ok(packet_size)
     }
 
-    pub fn get_buffer(&self, bytes_per_frame: u16) -> Result<(&[u8], u32), ()> {
+    pub fn get_buffer(&self, bytes_per_frame: u16) -> Result<(&[u8], u32, u64)> {
         let mut data: *mut BYTE = ptr::null_mut();
         let mut num_frames_available: u32 = 0;
         let mut flags: DWORD = 0;
         let mut audio_position: u64 = 0;
+        let mut qpc_position: u64 = 0;
         let result = unsafe {
-            (*self.ptr).GetBuffer(&mut data, &mut num_frames_available as *mut _, &mut flags, &mut audio_position as *mut _, ptr::null_mut())
+            (*self.ptr).GetBuffer(&mut data, &mut num_frames_available as *mut _, &mut flags, &mut audio_position as *mut _, &mut qpc_position as *mut _)
         };
 
-        if !SUCCEEDED(result) {
-            eprintln!("IAudioCaptureClient->GetBuffer failed! {:#x}", result);
-            return Err(());
-        }
+        check("IAudioCaptureClient->GetBuffer", result)?;
 
         // 2 channel 32-bit float slice of audio
         let audio = unsafe { std::slice::from_raw_parts(data, num_frames_available as usize * bytes_per_frame as usize) };
 
-        Ok((audio, num_frames_available))
+        Ok((audio, num_frames_available, qpc_position))
     }
 
-    pub fn release_buffer(&self, num_frames_available: u32) -> Result<(), ()> {
+    pub fn release_buffer(&self, num_frames_available: u32) -> Result<()> {
         let result = unsafe {
             (*self.ptr).ReleaseBuffer(num_frames_available)
         };
 
-        if !SUCCEEDED(result) {
-            eprintln!("IAudioCaptureClient->ReleaseBuffer failed! {:#x}", result);
-            return Err(());
-        }
+        check("IAudioCaptureClient->ReleaseBuffer", result)?;
 
         Ok(())
     }
@@ -0,0 +1,115 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+type Callback = Box<dyn Fn() + Send>;
+
+#[derive(Default)]
+struct Inner {
+    requested: AtomicBool,
+    callbacks: Mutex<Vec<Callback>>,
+    // Wakes threads in `wait_timeout`
+    wakeup: Condvar,
+}
+
+// Asks a running stream to wind down cleanly. Clones share their state, so one clone can be handed
+// to a signal handler or another thread while the backend watches the other.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown::default()
+    }
+
+    // Only the first request runs the callbacks
+    pub fn request(&self) {
+        if self.inner.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let callbacks: Vec<Callback> = self.inner.callbacks.lock().unwrap().drain(..).collect();
+        self.inner.wakeup.notify_all();
+        for callback in callbacks {
+            callback();
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+
+    // Sleeps for `timeout` or until shutdown is requested. Returns whether it was requested
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut callbacks = self.inner.callbacks.lock().unwrap();
+        while !self.is_requested() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            callbacks = self.inner.wakeup.wait_timeout(callbacks, deadline - now).unwrap().0;
+        }
+        true
+    }
+
+    // Runs `callback` when shutdown is requested, or straight away if it already has been
+    pub fn on_request<F: Fn() + Send + 'static>(&self, callback: F) {
+        let mut callbacks = self.inner.callbacks.lock().unwrap();
+        if self.is_requested() {
+            drop(callbacks);
+            callback();
+        } else {
+            callbacks.push(Box::new(callback));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn callbacks_run_once() {
+        let shutdown = Shutdown::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        shutdown.on_request(move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+        });
+
+        assert!(!shutdown.is_requested());
+        shutdown.clone().request();
+        shutdown.request();
+        assert!(shutdown.is_requested());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Registered too late to wait, so it runs straight away
+        let counted = calls.clone();
+        shutdown.on_request(move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn request_wakes_waiting_threads() {
+        let shutdown = Shutdown::new();
+        assert!(!shutdown.wait_timeout(Duration::from_millis(10)));
+
+        let waiter = shutdown.clone();
+        let thread = std::thread::spawn(move || {
+            let start = Instant::now();
+            (waiter.wait_timeout(Duration::from_secs(10)), start.elapsed())
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        shutdown.request();
+
+        let (requested, waited) = thread.join().unwrap();
+        assert!(requested);
+        assert!(waited < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,103 @@
+// Streams the null backend's test signal over TCP on the loopback interface and checks that the
+// player gets exactly what was recorded
+#![cfg(feature = "backend-null")]
+
+use audio_share::media::codec::Codec;
+use audio_share::media::dither::DitherSettings;
+use audio_share::media::format::SampleFormat;
+use audio_share::network::ReconnectSettings;
+use audio_share::null::{Input, Interface, MemoryOutput, Output, Settings};
+use audio_share::{Config, InterfaceTrait, Shutdown};
+use std::f64::consts::PI;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+const FREQUENCY: f64 = 440.0;
+const DURATION: Duration = Duration::from_millis(200);
+
+fn config(sample_format: SampleFormat, codec: Codec) -> Config {
+    Config {
+        host: "127.0.0.1".to_string(),
+        // The server takes any free port and the player is told which one it got
+        port: 0,
+        advertise: false,
+        sample_format,
+        codec,
+        // Without dither the recorded samples are exactly the rounded sine
+        dither: DitherSettings { enabled: false, ..DitherSettings::default() },
+        reconnect: ReconnectSettings {
+            initial_delay: Duration::from_millis(20),
+            max_delay: Duration::from_millis(20),
+            ..ReconnectSettings::default()
+        },
+        ..Config::default()
+    }
+}
+
+// The generator's sine, which runs at half of full scale
+fn expected(config: &Config) -> Vec<u8> {
+    let format = config.stream_format();
+    let frames = (DURATION.as_secs_f64() * format.rate as f64).round() as usize;
+    let mut phase: f64 = 0.0;
+    let mut samples = Vec::with_capacity(frames * format.channels as usize);
+    for _ in 0..frames {
+        for _ in 0..format.channels {
+            samples.push((phase.sin() * 0.5) as f32);
+        }
+        phase = (phase + 2.0 * PI * FREQUENCY / format.rate as f64) % (2.0 * PI);
+    }
+
+    let mut bytes = vec![0; samples.len() * format.sample_format.bytes_per_sample()];
+    format.sample_format.write_samples(&samples, &mut bytes);
+    bytes
+}
+
+fn stream(config: Config) -> Vec<u8> {
+    // The server doesn't keep audio for late clients, so the player gets time to connect first
+    let (listening, address) = mpsc::channel();
+    let recorder_config = config.clone();
+    let recorder = thread::spawn(move || {
+        let settings = Settings {
+            input: Input::Sine { frequency: FREQUENCY },
+            duration: Some(DURATION),
+            start_delay: Duration::from_millis(500),
+            listening: Some(listening),
+            ..Settings::default()
+        };
+        Interface::new(settings).start_recording(&recorder_config, &Shutdown::new())
+    });
+
+    let memory = MemoryOutput::new();
+    let player_config = Config { port: address.recv().expect("server never listened").port(), ..config.clone() };
+    let settings = Settings { output: Output::Memory(memory.clone()), ..Settings::default() };
+    Interface::new(settings).start_playback(&player_config, &Shutdown::new()).unwrap();
+    recorder.join().unwrap().unwrap();
+
+    assert_eq!(memory.format(), Some(config.stream_format()));
+    memory.samples()
+}
+
+fn assert_same(played: &[u8], expected: &[u8]) {
+    assert_eq!(played.len(), expected.len(), "played a different number of samples");
+    assert!(played == expected, "played samples differ from those recorded");
+}
+
+#[test]
+fn plays_what_was_recorded() {
+    let config = config(SampleFormat::S16LE, Codec::Pcm);
+    assert_same(&stream(config.clone()), &expected(&config));
+}
+
+#[test]
+fn plays_float_samples_unchanged() {
+    let config = config(SampleFormat::F32LE, Codec::Pcm);
+    assert_same(&stream(config.clone()), &expected(&config));
+}
+
+#[test]
+fn plays_every_sample_through_flac() {
+    // The duration isn't a whole number of blocks, so the last one only arrives when it is flushed
+    let config = config(SampleFormat::S24LE, Codec::Flac);
+    assert_same(&stream(config.clone()), &expected(&config));
+}